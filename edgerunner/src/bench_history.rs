@@ -0,0 +1,488 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::config::config_folder_path,
+    error::{EdgerunnerError, Result, ResultExt},
+    runner::benchmark::{BenchConfig, BenchReport, InferenceBenchmark},
+    system_benchmark::{BenchmarkConfig, BenchmarkReport},
+};
+
+const HISTORY_FILE: &str = "bench_history.jsonl";
+
+/// A measured value and whether a bigger one is an improvement.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metric {
+    pub value: f64,
+    pub higher_is_better: bool,
+}
+
+impl Metric {
+    fn higher(value: f64) -> Self {
+        Self {
+            value,
+            higher_is_better: true,
+        }
+    }
+
+    fn lower(value: f64) -> Self {
+        Self {
+            value,
+            higher_is_better: false,
+        }
+    }
+}
+
+pub type Metrics = BTreeMap<String, Metric>;
+
+impl InferenceBenchmark {
+    /// The median of every measurement, keyed by a stable name.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::new();
+        for prefill in &self.prefill {
+            let prefix = format!("prefill.{}", prefill.prompt_tokens);
+            metrics.insert(
+                format!("{prefix}.tokens_per_second"),
+                Metric::higher(prefill.tokens_per_second.p50),
+            );
+            metrics.insert(
+                format!("{prefix}.time_to_first_token_ms"),
+                Metric::lower(prefill.time_to_first_token_ms.p50),
+            );
+        }
+        metrics.insert(
+            "decode.tokens_per_second".to_string(),
+            Metric::higher(self.decode.tokens_per_second.p50),
+        );
+        if let Some(peak_memory_bytes) = self.peak_memory_bytes {
+            metrics.insert(
+                "peak_memory_bytes".to_string(),
+                Metric::lower(peak_memory_bytes as f64),
+            );
+        }
+        metrics
+    }
+}
+
+impl BenchmarkReport {
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::new();
+        for device in &self.devices {
+            let prefix = device.device.to_string().to_lowercase();
+            metrics.insert(format!("{prefix}.tflops"), Metric::higher(device.tflops));
+            metrics.insert(
+                format!("{prefix}.memory_bandwidth_gbps"),
+                Metric::higher(device.memory_bandwidth_gbps),
+            );
+            for q in &device.quantized_matmul {
+                metrics.insert(
+                    format!("{prefix}.qmatmul.{}.gflops", q.dtype),
+                    Metric::higher(q.gflops),
+                );
+            }
+            for t in &device.thread_scaling {
+                metrics.insert(
+                    format!("{prefix}.threads.{}.gflops", t.threads),
+                    Metric::higher(t.gflops),
+                );
+            }
+        }
+        metrics
+    }
+}
+
+impl BenchReport {
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.inference.metrics();
+        if let Some(system) = &self.system {
+            metrics.extend(system.metrics());
+        }
+        metrics
+    }
+}
+
+/// A benchmark run stored in the history.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchRecord {
+    /// The position of the record in the history, starting at 1.
+    #[serde(skip)]
+    pub id: usize,
+    /// RFC 3339 UTC time of the run.
+    pub timestamp: String,
+    pub crate_version: String,
+    /// `bench` or `bench kernels`.
+    pub kind: String,
+    pub device: String,
+    pub model: Option<String>,
+    pub settings: serde_json::Value,
+    pub metrics: Metrics,
+}
+
+impl BenchRecord {
+    pub fn new(
+        kind: &str,
+        device: String,
+        model: Option<String>,
+        settings: serde_json::Value,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            id: 0,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            kind: kind.to_string(),
+            device,
+            model,
+            settings,
+            metrics,
+        }
+    }
+
+    /// Records a run of the `bench` command.
+    pub fn from_bench_report(report: &BenchReport, config: &BenchConfig) -> Self {
+        let mut settings = serde_json::json!(config);
+        settings["threads"] = serde_json::json!(report.inference.threads);
+        Self::new(
+            "bench",
+            report.device.to_string(),
+            Some(report.model.clone()),
+            settings,
+            report.metrics(),
+        )
+    }
+
+    /// Records a run of the `bench kernels` command.
+    pub fn from_kernels_report(report: &BenchmarkReport, config: &BenchmarkConfig) -> Self {
+        let devices = report
+            .devices
+            .iter()
+            .map(|d| d.device.to_string())
+            .collect::<Vec<_>>();
+        let settings = serde_json::json!({
+            "seed": config.seed,
+            "matmul_size": config.matmul_size,
+            "qmatmul_weights": config.qmatmul_weights,
+            "qmatmul_batch": config.qmatmul_batch,
+            "quantized_dtypes": config
+                .quantized_dtypes
+                .iter()
+                .map(|dtype| format!("{:?}", dtype))
+                .collect::<Vec<_>>(),
+            "bandwidth_bytes": config.bandwidth_bytes,
+            "thread_counts": config.thread_counts,
+            "iterations": config.iterations,
+        });
+        Self::new(
+            "bench kernels",
+            devices.join(","),
+            None,
+            settings,
+            report.metrics(),
+        )
+    }
+}
+
+/// The benchmark history, one JSON record per line.
+pub struct BenchHistory {
+    path: PathBuf,
+}
+
+impl BenchHistory {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The history kept in the edgerunner config folder.
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(config_folder_path()?.join(HISTORY_FILE)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the record and returns its id.
+    pub fn append(&self, record: &BenchRecord) -> Result<usize> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let id = self.records()?.len() + 1;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = serde_json::to_string(record).kind(EdgerunnerError::Io)?;
+        writeln!(file, "{}", line)?;
+        Ok(id)
+    }
+
+    pub fn records(&self) -> Result<Vec<BenchRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(index, line)| {
+                let mut record: BenchRecord = serde_json::from_str(line).map_err(|e| {
+                    EdgerunnerError::Io(anyhow::Error::msg(format!(
+                        "invalid record {} in {}: {}",
+                        index + 1,
+                        self.path.display(),
+                        e
+                    )))
+                })?;
+                record.id = index + 1;
+                Ok(record)
+            })
+            .collect()
+    }
+
+    /// Picks the records to compare, by default the last run against the latest earlier run of
+    /// the same kind, model and device.
+    pub fn pair(
+        &self,
+        baseline: Option<usize>,
+        candidate: Option<usize>,
+    ) -> Result<(BenchRecord, BenchRecord)> {
+        let records = self.records()?;
+        let get = |id: usize| {
+            records.get(id.wrapping_sub(1)).cloned().ok_or_else(|| {
+                EdgerunnerError::config(format!("no benchmark run {} in the history", id))
+            })
+        };
+        let candidate = get(candidate.unwrap_or(records.len()))?;
+        let baseline = match baseline {
+            Some(id) => get(id)?,
+            None => records[..candidate.id - 1]
+                .iter()
+                .rev()
+                .find(|record| {
+                    record.kind == candidate.kind
+                        && record.model == candidate.model
+                        && record.device == candidate.device
+                })
+                .cloned()
+                .ok_or_else(|| {
+                    EdgerunnerError::config(format!(
+                        "no earlier {} run on {} of the same model to compare run {} with",
+                        candidate.kind, candidate.device, candidate.id
+                    ))
+                })?,
+        };
+        Ok((baseline, candidate))
+    }
+}
+
+/// The change of one metric between two runs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricChange {
+    pub name: String,
+    pub baseline: f64,
+    pub candidate: f64,
+    /// The relative change in percent, positive when the value grew.
+    pub change_percent: f64,
+    pub regression: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Comparison {
+    pub baseline: usize,
+    pub candidate: usize,
+    pub threshold_percent: f64,
+    pub changes: Vec<MetricChange>,
+    /// Differences between the runs that make the comparison less meaningful.
+    pub warnings: Vec<String>,
+}
+
+impl Comparison {
+    pub fn regressions(&self) -> impl Iterator<Item = &MetricChange> {
+        self.changes.iter().filter(|c| c.regression)
+    }
+
+    pub fn has_regressions(&self) -> bool {
+        self.regressions().next().is_some()
+    }
+}
+
+/// Compares the metrics the two runs have in common, a metric regresses when it gets worse by
+/// more than `threshold_percent`. Runs of different kinds or without common metrics cannot be
+/// compared.
+pub fn compare(
+    baseline: &BenchRecord,
+    candidate: &BenchRecord,
+    threshold_percent: f64,
+) -> Result<Comparison> {
+    if baseline.kind != candidate.kind {
+        return Err(EdgerunnerError::config(format!(
+            "run {} is a {} run and run {} a {} run, they cannot be compared",
+            baseline.id, baseline.kind, candidate.id, candidate.kind
+        )));
+    }
+    let mut warnings = Vec::new();
+    let mut differs = |what: &str, a: &dyn std::fmt::Debug, b: &dyn std::fmt::Debug| {
+        let (a, b) = (format!("{a:?}"), format!("{b:?}"));
+        if a != b {
+            warnings.push(format!("{what} differs: {a} vs {b}"));
+        }
+    };
+    differs("device", &baseline.device, &candidate.device);
+    differs("model", &baseline.model, &candidate.model);
+    differs("settings", &baseline.settings, &candidate.settings);
+
+    let changes: Vec<_> = baseline
+        .metrics
+        .iter()
+        .filter_map(|(name, before)| {
+            let after = candidate.metrics.get(name)?;
+            let change_percent = if before.value == 0. {
+                0.
+            } else {
+                (after.value - before.value) / before.value.abs() * 100.
+            };
+            let regression = match before.higher_is_better {
+                true => change_percent < -threshold_percent,
+                false => change_percent > threshold_percent,
+            };
+            Some(MetricChange {
+                name: name.clone(),
+                baseline: before.value,
+                candidate: after.value,
+                change_percent,
+                regression,
+            })
+        })
+        .collect();
+    if changes.is_empty() {
+        return Err(EdgerunnerError::config(format!(
+            "runs {} and {} have no metrics in common",
+            baseline.id, candidate.id
+        )));
+    }
+
+    Ok(Comparison {
+        baseline: baseline.id,
+        candidate: candidate.id,
+        threshold_percent,
+        changes,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(decode: f64, ttft_ms: f64) -> BenchRecord {
+        let metrics = Metrics::from([
+            (
+                "decode.tokens_per_second".to_string(),
+                Metric::higher(decode),
+            ),
+            (
+                "prefill.16.time_to_first_token_ms".to_string(),
+                Metric::lower(ttft_ms),
+            ),
+        ]);
+        BenchRecord::new(
+            "bench",
+            "CPU".to_string(),
+            Some("model.gguf".to_string()),
+            serde_json::json!({ "repetitions": 5 }),
+            metrics,
+        )
+    }
+
+    fn history_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("edgerunner-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_compare_detects_regressions() {
+        let comparison = compare(&record(10., 100.), &record(8., 104.), 5.).unwrap();
+        let regressions = comparison
+            .regressions()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(regressions, ["decode.tokens_per_second"]);
+        assert!(comparison.warnings.is_empty());
+
+        let comparison = compare(&record(10., 100.), &record(10.2, 120.), 5.).unwrap();
+        let regressions = comparison
+            .regressions()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(regressions, ["prefill.16.time_to_first_token_ms"]);
+    }
+
+    #[test]
+    fn test_compare_within_threshold() {
+        let comparison = compare(&record(10., 100.), &record(9.8, 103.), 5.).unwrap();
+        assert!(!comparison.has_regressions());
+        assert_eq!(comparison.changes[0].change_percent.round(), -2.);
+    }
+
+    #[test]
+    fn test_compare_rejects_unrelated_runs() {
+        let kernels = BenchRecord {
+            kind: "bench kernels".to_string(),
+            ..record(10., 100.)
+        };
+        assert!(compare(&record(10., 100.), &kernels, 5.).is_err());
+
+        let other_metrics = BenchRecord {
+            metrics: Metrics::from([("cpu.tflops".to_string(), Metric::higher(1.))]),
+            ..record(10., 100.)
+        };
+        assert!(compare(&record(10., 100.), &other_metrics, 5.).is_err());
+    }
+
+    #[test]
+    fn test_default_baseline_matches_the_candidate() {
+        let history = BenchHistory::new(history_path("matching"));
+        let other_model = BenchRecord {
+            model: Some("other.gguf".to_string()),
+            ..record(10., 100.)
+        };
+        let kernels = BenchRecord {
+            kind: "bench kernels".to_string(),
+            model: None,
+            ..record(10., 100.)
+        };
+        history.append(&record(10., 100.)).unwrap();
+        history.append(&other_model).unwrap();
+        history.append(&kernels).unwrap();
+        history.append(&record(11., 90.)).unwrap();
+
+        let (baseline, candidate) = history.pair(None, None).unwrap();
+        assert_eq!((baseline.id, candidate.id), (1, 4));
+        assert!(history.pair(None, Some(3)).is_err());
+
+        fs::remove_file(history.path()).unwrap();
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let history = BenchHistory::new(history_path("history"));
+        assert_eq!(history.append(&record(10., 100.)).unwrap(), 1);
+        assert_eq!(history.append(&record(11., 90.)).unwrap(), 2);
+        assert_eq!(history.append(&record(12., 80.)).unwrap(), 3);
+
+        let (baseline, candidate) = history.pair(None, None).unwrap();
+        assert_eq!((baseline.id, candidate.id), (2, 3));
+        let (baseline, candidate) = history.pair(Some(1), Some(2)).unwrap();
+        assert_eq!(baseline.metrics, record(10., 100.).metrics);
+        assert_eq!(candidate.id, 2);
+        assert!(history.pair(Some(4), None).is_err());
+
+        fs::remove_file(history.path()).unwrap();
+    }
+}
@@ -1,11 +1,43 @@
 use super::which::Which;
+use crate::{
+    error::{EdgerunnerError, Result, ResultExt},
+    model::types::ModelArchitecture,
+    runner::{
+        beam_search::BeamSearchConfig, device::DeviceSpec, prefix_cache::PrefixCacheConfig,
+        speculative::SpeculativeConfig, threads::ThreadConfig,
+    },
+};
+use candle_core::DType;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
-#[derive(Serialize, PartialEq, Deserialize, Debug)]
+/// Precision used when loading full precision (safetensors) weights.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightsDType {
+    #[default]
+    F32,
+    Bf16,
+}
+
+impl From<WeightsDType> for DType {
+    fn from(dtype: WeightsDType) -> Self {
+        match dtype {
+            WeightsDType::F32 => DType::F32,
+            WeightsDType::Bf16 => DType::BF16,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, PartialEq, Deserialize, Debug)]
 pub struct InferenceConfig {
-    // GGML file to load, typically a .bin file generated by the quantize command from llama.cpp
+    // GGML file to load, typically a .bin file generated by the quantize command from llama.cpp,
+    // or a directory holding a safetensors checkpoint (config.json + *.safetensors)
     pub model: Option<String>,
+    /// The precision to load safetensors weights in, ignored for quantized models.
+    #[serde(default)]
+    pub dtype: WeightsDType,
     /// The temperature used to generate samples.
     pub temperature: Option<f64>,
     /// Nucleus sampling probability cutoff.
@@ -18,54 +50,141 @@ pub struct InferenceConfig {
     pub repeat_penalty: f32,
     /// The context size to consider for the repeat penalty.
     pub repeat_last_n: usize,
-    /// The tokenizer config in json format.
+    /// Searches the most likely continuations instead of sampling.
+    #[serde(default)]
+    pub beam_search: Option<BeamSearchConfig>,
+    /// The tokenizer config in json format, when unset the vocabulary embedded in a gguf model is
+    /// used before falling back to the hub tokenizer.
     pub tokenizer: Option<String>,
     /// Display the token for the specified prompt.
     pub verbose_prompt: bool,
     /// The model size to use.
     pub which: Which,
+    /// The device to run on, missing devices fall back to the cpu.
+    #[serde(default)]
+    pub device: DeviceSpec,
+    /// The cpu threads of the prefill and decode, and the cores they are pinned to.
+    #[serde(default)]
+    pub threads: ThreadConfig,
+    /// Keeps the kv cache of the prompts to skip the prefill of their shared prefixes.
+    #[serde(default)]
+    pub prefix_cache: Option<PrefixCacheConfig>,
+    /// Drafts tokens with a smaller model that shares the vocabulary.
+    #[serde(default)]
+    pub speculative: Option<SpeculativeConfig>,
 }
 
 impl Default for InferenceConfig {
     fn default() -> Self {
         InferenceConfig {
             model: None, // or Some("default_model_path".to_string()) if there is a default model
+            dtype: WeightsDType::F32,
             temperature: Some(0.8),
             top_p: Some(0.9),
             seed: 299792458,
             sample_len: 1000,
             repeat_penalty: 1.1,
             repeat_last_n: 64,
+            beam_search: None,
             tokenizer: None,
             verbose_prompt: true,
             which: Which::Mistral7bInstruct,
+            device: DeviceSpec::Auto,
+            threads: ThreadConfig::default(),
+            prefix_cache: None,
+            speculative: None,
         }
     }
 }
 
+/// The settings of a single generation, which can change between runs of a loaded model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    /// The maximum number of tokens to generate.
+    pub sample_len: usize,
+    /// The temperature, greedy sampling when unset.
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    pub seed: u64,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    pub repeat_penalty: f32,
+    /// The number of last tokens the repeat penalty looks at.
+    pub repeat_last_n: usize,
+    /// Searches the most likely continuations instead of sampling, which ignores the
+    /// temperature, the nucleus cutoff and the seed.
+    #[serde(default)]
+    pub beam_search: Option<BeamSearchConfig>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        InferenceConfig::default().sampling()
+    }
+}
+
 impl InferenceConfig {
-    pub fn tokenizer(&self) -> anyhow::Result<Tokenizer> {
+    /// The sampling settings of the config.
+    pub fn sampling(&self) -> SamplingParams {
+        SamplingParams {
+            sample_len: self.sample_len,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            beam_search: self.beam_search.clone(),
+        }
+    }
+
+    /// Replaces the sampling settings of the config.
+    pub fn set_sampling(&mut self, params: &SamplingParams) {
+        self.sample_len = params.sample_len;
+        self.temperature = params.temperature;
+        self.top_p = params.top_p;
+        self.seed = params.seed;
+        self.repeat_penalty = params.repeat_penalty;
+        self.repeat_last_n = params.repeat_last_n;
+        self.beam_search = params.beam_search.clone();
+    }
+
+    pub fn tokenizer(&self) -> Result<Tokenizer> {
+        self.tokenizer_from(self.which.tokenizer_repo())
+    }
+
+    /// The tokenizer of a loaded model, downloaded from the repository of its architecture
+    /// rather than the one of `which` when the two vocabularies differ.
+    pub fn tokenizer_for(&self, architecture: ModelArchitecture) -> Result<Tokenizer> {
+        self.tokenizer_from(
+            architecture
+                .tokenizer_repo()
+                .unwrap_or_else(|| self.which.tokenizer_repo()),
+        )
+    }
+
+    fn tokenizer_from(&self, repo: &str) -> Result<Tokenizer> {
         let tokenizer_path = match &self.tokenizer {
             Some(config) => std::path::PathBuf::from(config),
             None => {
-                let api = hf_hub::api::sync::Api::new()?;
-                let repo = self.which.tokenizer_repo();
+                let api = hf_hub::api::sync::Api::new().kind(EdgerunnerError::Download)?;
                 let api = api.model(repo.to_string());
-                api.get("tokenizer.json")?
+                api.get("tokenizer.json").kind(EdgerunnerError::Download)?
             }
         };
-        Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)
+        Tokenizer::from_file(&tokenizer_path)
+            .map_err(|e| anyhow::Error::msg(format!("{}: {}", tokenizer_path.display(), e)))
+            .kind(EdgerunnerError::Tokenizer)
     }
 
-    pub fn model(&self) -> anyhow::Result<std::path::PathBuf> {
+    pub fn model(&self) -> Result<std::path::PathBuf> {
         let model_path = match &self.model {
             Some(config) => std::path::PathBuf::from(config),
             None => {
                 let (repo, filename) = self.which.get_repo_and_filename();
 
-                let api = hf_hub::api::sync::Api::new()?;
+                let api = hf_hub::api::sync::Api::new().kind(EdgerunnerError::Download)?;
                 let api = api.model(repo.to_string());
-                api.get(filename)?
+                api.get(filename).kind(EdgerunnerError::Download)?
             }
         };
         Ok(model_path)
@@ -1,5 +1,6 @@
 use std::fmt;
 
+use candle_core::quantized::GgmlDType;
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
@@ -22,6 +23,37 @@ pub enum Which {
     OpenChat35,
 }
 
+/// 7b mistral and mixtral share the attention shape: 32 layers of 8 kv heads of 128 dims, with
+/// keys and values cached in f32.
+const MISTRAL_KV_CACHE_BYTES_PER_TOKEN: u64 = 2 * 32 * 8 * 128 * 4;
+const MISTRAL_7B_PARAMETERS: u64 = 7_240_000_000;
+const MIXTRAL_PARAMETERS: u64 = 46_700_000_000;
+const MIXTRAL_ACTIVE_PARAMETERS: u64 = 12_900_000_000;
+
+/// Size and shape facts about the weights of a model, used to estimate its memory use and
+/// speed on a machine.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ModelProfile {
+    pub file_size_bytes: u64,
+    pub parameters: u64,
+    /// The parameters used for every token, less than `parameters` for mixture of experts.
+    pub active_parameters: u64,
+    /// The llama.cpp name of the quantization of the weights file.
+    pub quantization: &'static str,
+    /// The quantized kernel most of the weights go through.
+    #[serde(skip)]
+    pub kernel_dtype: GgmlDType,
+    pub kv_cache_bytes_per_token: u64,
+}
+
+impl ModelProfile {
+    /// The bytes of weights read to generate one token.
+    pub fn active_weights_bytes(&self) -> u64 {
+        (self.file_size_bytes as f64 * self.active_parameters as f64 / self.parameters as f64)
+            as u64
+    }
+}
+
 impl fmt::Display for Which {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name = self.to_possible_value().unwrap();
@@ -81,6 +113,34 @@ impl Which {
         }
     }
 
+    pub fn profile(&self) -> ModelProfile {
+        let mistral_7b = |file_size_bytes, quantization, kernel_dtype| ModelProfile {
+            file_size_bytes,
+            parameters: MISTRAL_7B_PARAMETERS,
+            active_parameters: MISTRAL_7B_PARAMETERS,
+            quantization,
+            kernel_dtype,
+            kv_cache_bytes_per_token: MISTRAL_KV_CACHE_BYTES_PER_TOKEN,
+        };
+        match self {
+            Self::Mistral7b | Self::Mistral7bInstruct => {
+                mistral_7b(4_140_000_000, "Q4_K_S", GgmlDType::Q4K)
+            }
+            Self::Mistral7bInstructQ2 => mistral_7b(3_080_000_000, "Q2_K", GgmlDType::Q2K),
+            Self::Zephyr7bBeta | Self::OpenChat35 => {
+                mistral_7b(4_370_000_000, "Q4_K_M", GgmlDType::Q4K)
+            }
+            Self::Mixtral | Self::MixtralInstruct => ModelProfile {
+                file_size_bytes: 26_440_000_000,
+                parameters: MIXTRAL_PARAMETERS,
+                active_parameters: MIXTRAL_ACTIVE_PARAMETERS,
+                quantization: "Q4_K_M",
+                kernel_dtype: GgmlDType::Q4K,
+                kv_cache_bytes_per_token: MISTRAL_KV_CACHE_BYTES_PER_TOKEN,
+            },
+        }
+    }
+
     pub fn get_repo_and_filename(&self) -> (&'static str, &'static str) {
         match self {
             Self::Mixtral => (
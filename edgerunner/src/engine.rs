@@ -0,0 +1,591 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    conf::{
+        model::{InferenceConfig, SamplingParams},
+        which::Which,
+    },
+    error::{EdgerunnerError, Result, ResultExt},
+    model::{
+        loader::{weight_files, LoadModel, Model},
+        prompt::{handle_chat_input, handle_tool_chat, GeneratedPrompt},
+        tools::{parse_tool_calls, ChatMessage, ChatRequest, Role, ToolChoice},
+    },
+    runner::{
+        device::DeviceSpec,
+        embeddings::{self, EmbeddingOptions},
+        eval::{self, ContinuationScore, EvalConfig, PerplexityReport},
+        events::{FinishReason, NoopObserver, SharedObserver},
+        grammar::ToolCallGrammar,
+        prefix_cache::{PrefixCache, PrefixCacheConfig, PrefixCacheStats},
+        session::{ModelFingerprint, Session},
+        speculative::{Draft, SpeculativeConfig},
+        text_generation::{Generation, TextGeneration},
+        threads::{ThreadConfig, ThreadPools},
+    },
+    util::{fnv1a, FNV_OFFSET_BASIS},
+};
+
+/// Configures and loads an [`Engine`], unset settings keep their [`InferenceConfig`] default.
+pub struct EngineBuilder {
+    config: InferenceConfig,
+    observer: SharedObserver,
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl EngineBuilder {
+    /// Replaces every setting with `config`.
+    pub fn config(mut self, config: InferenceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn which(mut self, which: Which) -> Self {
+        self.config.which = which;
+        self
+    }
+
+    /// Loads a local gguf file or safetensors checkpoint instead of downloading `which`.
+    pub fn model_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.model = Some(path.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    pub fn tokenizer_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.tokenizer = Some(path.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    pub fn device(mut self, device: DeviceSpec) -> Self {
+        self.config.device = device;
+        self
+    }
+
+    pub fn threads(mut self, threads: ThreadConfig) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    /// The sampling settings used when a call does not override them.
+    pub fn sampling(mut self, params: &SamplingParams) -> Self {
+        self.config.set_sampling(params);
+        self
+    }
+
+    /// Reuses the kv cache of earlier prompts, see [`PrefixCache`].
+    pub fn prefix_cache(mut self, config: PrefixCacheConfig) -> Self {
+        self.config.prefix_cache = Some(config);
+        self
+    }
+
+    /// Decodes speculatively with a smaller draft model, see [`SpeculativeConfig`].
+    pub fn speculative(mut self, config: SpeculativeConfig) -> Self {
+        self.config.speculative = Some(config);
+        self
+    }
+
+    /// Reports the loading and the generations to `observer`.
+    pub fn observer(mut self, observer: SharedObserver) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Shares a stop flag with the caller, see [`Engine::stop_flag`].
+    pub fn stop_flag(mut self, stop_flag: Arc<AtomicBool>) -> Self {
+        self.stop_flag = stop_flag;
+        self
+    }
+
+    /// Loads the model and the tokenizer.
+    pub fn build(self) -> Result<Engine> {
+        let model = LoadModel::load_model_with_observer(&self.config, self.observer.as_ref())?;
+        self.build_from(model)
+    }
+
+    /// Builds the engine around an already loaded model, loading the draft model if there is one.
+    pub fn build_from(self, model: Model) -> Result<Engine> {
+        let draft = match &self.config.speculative {
+            Some(speculative) => Some(self.load_draft(speculative, &model)?),
+            None => None,
+        };
+        let fingerprint = fingerprint(&self.config, &model)?;
+        let threads = ThreadPools::new(&self.config.threads)?;
+        let params = self.config.sampling();
+        let mut pipeline = TextGeneration::new(
+            model.weights,
+            model.device,
+            model.tokenizer,
+            params.repeat_penalty,
+            params.repeat_last_n,
+            params.seed,
+            params.temperature,
+            params.top_p,
+        )
+        .with_threads(threads)
+        .with_observer(self.observer);
+        if let Some(config) = &self.config.prefix_cache {
+            let prefix_cache = PrefixCache::new(model_key(&fingerprint), config.clone())?;
+            pipeline = pipeline.with_prefix_cache(prefix_cache);
+        }
+        if let Some(draft) = draft {
+            pipeline = pipeline.with_draft(draft);
+        }
+
+        Ok(Engine {
+            config: self.config,
+            pipeline,
+            fingerprint,
+            stop_flag: self.stop_flag,
+        })
+    }
+
+    /// Loads the draft model on the device of the model, it has to share its vocabulary.
+    fn load_draft(&self, speculative: &SpeculativeConfig, model: &Model) -> Result<Draft> {
+        let config = InferenceConfig {
+            model: Some(speculative.draft_model.clone()),
+            prefix_cache: None,
+            speculative: None,
+            ..self.config.clone()
+        };
+        let draft = LoadModel::load_model_with_observer(&config, self.observer.as_ref())?;
+        let (vocab_size, draft_vocab_size) = (
+            model.weights.metadata().vocab_size,
+            draft.weights.metadata().vocab_size,
+        );
+        if vocab_size != draft_vocab_size {
+            return Err(EdgerunnerError::config(format!(
+                "the draft model {} has {} tokens, the model has {}",
+                speculative.draft_model, draft_vocab_size, vocab_size
+            )));
+        }
+        Ok(Draft::new(draft.weights, speculative.draft_tokens))
+    }
+}
+
+fn model_name(config: &InferenceConfig) -> String {
+    match &config.model {
+        Some(model) => Path::new(model)
+            .file_name()
+            .map_or_else(|| model.clone(), |name| name.to_string_lossy().to_string()),
+        None => config.which.to_string(),
+    }
+}
+
+/// The bytes hashed at each end of a weight file.
+const HASHED_BYTES: u64 = 1 << 20;
+
+/// The total size of the weight files and a hash of their sizes and of their first and last
+/// [`HASHED_BYTES`], which hold the gguf or safetensors headers and some of the weights, rather
+/// than of the whole files.
+fn weights_digest(files: &[PathBuf]) -> Result<(u64, u64)> {
+    let mut size_bytes = 0;
+    let mut hash = FNV_OFFSET_BASIS;
+    for path in files {
+        let mut file = File::open(path).kind(EdgerunnerError::Load)?;
+        let len = file.metadata()?.len();
+        size_bytes += len;
+        hash = fnv1a(hash, len.to_le_bytes());
+        let mut bytes = Vec::new();
+        file.by_ref().take(HASHED_BYTES).read_to_end(&mut bytes)?;
+        if len > HASHED_BYTES {
+            file.seek(SeekFrom::Start(
+                len.saturating_sub(HASHED_BYTES).max(HASHED_BYTES),
+            ))?;
+            file.read_to_end(&mut bytes)?;
+        }
+        hash = fnv1a(hash, bytes);
+    }
+    Ok((size_bytes, hash))
+}
+
+/// Identifies the weights of `model`, by the files it was loaded from or else the configured
+/// model path, so that the sessions and the prefix cache of one model never go to another.
+fn fingerprint(config: &InferenceConfig, model: &Model) -> Result<ModelFingerprint> {
+    let path = model
+        .path
+        .clone()
+        .or_else(|| config.model.as_ref().map(PathBuf::from));
+    let files = match &path {
+        Some(path) => weight_files(path)?,
+        None => Vec::new(),
+    };
+    let (size_bytes, weights_hash) = weights_digest(&files)?;
+    let metadata = model.weights.metadata();
+    Ok(ModelFingerprint {
+        model: model_name(config),
+        size_bytes,
+        weights_hash,
+        architecture: metadata.architecture,
+        vocab_size: metadata.vocab_size,
+    })
+}
+
+/// The prefix cache key of the weights, the cache of one model is useless to another.
+fn model_key(fingerprint: &ModelFingerprint) -> String {
+    format!(
+        "{}:{}:{:016x}",
+        fingerprint.model, fingerprint.size_bytes, fingerprint.weights_hash
+    )
+}
+
+/// The reply to a [`ChatRequest`], an assistant message with its text or its tool calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatCompletion {
+    pub message: ChatMessage,
+    /// The raw reply and its stats, finishing with [`FinishReason::ToolCalls`] when the reply
+    /// calls tools.
+    pub generation: Generation,
+}
+
+/// A loaded model ready to generate, chat, tokenize and embed. Every call takes the
+/// [`SamplingParams`] it runs with, so changing them needs no reload:
+///
+/// ```no_run
+/// # use edgerunner::{conf::model::SamplingParams, engine::Engine};
+/// let mut engine = Engine::builder().build()?;
+/// let greedy = SamplingParams { temperature: None, ..engine.params() };
+/// let reply = engine.chat("How does this work?", &[], None, &greedy)?;
+/// println!("{}", reply.text);
+/// # Ok::<(), edgerunner::error::EdgerunnerError>(())
+/// ```
+pub struct Engine {
+    config: InferenceConfig,
+    pipeline: TextGeneration,
+    fingerprint: ModelFingerprint,
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            config: InferenceConfig::default(),
+            observer: Arc::new(NoopObserver),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn config(&self) -> &InferenceConfig {
+        &self.config
+    }
+
+    /// The maximum number of prompt and generated tokens of a call.
+    pub fn context_length(&self) -> usize {
+        self.pipeline.context_length()
+    }
+
+    /// The default sampling settings, a starting point for per call overrides.
+    pub fn params(&self) -> SamplingParams {
+        self.config.sampling()
+    }
+
+    pub fn set_params(&mut self, params: &SamplingParams) {
+        self.config.set_sampling(params);
+    }
+
+    /// Names the model by its file rather than its path, so that the files derived from it can
+    /// move with it.
+    pub fn model_name(&self) -> String {
+        model_name(&self.config)
+    }
+
+    /// Identifies the loaded weights in the sessions it saves.
+    pub fn fingerprint(&self) -> ModelFingerprint {
+        self.fingerprint.clone()
+    }
+
+    /// The hits and misses of the prefix cache, `None` when it is disabled.
+    pub fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+        self.pipeline.prefix_cache_stats()
+    }
+
+    /// Raising the flag cancels the running call, it is lowered again when the next call starts.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop_flag.clone()
+    }
+
+    /// Completes the raw `prompt`.
+    pub fn generate(&mut self, prompt: &str, params: &SamplingParams) -> Result<Generation> {
+        self.generate_stream(prompt, params, |_| {})
+    }
+
+    /// Completes the raw `prompt`, streaming the text to `on_token` as it is generated.
+    pub fn generate_stream(
+        &mut self,
+        prompt: &str,
+        params: &SamplingParams,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        self.run(GeneratedPrompt(prompt.to_string()), params, None, on_token)
+    }
+
+    /// Answers `message` with the chat template of the model, `history` holds the earlier
+    /// alternating user and assistant messages.
+    pub fn chat(
+        &mut self,
+        message: &str,
+        history: &[String],
+        system_prompt: Option<&str>,
+        params: &SamplingParams,
+    ) -> Result<Generation> {
+        self.chat_stream(message, history, system_prompt, params, |_| {})
+    }
+
+    /// Like [`chat`](Self::chat), streaming the answer to `on_token`.
+    pub fn chat_stream(
+        &mut self,
+        message: &str,
+        history: &[String],
+        system_prompt: Option<&str>,
+        params: &SamplingParams,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        let system_prompt = system_prompt.map(str::to_string);
+        let prompt =
+            handle_chat_input(self.config.which, message, history, system_prompt.as_ref())?;
+        self.run(prompt, params, None, on_token)
+    }
+
+    /// Completes the raw `prompt` `n` times from a single prefill, see
+    /// [`TextGeneration::generate_n`].
+    pub fn generate_n(
+        &mut self,
+        prompt: &str,
+        n: usize,
+        params: &SamplingParams,
+    ) -> Result<Vec<Generation>> {
+        self.run_n(GeneratedPrompt(prompt.to_string()), n, params)
+    }
+
+    /// Answers `message` `n` times from a single prefill, like [`chat`](Self::chat).
+    pub fn chat_n(
+        &mut self,
+        message: &str,
+        history: &[String],
+        system_prompt: Option<&str>,
+        n: usize,
+        params: &SamplingParams,
+    ) -> Result<Vec<Generation>> {
+        let system_prompt = system_prompt.map(str::to_string);
+        let prompt =
+            handle_chat_input(self.config.which, message, history, system_prompt.as_ref())?;
+        self.run_n(prompt, n, params)
+    }
+
+    /// Answers the conversation of `request`, the reply may call the tools of the request
+    /// instead of answering.
+    pub fn chat_completion(
+        &mut self,
+        request: &ChatRequest,
+        params: &SamplingParams,
+    ) -> Result<ChatCompletion> {
+        self.chat_completion_stream(request, params, |_| {})
+    }
+
+    /// Like [`chat_completion`](Self::chat_completion), streaming the raw reply to `on_token`.
+    pub fn chat_completion_stream(
+        &mut self,
+        request: &ChatRequest,
+        params: &SamplingParams,
+        on_token: impl Fn(&str),
+    ) -> Result<ChatCompletion> {
+        let tools = match request.tool_choice {
+            ToolChoice::None => &[],
+            ToolChoice::Auto | ToolChoice::Required => request.tools.as_slice(),
+        };
+        let grammar = match request.tool_choice {
+            ToolChoice::Required if tools.is_empty() => {
+                return Err(EdgerunnerError::config(
+                    "a tool call is required but the request has no tools",
+                ))
+            }
+            ToolChoice::Required => Some(ToolCallGrammar::new(
+                tools
+                    .iter()
+                    .map(|tool| tool.function.name.clone())
+                    .collect(),
+            )),
+            ToolChoice::Auto | ToolChoice::None => None,
+        };
+        let prompt = handle_tool_chat(self.config.which, &request.messages, tools)?;
+        let mut generation = self.run(prompt, params, grammar, on_token)?;
+
+        let (content, tool_calls) = if tools.is_empty() {
+            (Some(generation.text.clone()), Vec::new())
+        } else {
+            // ids stay unique across the conversation
+            let first_id = request
+                .messages
+                .iter()
+                .map(|message| message.tool_calls.len())
+                .sum();
+            parse_tool_calls(&generation.text, first_id)
+        };
+        if !tool_calls.is_empty() {
+            generation.finish_reason = FinishReason::ToolCalls;
+        }
+        Ok(ChatCompletion {
+            message: ChatMessage {
+                role: Role::Assistant,
+                content,
+                tool_calls,
+                tool_call_id: None,
+            },
+            generation,
+        })
+    }
+
+    fn run(
+        &mut self,
+        prompt: GeneratedPrompt,
+        params: &SamplingParams,
+        grammar: Option<ToolCallGrammar>,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        self.stop_flag.store(false, Ordering::SeqCst);
+        self.pipeline.set_sampling(params);
+        self.pipeline.set_grammar(grammar);
+        self.pipeline.generate(
+            prompt,
+            params.sample_len,
+            &self.config.which,
+            self.stop_flag.clone(),
+            on_token,
+        )
+    }
+
+    fn run_n(
+        &mut self,
+        prompt: GeneratedPrompt,
+        n: usize,
+        params: &SamplingParams,
+    ) -> Result<Vec<Generation>> {
+        self.stop_flag.store(false, Ordering::SeqCst);
+        self.pipeline.set_sampling(params);
+        self.pipeline.set_grammar(None);
+        self.pipeline.generate_n(
+            prompt,
+            n,
+            params.sample_len,
+            &self.config.which,
+            self.stop_flag.clone(),
+            |_, _| {},
+        )
+    }
+
+    /// Saves the tokens, the sampler state and the kv cache of the last generation.
+    pub fn save_session(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.pipeline.session(self.fingerprint())?.save(path)
+    }
+
+    /// Restores a session saved by the same model, [`resume`](Self::resume) continues it.
+    pub fn load_session(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let session = Session::load(path, self.pipeline.device())?;
+        let fingerprint = &self.fingerprint;
+        if session.model != *fingerprint {
+            return Err(EdgerunnerError::config(format!(
+                "the session was saved with {} ({} bytes, weights {:016x}), not {} ({} bytes, \
+                 weights {:016x})",
+                session.model.model,
+                session.model.size_bytes,
+                session.model.weights_hash,
+                fingerprint.model,
+                fingerprint.size_bytes,
+                fingerprint.weights_hash
+            )));
+        }
+        self.pipeline.restore_session(session)
+    }
+
+    /// Generates `sample_len` more tokens after the last generation or the loaded session, with
+    /// its sampling settings, as if it had not stopped.
+    pub fn resume(&mut self, sample_len: usize) -> Result<Generation> {
+        self.resume_stream(sample_len, |_| {})
+    }
+
+    /// Like [`resume`](Self::resume), streaming the text to `on_token`.
+    pub fn resume_stream(
+        &mut self,
+        sample_len: usize,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        self.stop_flag.store(false, Ordering::SeqCst);
+        self.pipeline.resume(
+            sample_len,
+            &self.config.which,
+            self.stop_flag.clone(),
+            on_token,
+        )
+    }
+
+    /// The token ids the model sees for `text`, including the special tokens such as bos.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
+        self.encode(text, true)
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        let encoding = self
+            .pipeline
+            .tokenizer()
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)
+            .kind(EdgerunnerError::Tokenizer)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(self.tokenize(text)?.len())
+    }
+
+    /// The per-token negative log-likelihood and the perplexity of `text`.
+    pub fn perplexity(&mut self, text: &str, config: &EvalConfig) -> Result<PerplexityReport> {
+        let tokens = self.tokenize(text)?;
+        eval::perplexity(&mut self.pipeline, &tokens, config)
+    }
+
+    /// How likely `continuation` follows `prompt`, to rank the choices of a multiple choice
+    /// question. The continuation is tokenized on its own, so it usually starts with a space.
+    pub fn score(&mut self, prompt: &str, continuation: &str) -> Result<ContinuationScore> {
+        let prompt = self.tokenize(prompt)?;
+        let continuation = self.encode(continuation, false)?;
+        eval::score(&mut self.pipeline, &prompt, &continuation)
+    }
+
+    /// The embedding of `text`, pooled from the last hidden states of the model.
+    pub fn embed(&mut self, text: &str, options: &EmbeddingOptions) -> Result<Vec<f32>> {
+        let mut embeddings = self.embed_batch(&[text], options)?;
+        Ok(embeddings.remove(0))
+    }
+
+    /// The embeddings of `texts`, running `options.batch_size` of them through the model at once.
+    pub fn embed_batch(
+        &mut self,
+        texts: &[&str],
+        options: &EmbeddingOptions,
+    ) -> Result<Vec<Vec<f32>>> {
+        if options.batch_size == 0 {
+            return Err(EdgerunnerError::config("the batch size must be at least 1"));
+        }
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for texts in texts.chunks(options.batch_size) {
+            let batch = texts
+                .iter()
+                .map(|text| self.tokenize(text))
+                .collect::<Result<Vec<_>>>()?;
+            if batch.iter().any(Vec::is_empty) {
+                return Err(EdgerunnerError::config("there is no text to embed"));
+            }
+            let hidden = self.pipeline.hidden_states(&batch)?;
+            let lengths = batch.iter().map(Vec::len).collect::<Vec<_>>();
+            embeddings.extend(embeddings::pool(&hidden, &lengths, options)?);
+        }
+        Ok(embeddings)
+    }
+}
@@ -0,0 +1,181 @@
+use thiserror::Error;
+
+/// The error of every public edgerunner function.
+///
+/// Each variant has a stable [`code`](EdgerunnerError::code) for machines, with the matching
+/// CLI [`exit_code`](EdgerunnerError::exit_code) and [`http_status`](EdgerunnerError::http_status).
+#[derive(Debug, Error)]
+pub enum EdgerunnerError {
+    /// Invalid arguments, settings or config files.
+    #[error("Invalid configuration: {0:#}")]
+    Config(anyhow::Error),
+
+    /// The model or tokenizer could not be fetched from the hub.
+    #[error("Download failed: {0:#}")]
+    Download(anyhow::Error),
+
+    /// The local model cache could not be used.
+    #[error("Cache error: {0:#}")]
+    Cache(anyhow::Error),
+
+    /// The weights could not be read or do not describe a supported model.
+    #[error("Could not load the model: {0:#}")]
+    Load(anyhow::Error),
+
+    #[error("Tokenizer error: {0:#}")]
+    Tokenizer(anyhow::Error),
+
+    /// The requested device could not be opened or used.
+    #[error("Device error: {0:#}")]
+    Device(anyhow::Error),
+
+    /// Running the model failed.
+    #[error("Inference failed: {0:#}")]
+    Inference(anyhow::Error),
+
+    /// The tokens do not fit in the context window of the model.
+    #[error("{tokens} tokens exceed the context length {context_length}")]
+    ContextOverflow {
+        tokens: usize,
+        context_length: usize,
+    },
+
+    #[error("Operation was stopped by the user")]
+    Cancelled,
+
+    /// Reading or writing reports, histories or other files failed.
+    #[error("I/O error: {0:#}")]
+    Io(anyhow::Error),
+}
+
+pub type Result<T, E = EdgerunnerError> = std::result::Result<T, E>;
+
+impl EdgerunnerError {
+    /// A stable identifier of the kind of error, safe to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Config(_) => "config",
+            Self::Download(_) => "download",
+            Self::Cache(_) => "cache",
+            Self::Load(_) => "load",
+            Self::Tokenizer(_) => "tokenizer",
+            Self::Device(_) => "device",
+            Self::Inference(_) => "inference",
+            Self::ContextOverflow { .. } => "context_overflow",
+            Self::Cancelled => "cancelled",
+            Self::Io(_) => "io",
+        }
+    }
+
+    /// The exit status of the CLI, 1 is left to failed checks such as benchmark regressions.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Download(_) => 3,
+            Self::Cache(_) => 4,
+            Self::Load(_) => 5,
+            Self::Tokenizer(_) => 6,
+            Self::Device(_) => 7,
+            Self::Inference(_) => 8,
+            Self::ContextOverflow { .. } => 9,
+            Self::Io(_) => 10,
+            // the shell convention for a process ended by ctrl-c
+            Self::Cancelled => 130,
+        }
+    }
+
+    /// The HTTP status an API should answer with.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::Config(_) | Self::ContextOverflow { .. } => 400,
+            Self::Cancelled => 499,
+            Self::Download(_) => 502,
+            Self::Device(_) => 503,
+            Self::Cache(_)
+            | Self::Load(_)
+            | Self::Tokenizer(_)
+            | Self::Inference(_)
+            | Self::Io(_) => 500,
+        }
+    }
+
+    pub fn config(message: impl std::fmt::Display) -> Self {
+        Self::Config(anyhow::Error::msg(message.to_string()))
+    }
+
+    pub fn load(message: impl std::fmt::Display) -> Self {
+        Self::Load(anyhow::Error::msg(message.to_string()))
+    }
+
+    pub fn inference(message: impl std::fmt::Display) -> Self {
+        Self::Inference(anyhow::Error::msg(message.to_string()))
+    }
+}
+
+impl From<candle_core::Error> for EdgerunnerError {
+    fn from(e: candle_core::Error) -> Self {
+        Self::Inference(e.into())
+    }
+}
+
+impl From<std::io::Error> for EdgerunnerError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e.into())
+    }
+}
+
+/// Gives the errors of other crates an edgerunner kind, as in `.kind(EdgerunnerError::Load)?`.
+pub trait ResultExt<T> {
+    fn kind(self, kind: fn(anyhow::Error) -> EdgerunnerError) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn kind(self, kind: fn(anyhow::Error) -> EdgerunnerError) -> Result<T> {
+        self.map_err(|e| kind(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_distinct() {
+        let errors = [
+            EdgerunnerError::config("c"),
+            EdgerunnerError::Download(anyhow::Error::msg("d")),
+            EdgerunnerError::Cache(anyhow::Error::msg("c")),
+            EdgerunnerError::load("l"),
+            EdgerunnerError::Tokenizer(anyhow::Error::msg("t")),
+            EdgerunnerError::Device(anyhow::Error::msg("d")),
+            EdgerunnerError::inference("i"),
+            EdgerunnerError::ContextOverflow {
+                tokens: 10,
+                context_length: 8,
+            },
+            EdgerunnerError::Cancelled,
+            EdgerunnerError::Io(anyhow::Error::msg("i")),
+        ];
+        let mut codes = errors.iter().map(|e| e.code()).collect::<Vec<_>>();
+        let mut exit_codes = errors.iter().map(|e| e.exit_code()).collect::<Vec<_>>();
+        codes.sort();
+        codes.dedup();
+        exit_codes.sort();
+        exit_codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(exit_codes.len(), errors.len());
+        assert!(!exit_codes.contains(&0) && !exit_codes.contains(&1));
+    }
+
+    #[test]
+    fn test_kind_keeps_the_cause() {
+        let result: std::result::Result<(), std::io::Error> = Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no model",
+        ));
+        let error = result.kind(EdgerunnerError::Load).unwrap_err();
+        assert_eq!(error.code(), "load");
+        assert_eq!(error.to_string(), "Could not load the model: no model");
+        assert_eq!(error.http_status(), 500);
+    }
+}
@@ -1,11 +1,36 @@
-use clap::{value_parser, Arg, Command};
+use std::{collections::HashMap, io::Read};
 
-use conf::{model::InferenceConfig, which::Which};
-use model::loader::LoadModel;
+use candle_core::quantized::GgmlDType;
+use clap::{parser::ValueSource, value_parser, Arg, ArgAction, ArgMatches, Command};
 
+use conf::{
+    model::{InferenceConfig, WeightsDType},
+    which::Which,
+};
+use error::{EdgerunnerError, Result, ResultExt};
+use model::{loader::LoadModel, prompt::fill_template};
+use rag::ChunkConfig;
+use recommend::RecommendationRequest;
+use runner::{
+    benchmark::BenchConfig,
+    device::DeviceSpec,
+    embeddings::{EmbeddingFormat, EmbeddingOptions, Pooling},
+    eval::EvalConfig,
+    output::OutputFormat,
+    prefix_cache::PrefixCacheConfig,
+    speculative::SpeculativeConfig,
+    threads::{parse_core_list, ThreadConfig},
+};
+use system_benchmark::{parse_ggml_dtype, BenchmarkConfig};
+
+pub mod bench_history;
 pub mod conf;
+pub mod engine;
+pub mod error;
 pub mod log_util;
 pub mod model;
+pub mod rag;
+pub mod recommend;
 pub mod runner;
 pub mod system_benchmark;
 pub mod util;
@@ -13,15 +38,494 @@ pub mod util;
 #[derive(Debug)]
 pub struct ArgsResult {
     pub prompt: String,
+    /// The system prompt of the chat, the template default when unset.
+    pub system_prompt: Option<String>,
     pub config: InferenceConfig,
+    pub skip_benchmark: bool,
+    pub command: CliCommand,
+}
+
+#[derive(Debug)]
+pub enum CliCommand {
+    /// Generate a response to the prompt, or continue the session loaded from `load_session`,
+    /// optionally saving the session to `save_session` afterwards. `quiet` prints the result
+    /// alone.
+    Generate {
+        load_session: Option<String>,
+        save_session: Option<String>,
+        output: OutputFormat,
+        quiet: bool,
+    },
+    /// Measure the inference throughput of the model, optionally writing the JSON report to a file.
+    /// The run is recorded in the benchmark history, the default one when `history` is unset.
+    Bench {
+        config: BenchConfig,
+        report: Option<String>,
+        history: Option<String>,
+    },
+    /// Run the system microbenchmarks, optionally writing the JSON report to a file.
+    BenchKernels {
+        config: BenchmarkConfig,
+        report: Option<String>,
+        history: Option<String>,
+    },
+    /// Compare two runs of the benchmark history, by default the last one to the one before.
+    BenchCompare {
+        baseline: Option<usize>,
+        candidate: Option<usize>,
+        threshold_percent: f64,
+        history: Option<String>,
+    },
+    /// Embed the texts, or the lines of `input`, and write the vectors as JSONL or NPY to `output`,
+    /// JSONL goes to stdout when no output is given.
+    Embed {
+        texts: Vec<String>,
+        input: Option<String>,
+        options: EmbeddingOptions,
+        format: EmbeddingFormat,
+        output: Option<String>,
+    },
+    /// Compute the perplexity of the model over the text of `input`, optionally writing the JSON
+    /// report with the per-token losses to a file.
+    Eval {
+        input: String,
+        config: EvalConfig,
+        report: Option<String>,
+    },
+    /// Chunk, embed and index the text and markdown files of `dir` in the `index` file.
+    RagIngest {
+        dir: String,
+        index: String,
+        chunking: ChunkConfig,
+    },
+    /// Answer the question with the `top_k` most similar chunks of the `index` as cited context.
+    RagAsk {
+        question: String,
+        index: String,
+        top_k: usize,
+    },
+    /// Recommend the model that suits the machine, optionally writing the JSON report to a file.
+    Recommend {
+        request: RecommendationRequest,
+        report: Option<String>,
+    },
+}
+
+pub fn is_model_cached(which: &Which) -> Result<bool> {
+    LoadModel::check_cache(which, None)
+}
+
+fn bench_command() -> Command {
+    Command::new("bench")
+        .about("Measure prefill and decode throughput of the model")
+        .args_conflicts_with_subcommands(true)
+        .subcommand(kernels_command())
+        .subcommand(compare_command())
+        .arg(
+            Arg::new("history")
+                .long("history")
+                .value_name("PATH")
+                .help("The benchmark history file, defaults to one in the config folder")
+                .value_parser(value_parser!(String))
+                .global(true),
+        )
+        .arg(
+            Arg::new("prompt-lengths")
+                .long("prompt-lengths")
+                .value_name("TOKENS")
+                .help("Comma separated prompt lengths to measure the prefill at")
+                .value_delimiter(',')
+                .value_parser(value_parser!(usize))
+                .default_value("16,64,256"),
+        )
+        .arg(
+            Arg::new("decode-tokens")
+                .long("decode-tokens")
+                .value_name("TOKENS")
+                .help("The number of tokens generated to measure the decode")
+                .value_parser(value_parser!(usize))
+                .default_value("64"),
+        )
+        .arg(
+            Arg::new("repetitions")
+                .short('r')
+                .long("repetitions")
+                .value_name("N")
+                .help("The number of measured runs of every scenario")
+                .value_parser(value_parser!(usize))
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("warmup")
+                .long("warmup")
+                .value_name("N")
+                .help("The number of unmeasured warm up runs of every scenario")
+                .value_parser(value_parser!(usize))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .help("Also write the JSON report to this file")
+                .value_parser(value_parser!(String)),
+        )
+}
+
+fn kernels_command() -> Command {
+    Command::new("kernels")
+        .about("Measure matmul, quantized matmul, memory bandwidth and thread scaling")
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seeds the benchmark tensors")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("dtypes")
+                .long("dtypes")
+                .value_name("DTYPES")
+                .help("Comma separated quantized dtypes to measure, e.g. q4_0,q4_k,q8_0")
+                .value_delimiter(',')
+                .value_parser(|s: &str| parse_ggml_dtype(s).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("THREADS")
+                .help("Comma separated CPU thread counts to measure the scaling with")
+                .value_delimiter(',')
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("iterations")
+                .long("iterations")
+                .value_name("N")
+                .help("The number of timed iterations of every benchmark")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .help("Also write the JSON report to this file")
+                .value_parser(value_parser!(String)),
+        )
+}
+
+fn compare_command() -> Command {
+    Command::new("compare")
+        .about("Compare two benchmark runs and fail on regressions beyond the threshold")
+        .arg(
+            Arg::new("baseline")
+                .value_name("BASELINE")
+                .help("The id of the baseline run, defaults to the run before the candidate")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("candidate")
+                .value_name("CANDIDATE")
+                .help("The id of the candidate run, defaults to the last run")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("threshold")
+                .long("threshold")
+                .value_name("PERCENT")
+                .help("How much worse a metric may get before it is a regression")
+                .value_parser(value_parser!(f64))
+                .default_value("5"),
+        )
+}
+
+fn embed_command() -> Command {
+    Command::new("embed")
+        .about("Embed texts with the hidden states of the model")
+        .arg(
+            Arg::new("texts")
+                .value_name("TEXT")
+                .help("The texts to embed")
+                .num_args(0..)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("PATH")
+                .help("A file with one text to embed per line")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new("pooling")
+                .long("pooling")
+                .value_name("POOLING")
+                .help("How the token states are reduced to one vector")
+                .value_parser(value_parser!(Pooling))
+                .default_value("mean"),
+        )
+        .arg(
+            Arg::new("no-normalize")
+                .long("no-normalize")
+                .help("Keep the pooled vectors instead of scaling them to unit length")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("batch-size")
+                .long("batch-size")
+                .value_name("N")
+                .help("The number of texts run through the model at once")
+                .value_parser(value_parser!(usize))
+                .default_value("8"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("The output format")
+                .value_parser(value_parser!(EmbeddingFormat))
+                .default_value("jsonl"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("PATH")
+                .help("The file to write, required for npy")
+                .value_parser(value_parser!(String)),
+        )
+}
+
+fn eval_command() -> Command {
+    Command::new("eval")
+        .about("Compute the per-token log-likelihood and perplexity of a text")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("PATH")
+                .help("The text file to score")
+                .value_parser(value_parser!(String))
+                .required(true),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .value_name("TOKENS")
+                .help("The tokens the model sees at once, defaults to the context length")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("stride")
+                .long("stride")
+                .value_name("TOKENS")
+                .help("The tokens the window moves by, defaults to half the window")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .help("Write the JSON report with the per-token losses to this file")
+                .value_parser(value_parser!(String)),
+        )
+}
+
+fn rag_command() -> Command {
+    let index = Arg::new("index")
+        .long("index")
+        .value_name("PATH")
+        .help("The index file")
+        .value_parser(value_parser!(String))
+        .required(true);
+    Command::new("rag")
+        .about("Answer questions from local documents")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("ingest")
+                .about("Chunk, embed and index the text and markdown files of a directory")
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .help("The directory of the documents")
+                        .value_parser(value_parser!(String))
+                        .required(true),
+                )
+                .arg(index.clone())
+                .arg(
+                    Arg::new("chunk-words")
+                        .long("chunk-words")
+                        .value_name("WORDS")
+                        .help("The number of words of a chunk")
+                        .value_parser(value_parser!(usize))
+                        .default_value("200"),
+                )
+                .arg(
+                    Arg::new("overlap-words")
+                        .long("overlap-words")
+                        .value_name("WORDS")
+                        .help("The number of words consecutive chunks share")
+                        .value_parser(value_parser!(usize))
+                        .default_value("40"),
+                ),
+        )
+        .subcommand(
+            Command::new("ask")
+                .about("Answer a question with the most similar chunks as cited context")
+                .arg(
+                    Arg::new("question")
+                        .value_name("QUESTION")
+                        .help("The question to answer")
+                        .num_args(1..)
+                        .value_parser(value_parser!(String))
+                        .required(true),
+                )
+                .arg(index)
+                .arg(
+                    Arg::new("top-k")
+                        .short('k')
+                        .long("top-k")
+                        .value_name("K")
+                        .help("The number of chunks given to the model")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4"),
+                ),
+        )
+}
+
+fn recommend_command() -> Command {
+    Command::new("recommend")
+        .about("Benchmark the machine and recommend the model that suits it")
+        .arg(
+            Arg::new("tokens-per-second")
+                .long("tokens-per-second")
+                .value_name("TOKENS")
+                .help("The minimum decode speed the model has to reach")
+                .value_parser(value_parser!(f64))
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("memory-budget")
+                .long("memory-budget")
+                .value_name("GB")
+                .help("The memory the model may use instead of the detected available memory")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("context")
+                .long("context")
+                .value_name("TOKENS")
+                .help("The context length the kv cache is sized for")
+                .value_parser(value_parser!(usize))
+                .default_value("4096"),
+        )
+        .arg(
+            Arg::new("all-models")
+                .long("all-models")
+                .help("Also consider the models that are not supported yet")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .help("Write the JSON recommendation to this file")
+                .value_parser(value_parser!(String)),
+        )
+}
+
+/// Parses a `--var` template variable, `name=value`.
+fn parse_var(s: &str) -> std::result::Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("{s} is not name=value"))
+}
+
+/// The variables of the prompt templates, those of `--var` over those of the `--vars` file.
+fn template_vars(matches: &ArgMatches) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    if let Some(path) = matches.get_one::<String>("vars") {
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path)?).kind(EdgerunnerError::Config)?;
+        let object = json.as_object().ok_or_else(|| {
+            EdgerunnerError::config(format!("the variables of {path} are not a JSON object"))
+        })?;
+        vars.extend(object.iter().map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            (name.clone(), value)
+        }));
+    }
+    vars.extend(
+        matches
+            .get_many::<(String, String)>("var")
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    Ok(vars)
+}
+
+/// Reads a value that has a default, a missing one is a bug in the command definition.
+fn required<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str) -> Result<T> {
+    matches
+        .get_one::<T>(id)
+        .cloned()
+        .ok_or_else(|| EdgerunnerError::config(format!("missing argument {}", id)))
+}
+
+/// Parses the command line of the process, a piped stdin is the prompt when none is given.
+pub fn get_args() -> Result<ArgsResult> {
+    let stdin = std::io::stdin();
+    let piped = stdin_is_piped().then(|| stdin.lock());
+    get_args_with_input(std::env::args_os(), piped)
+}
+
+/// Whether stdin is a pipe or a redirected file. Terminals, sockets and the like that a parent
+/// process may leave open without ever writing to are not read unless `--prompt-file -` asks.
+#[cfg(unix)]
+fn stdin_is_piped() -> bool {
+    use std::os::{fd::AsFd, unix::fs::FileTypeExt};
+    let Ok(fd) = std::io::stdin().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    std::fs::File::from(fd)
+        .metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_fifo() || metadata.is_file())
 }
 
-pub fn is_model_cached(which: &Which) -> bool {
-    LoadModel::check_cache(which, None).unwrap()
+#[cfg(not(unix))]
+fn stdin_is_piped() -> bool {
+    use std::io::IsTerminal;
+    !std::io::stdin().is_terminal()
 }
 
-pub fn get_args() -> anyhow::Result<ArgsResult> {
+/// Parses the command line, clap errors, including `--help`, are kept as the source of the
+/// [`EdgerunnerError::Config`] so that the CLI can print them the clap way.
+pub fn get_args_from<I, T>(args: I) -> Result<ArgsResult>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    get_args_with_input(args, None::<std::io::Empty>)
+}
+
+/// Like [`get_args_from`], reading the prompt from `input` when the command line does not give
+/// one to generate from.
+pub fn get_args_with_input<I, T>(args: I, input: Option<impl Read>) -> Result<ArgsResult>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
     let matches = Command::new("runner")
+        .subcommand(bench_command())
+        .subcommand(embed_command())
+        .subcommand(eval_command())
+        .subcommand(rag_command())
+        .subcommand(recommend_command())
         .arg(
             Arg::new("model")
                 .short('m')
@@ -29,7 +533,107 @@ pub fn get_args() -> anyhow::Result<ArgsResult> {
                 .value_name("MODEL")
                 .help("The model to use")
                 .value_parser(value_parser!(Which))
-                .default_value("7b-mistral-instruct"),
+                .default_value("7b-mistral-instruct")
+                .global(true),
+        )
+        .arg(
+            Arg::new("model-path")
+                .long("model-path")
+                .value_name("PATH")
+                .help("A local gguf/ggml file or safetensors checkpoint directory to load")
+                .value_parser(value_parser!(String))
+                .global(true),
+        )
+        .arg(
+            Arg::new("dtype")
+                .long("dtype")
+                .value_name("DTYPE")
+                .help("The precision used to load safetensors weights")
+                .value_parser(value_parser!(WeightsDType))
+                .default_value("f32")
+                .global(true),
+        )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .value_name("DEVICE")
+                .help("The device to run on: auto, cpu, cuda:N or metal:N, missing devices fall back to the cpu")
+                .value_parser(|s: &str| s.parse::<DeviceSpec>().map_err(|e| e.to_string()))
+                .default_value("auto")
+                .global(true),
+        )
+        .arg(
+            Arg::new("prefill-threads")
+                .long("prefill-threads")
+                .value_name("N")
+                .help("The cpu threads processing the prompt, defaults to all cores")
+                .value_parser(value_parser!(usize))
+                .global(true),
+        )
+        .arg(
+            Arg::new("decode-threads")
+                .long("decode-threads")
+                .value_name("N")
+                .help("The cpu threads generating tokens, defaults to all cores")
+                .value_parser(value_parser!(usize))
+                .global(true),
+        )
+        .arg(
+            Arg::new("pin-cores")
+                .long("pin-cores")
+                .value_name("CORES")
+                .help("Pin the cpu threads to these cores, e.g. 0-3,8 (linux only)")
+                .value_parser(|s: &str| parse_core_list(s).map_err(|e| e.to_string()))
+                .global(true),
+        )
+        .arg(
+            Arg::new("tokenizer")
+                .long("tokenizer")
+                .value_name("PATH")
+                .help("A local tokenizer.json to use instead of the hub tokenizer")
+                .value_parser(value_parser!(String))
+                .global(true),
+        )
+        .arg(
+            Arg::new("sample-len")
+                .short('n')
+                .long("sample-len")
+                .value_name("TOKENS")
+                .help("The maximum number of tokens to generate")
+                .value_parser(value_parser!(usize))
+                .global(true),
+        )
+        .arg(
+            Arg::new("prompt-cache")
+                .long("prompt-cache")
+                .value_name("DIR")
+                .help("Keep the kv cache of prompts in this directory to skip the prefill of shared prefixes")
+                .value_parser(value_parser!(String))
+                .global(true),
+        )
+        .arg(
+            Arg::new("draft-model")
+                .long("draft-model")
+                .value_name("PATH")
+                .help("A smaller model sharing the vocabulary that drafts tokens for speculative decoding")
+                .value_parser(value_parser!(String))
+                .global(true),
+        )
+        .arg(
+            Arg::new("draft-tokens")
+                .long("draft-tokens")
+                .value_name("TOKENS")
+                .help("The tokens the draft model proposes before every check")
+                .value_parser(value_parser!(usize))
+                .default_value("4")
+                .global(true),
+        )
+        .arg(
+            Arg::new("skip-benchmark")
+                .long("skip-benchmark")
+                .help("Do not estimate the device TFLOPS before running")
+                .action(ArgAction::SetTrue)
+                .global(true),
         )
         .arg(
             Arg::new("prompt")
@@ -38,25 +642,288 @@ pub fn get_args() -> anyhow::Result<ArgsResult> {
                 .value_name("PROMPT")
                 .num_args(1..)
                 .value_parser(value_parser!(String))
-                .help("The prompt to use")
+                .help("The prompt to use, read from stdin when it is piped and this is not given")
                 .default_value("How does this work?"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("prompt-file")
+                .long("prompt-file")
+                .value_name("PATH")
+                .help("Read the prompt from this file, or from stdin when it is -")
+                .value_parser(value_parser!(String))
+                .conflicts_with("prompt"),
+        )
+        .arg(
+            Arg::new("system")
+                .long("system")
+                .value_name("PROMPT")
+                .num_args(1..)
+                .value_parser(value_parser!(String))
+                .help("The system prompt of the chat"),
+        )
+        .arg(
+            Arg::new("system-file")
+                .long("system-file")
+                .value_name("PATH")
+                .help("Read the system prompt from this file")
+                .value_parser(value_parser!(String))
+                .conflicts_with("system"),
+        )
+        .arg(
+            Arg::new("var")
+                .long("var")
+                .value_name("NAME=VALUE")
+                .help("Fill the {{NAME}} placeholders of the prompts with VALUE")
+                .value_parser(parse_var)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("vars")
+                .long("vars")
+                .value_name("PATH")
+                .help("A JSON object of the values of the placeholders, --var values take precedence")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new("save-session")
+                .long("save-session")
+                .value_name("PATH")
+                .help("Save the tokens, sampler state and kv cache of the generation to this file")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new("load-session")
+                .long("load-session")
+                .value_name("PATH")
+                .help("Continue the generation saved in this file instead of answering the prompt")
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Print the text, a JSON summary, or a JSON event per token and a summary")
+                .value_parser(value_parser!(OutputFormat))
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Print the result alone, without the TFLOPS estimation and the stats")
+                .action(ArgAction::SetTrue),
+        )
+        .try_get_matches_from(args)
+        .kind(EdgerunnerError::Config)?;
+
+    let model = required::<Which>(&matches, "model")?;
+    let model_path = matches.get_one::<String>("model-path").cloned();
+    let dtype = required::<WeightsDType>(&matches, "dtype")?;
+    let tokenizer = matches.get_one::<String>("tokenizer").cloned();
+    let device = required::<DeviceSpec>(&matches, "device")?;
+    let threads = ThreadConfig {
+        prefill_threads: matches.get_one::<usize>("prefill-threads").copied(),
+        decode_threads: matches.get_one::<usize>("decode-threads").copied(),
+        pin_cores: matches.get_one::<Vec<usize>>("pin-cores").cloned(),
+    };
+    let prefix_cache = matches
+        .get_one::<String>("prompt-cache")
+        .map(|dir| PrefixCacheConfig {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        });
+    let speculative =
+        matches
+            .get_one::<String>("draft-model")
+            .map(|draft_model| SpeculativeConfig {
+                draft_model: draft_model.clone(),
+                draft_tokens: matches
+                    .get_one::<usize>("draft-tokens")
+                    .copied()
+                    .unwrap_or(4),
+            });
+    let defaults = InferenceConfig::default();
+    let sample_len = matches
+        .get_one::<usize>("sample-len")
+        .copied()
+        .unwrap_or(defaults.sample_len);
 
-    let model = matches.get_one::<Which>("model").unwrap();
+    let command = match matches.subcommand() {
+        Some(("bench", bench)) => match bench.subcommand() {
+            Some(("kernels", kernels)) => {
+                let defaults = BenchmarkConfig::default();
+                CliCommand::BenchKernels {
+                    config: BenchmarkConfig {
+                        seed: kernels
+                            .get_one::<u64>("seed")
+                            .copied()
+                            .unwrap_or(defaults.seed),
+                        quantized_dtypes: kernels
+                            .get_many::<GgmlDType>("dtypes")
+                            .map(|dtypes| dtypes.copied().collect())
+                            .unwrap_or(defaults.quantized_dtypes),
+                        thread_counts: kernels
+                            .get_many::<usize>("threads")
+                            .map(|threads| threads.copied().collect())
+                            .unwrap_or(defaults.thread_counts),
+                        iterations: kernels
+                            .get_one::<usize>("iterations")
+                            .copied()
+                            .unwrap_or(defaults.iterations),
+                        ..defaults
+                    },
+                    report: kernels.get_one::<String>("report").cloned(),
+                    history: kernels.get_one::<String>("history").cloned(),
+                }
+            }
+            Some(("compare", compare)) => CliCommand::BenchCompare {
+                baseline: compare.get_one::<usize>("baseline").copied(),
+                candidate: compare.get_one::<usize>("candidate").copied(),
+                threshold_percent: required(compare, "threshold")?,
+                history: compare.get_one::<String>("history").cloned(),
+            },
+            _ => CliCommand::Bench {
+                config: BenchConfig {
+                    prompt_lengths: bench
+                        .get_many::<usize>("prompt-lengths")
+                        .map(|lengths| lengths.copied().collect())
+                        .unwrap_or_default(),
+                    decode_tokens: required(bench, "decode-tokens")?,
+                    repetitions: required(bench, "repetitions")?,
+                    warmup_repetitions: required(bench, "warmup")?,
+                    seed: defaults.seed,
+                },
+                report: bench.get_one::<String>("report").cloned(),
+                history: bench.get_one::<String>("history").cloned(),
+            },
+        },
+        Some(("embed", embed)) => CliCommand::Embed {
+            texts: embed
+                .get_many::<String>("texts")
+                .map(|texts| texts.cloned().collect())
+                .unwrap_or_default(),
+            input: embed.get_one::<String>("input").cloned(),
+            options: EmbeddingOptions {
+                pooling: required(embed, "pooling")?,
+                normalize: !embed.get_flag("no-normalize"),
+                batch_size: required(embed, "batch-size")?,
+            },
+            format: required(embed, "format")?,
+            output: embed.get_one::<String>("output").cloned(),
+        },
+        Some(("eval", eval)) => CliCommand::Eval {
+            input: required(eval, "input")?,
+            config: EvalConfig {
+                window: eval.get_one::<usize>("window").copied(),
+                stride: eval.get_one::<usize>("stride").copied(),
+            },
+            report: eval.get_one::<String>("report").cloned(),
+        },
+        Some(("rag", rag)) => match rag.subcommand() {
+            Some(("ingest", ingest)) => CliCommand::RagIngest {
+                dir: required(ingest, "dir")?,
+                index: required(ingest, "index")?,
+                chunking: ChunkConfig {
+                    chunk_words: required(ingest, "chunk-words")?,
+                    overlap_words: required(ingest, "overlap-words")?,
+                },
+            },
+            Some(("ask", ask)) => CliCommand::RagAsk {
+                question: ask
+                    .get_many::<String>("question")
+                    .map(|words| words.cloned().collect::<Vec<_>>().join(" "))
+                    .unwrap_or_default(),
+                index: required(ask, "index")?,
+                top_k: required(ask, "top-k")?,
+            },
+            _ => return Err(EdgerunnerError::config("missing rag command")),
+        },
+        Some(("recommend", recommend)) => CliCommand::Recommend {
+            request: RecommendationRequest {
+                min_tokens_per_second: required(recommend, "tokens-per-second")?,
+                memory_budget_bytes: recommend
+                    .get_one::<f64>("memory-budget")
+                    .map(|gb| (gb * 1e9) as u64),
+                context_length: required(recommend, "context")?,
+                only_available: !recommend.get_flag("all-models"),
+            },
+            report: recommend.get_one::<String>("report").cloned(),
+        },
+        _ => CliCommand::Generate {
+            load_session: matches.get_one::<String>("load-session").cloned(),
+            save_session: matches.get_one::<String>("save-session").cloned(),
+            output: required(&matches, "output")?,
+            quiet: matches.get_flag("quiet"),
+        },
+    };
 
     let prompt_values = matches
         .get_many::<String>("prompt")
-        .unwrap()
-        .cloned()
-        .collect::<Vec<String>>();
-    let prompt = prompt_values.join(" "); //
+        .map(|values| values.cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+    let mut prompt = prompt_values.join(" ");
+    if let Some(path) = matches.get_one::<String>("prompt-file") {
+        prompt = match (path.as_str(), input) {
+            ("-", Some(mut input)) => {
+                let mut piped = String::new();
+                input.read_to_string(&mut piped)?;
+                piped
+            }
+            ("-", None) => std::io::read_to_string(std::io::stdin())?,
+            _ => std::fs::read_to_string(path)?,
+        }
+        .trim_end()
+        .to_string();
+    } else if matches.value_source("prompt") != Some(ValueSource::CommandLine)
+        && matches!(
+            command,
+            CliCommand::Generate {
+                load_session: None,
+                ..
+            }
+        )
+    {
+        // an empty stdin, such as /dev/null, keeps the default prompt
+        if let Some(mut input) = input {
+            let mut piped = String::new();
+            input.read_to_string(&mut piped)?;
+            if !piped.trim().is_empty() {
+                prompt = piped.trim_end().to_string();
+            }
+        }
+    }
+    let mut system_prompt = match matches.get_one::<String>("system-file") {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim_end().to_string()),
+        None => matches
+            .get_many::<String>("system")
+            .map(|words| words.cloned().collect::<Vec<_>>().join(" ")),
+    };
+
+    // prompts are only templates when there are variables, so that braces stay as they are
+    let vars = template_vars(&matches)?;
+    if !vars.is_empty() {
+        prompt = fill_template(&prompt, &vars)?;
+        system_prompt = system_prompt
+            .map(|system_prompt| fill_template(&system_prompt, &vars))
+            .transpose()?;
+    }
 
     Ok(ArgsResult {
         prompt,
+        system_prompt,
         config: InferenceConfig {
-            which: *model,
-            ..Default::default()
+            which: model,
+            model: model_path,
+            dtype,
+            tokenizer,
+            sample_len,
+            device,
+            threads,
+            prefix_cache,
+            speculative,
+            ..defaults
         },
+        skip_benchmark: matches.get_flag("skip-benchmark"),
+        command,
     })
 }
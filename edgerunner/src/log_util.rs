@@ -14,7 +14,8 @@ fn colored_level(style: &mut Style, level: Level) -> StyledValue<'_, &'static st
 pub fn set_env_logger() {
     use std::io::Write;
 
-    env_logger::Builder::from_default_env()
+    // warnings, such as a device falling back to the cpu, are shown unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
         .format(|f, record| {
             let mut style = f.style();
             let level = colored_level(&mut style, record.level());
@@ -27,7 +28,7 @@ pub fn set_env_logger() {
                 "{} {}: {} {}: {}\n{}",
                 level,
                 file,
-                record.file().unwrap(),
+                record.file().unwrap_or("unknown"),
                 module,
                 record.target(),
                 record.args()
@@ -8,89 +8,509 @@ use std::{
     time::Duration,
 };
 
+use clap::ValueEnum;
 use edgerunner::{
+    bench_history::{compare, BenchHistory, BenchRecord},
+    conf::which::Which,
+    engine::Engine,
+    error::{EdgerunnerError, Result, ResultExt},
     get_args,
     log_util::set_env_logger,
-    model::{loader::LoadModel, prompt::handle_user_input},
-    runner::text_generation::TextGeneration,
-    system_benchmark::{estimate_tflops, DeviceName},
+    model::loader::LoadModel,
+    rag::{self, RagIndex},
+    recommend::{recommend_model, HardwareProfile, RecommendationRequest},
+    runner::{
+        benchmark::{run_inference_benchmark, BenchConfig, BenchReport},
+        embeddings::{write_jsonl, write_npy, EmbeddingFormat, EmbeddingOptions},
+        eval::EvalConfig,
+        events::EngineEvent,
+        output::{GenerationSummary, OutputEvent, OutputFormat},
+        threads::ThreadPools,
+    },
+    system_benchmark::{
+        estimate_tflops, run_system_benchmarks, BenchDevice, BenchmarkConfig, DeviceName,
+    },
+    ArgsResult, CliCommand,
 };
 use log::debug;
 
 fn main() {
     set_env_logger();
 
-    // Estimate TFLOPS
-    // when cuda or metal is enabled, it will estimate the TFLOPS for the GPU and CPU
-    let tflops_results = estimate_tflops().expect("Failed to estimate TFLOPS");
-    if !tflops_results.is_empty() {
+    let stop_flag = Arc::new(AtomicBool::new(false));
+
+    // uncomment to simulate stop flag
+    // let stop_flag_clone = stop_flag.clone();
+    // simulate_stop_flag(stop_flag_clone);
+
+    if let Err(e) = get_args().and_then(|args| run(args, stop_flag)) {
+        exit_with(e);
+    }
+}
+
+fn run(args: ArgsResult, stop_flag: Arc<AtomicBool>) -> Result<()> {
+    match args.command {
+        CliCommand::Generate {
+            ref load_session,
+            ref save_session,
+            output,
+            quiet,
+        } => generate(
+            &args,
+            load_session.as_deref(),
+            save_session.as_deref(),
+            output,
+            quiet,
+            stop_flag,
+        ),
+        CliCommand::Bench {
+            ref config,
+            ref report,
+            ref history,
+        } => bench(&args, config, report.as_deref(), history.as_deref()),
+        CliCommand::BenchKernels {
+            ref config,
+            ref report,
+            ref history,
+        } => {
+            log_simd_flags();
+            let kernels = run_system_benchmarks(config)?;
+            record_history(
+                &BenchRecord::from_kernels_report(&kernels, config),
+                history.as_deref(),
+            )?;
+            write_report(
+                &serde_json::to_string_pretty(&kernels).kind(EdgerunnerError::Io)?,
+                report.as_deref(),
+            )
+        }
+        CliCommand::BenchCompare {
+            baseline,
+            candidate,
+            threshold_percent,
+            ref history,
+        } => compare_runs(baseline, candidate, threshold_percent, history.as_deref()),
+        CliCommand::Embed {
+            ref texts,
+            ref input,
+            ref options,
+            format,
+            ref output,
+        } => embed(
+            &args,
+            texts,
+            input.as_deref(),
+            options,
+            format,
+            output.as_deref(),
+        ),
+        CliCommand::Eval {
+            ref input,
+            ref config,
+            ref report,
+        } => eval(&args, input, config, report.as_deref()),
+        CliCommand::RagIngest {
+            ref dir,
+            ref index,
+            ref chunking,
+        } => {
+            let mut engine = Engine::builder().config(args.config.clone()).build()?;
+            let rag_index =
+                RagIndex::ingest(&mut engine, dir, chunking, &EmbeddingOptions::default())?;
+            rag_index.save(index)?;
+            println!("Indexed {} chunks in {}", rag_index.chunks.len(), index);
+            Ok(())
+        }
+        CliCommand::RagAsk {
+            ref question,
+            ref index,
+            top_k,
+        } => rag_ask(&args, question, index, top_k, stop_flag),
+        CliCommand::Recommend {
+            ref request,
+            ref report,
+        } => recommend(request, report.as_deref()),
+    }
+}
+
+/// Prints the error and exits with its stable exit code, clap errors print usage or help.
+fn exit_with(e: EdgerunnerError) -> ! {
+    if let EdgerunnerError::Config(source) = &e {
+        if let Some(clap_error) = source.downcast_ref::<clap::Error>() {
+            clap_error.exit();
+        }
+    }
+    eprintln!("Error [{}]: {}", e.code(), e);
+    std::process::exit(e.exit_code());
+}
+
+fn generate(
+    args: &ArgsResult,
+    load_session: Option<&str>,
+    save_session: Option<&str>,
+    output: OutputFormat,
+    quiet: bool,
+    stop_flag: Arc<AtomicBool>,
+) -> Result<()> {
+    if !args.skip_benchmark && !quiet {
+        // Estimate TFLOPS
+        // when cuda or metal is enabled, it will estimate the TFLOPS for the GPU and CPU
+        let tflops_results = estimate_tflops()?;
         for (name, tflops) in tflops_results {
-            match name {
-                DeviceName::CPU => println!("CPU TFLOPS: {:.2}", tflops),
-                DeviceName::GPU => println!("GPU TFLOPS: {:.2}", tflops),
+            let line = match name {
+                DeviceName::CPU => format!("CPU TFLOPS: {:.2}", tflops),
+                DeviceName::GPU => format!("GPU TFLOPS: {:.2}", tflops),
+            };
+            // stdout is kept for the JSON
+            match output {
+                OutputFormat::Text => println!("{line}"),
+                OutputFormat::Json | OutputFormat::JsonlStream => eprintln!("{line}"),
             }
         }
     }
 
-    let stop_flag = Arc::new(AtomicBool::new(false));
+    log_simd_flags();
 
-    // uncomment to simulate stop flag
-    // let stop_flag_clone = stop_flag.clone();
-    // simulate_stop_flag(stop_flag_clone);
+    // let system_prompt = "The following is a conversation with an AI assistant. The assistant is helpful, creative, clever, and very friendly.\n\nHuman: Hello, who are you?\nAI: I am an AI created by OpenAI. How can I help you today?\nHuman:";
+
+    debug!("Args: {:?}", args);
+    // whether the observer streamed the text that the next call of `print_token` prints
+    let token_text = Arc::new(AtomicBool::new(false));
+    let observer = token_text.clone();
+    // if model is not set in config it uses the which model details
+    let mut engine = Engine::builder()
+        .config(args.config.clone())
+        .stop_flag(stop_flag)
+        .observer(Arc::new(move |event: &EngineEvent| {
+            if let (OutputFormat::JsonlStream, EngineEvent::TokenGenerated { index, id, text }) =
+                (output, event)
+            {
+                // tokens without text yet, such as the first bytes of a character, have an
+                // empty delta
+                print_json(&OutputEvent::Token {
+                    delta: text.clone().unwrap_or_default(),
+                    index: *index,
+                    id: *id,
+                });
+                observer.store(text.is_some(), Ordering::SeqCst);
+            }
+        }))
+        .build()?;
+
+    let print_token = |t: &str| {
+        match output {
+            OutputFormat::Text => print!("{t}"),
+            OutputFormat::Json => return,
+            OutputFormat::JsonlStream => {
+                if !token_text.swap(false, Ordering::SeqCst) {
+                    print_json(&OutputEvent::Text {
+                        delta: t.to_string(),
+                    });
+                }
+            }
+        }
+        // a closed stdout shows up on the next print
+        let _ = std::io::stdout().flush();
+    };
+    let params = engine.params();
+    let generation = match load_session {
+        Some(path) => {
+            engine.load_session(path)?;
+            engine.resume_stream(params.sample_len, print_token)?
+        }
+        // TODO:: currently defaulting to chat prompt type
+        None => engine.chat_stream(
+            &args.prompt,
+            &[],
+            args.system_prompt.as_deref(),
+            &params,
+            print_token,
+        )?,
+    };
+    if let Some(path) = save_session {
+        engine.save_session(path)?;
+    }
+    let summary = GenerationSummary::new(&generation, engine.prefix_cache_stats());
+    match output {
+        OutputFormat::Text if quiet => println!(),
+        OutputFormat::Text => print_stats(&summary),
+        OutputFormat::Json => print_json(&summary),
+        OutputFormat::JsonlStream => print_json(&OutputEvent::Summary(summary)),
+    }
+    Ok(())
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string(value).expect("the output serializes to JSON")
+    );
+}
 
-    if let Err(e) = get_args().map(|args| {
-        debug!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle_core::utils::with_avx(),
-            candle_core::utils::with_neon(),
-            candle_core::utils::with_simd128(),
-            candle_core::utils::with_f16c()
+fn print_stats(summary: &GenerationSummary) {
+    println!(
+        "\n\n{:4} prompt tokens processed: {:.2} token/s",
+        summary.prompt_tokens, summary.prompt_tokens_per_second,
+    );
+    println!(
+        "{:4} tokens generated: {:.2} token/s",
+        summary.generated_tokens.saturating_sub(1),
+        summary.tokens_per_second
+    );
+    println!("Full response was {} bytes", summary.text.len());
+    if let Some(stats) = summary.prefix_cache {
+        println!(
+            "Prompt cache: {} of {} prompt tokens reused, {} hits, {} misses",
+            summary.cached_tokens, summary.prompt_tokens, stats.hits, stats.misses
+        );
+    }
+    if let Some(stats) = summary.speculative {
+        println!(
+            "Draft acceptance rate: {:.1}% of {} drafted tokens",
+            stats.acceptance_rate() * 100.,
+            stats.drafted_tokens
         );
+    }
+}
 
-        // let system_prompt = "The following is a conversation with an AI assistant. The assistant is helpful, creative, clever, and very friendly.\n\nHuman: Hello, who are you?\nAI: I am an AI created by OpenAI. How can I help you today?\nHuman:";
+fn bench(
+    args: &ArgsResult,
+    config: &BenchConfig,
+    report_path: Option<&str>,
+    history: Option<&str>,
+) -> Result<()> {
+    log_simd_flags();
+    debug!("Args: {:?}", args);
 
-        debug!("Args: {:?}", args);
-        // TODO:: currently defaulting to chat prompt type
-        let prompt = handle_user_input(args.config.which, &args.prompt, None).unwrap();
-
-        // if model is not set in config it uses the which model details
-        let model = LoadModel::load_model(&args.config).unwrap();
-
-        let mut pipeline = TextGeneration::new(
-            model.weights,
-            model.device,
-            model.tokenizer,
-            args.config.repeat_penalty,
-            args.config.repeat_last_n,
-            args.config.seed,
-            args.config.temperature,
-            args.config.top_p,
+    let mut model = LoadModel::load_model(&args.config)?;
+    let threads = ThreadPools::new(&args.config.threads)?;
+    let inference =
+        run_inference_benchmark(model.weights.as_mut(), &model.device, &threads, config)?;
+    // run after the inference benchmark so that their buffers stay out of its peak memory
+    let system = if args.skip_benchmark {
+        None
+    } else {
+        Some(run_system_benchmarks(&BenchmarkConfig::default())?)
+    };
+    let report = BenchReport {
+        model: args
+            .config
+            .model
+            .clone()
+            .unwrap_or_else(|| args.config.which.to_string()),
+        architecture: model.weights.metadata().architecture,
+        device: model.device.name(),
+        system,
+        inference,
+    };
+
+    record_history(&BenchRecord::from_bench_report(&report, config), history)?;
+    write_report(
+        &serde_json::to_string_pretty(&report).kind(EdgerunnerError::Io)?,
+        report_path,
+    )
+}
+
+fn embed(
+    args: &ArgsResult,
+    texts: &[String],
+    input: Option<&str>,
+    options: &EmbeddingOptions,
+    format: EmbeddingFormat,
+    output: Option<&str>,
+) -> Result<()> {
+    let mut texts = texts.to_vec();
+    if let Some(input) = input {
+        let content = std::fs::read_to_string(input)?;
+        texts.extend(
+            content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string),
         );
-        let (response, prompt_tokens, prompt_secs, sampled, sampled_secs) = pipeline
-            .run(
-                prompt,
-                args.config.sample_len,
-                &args.config.which,
-                stop_flag,
-                |t| {
-                    print!("{t}");
-                    std::io::stdout().flush().unwrap();
-                },
-            )
-            .unwrap();
+    }
+    if texts.is_empty() {
+        return Err(EdgerunnerError::config(
+            "no texts to embed, pass them or --input",
+        ));
+    }
+    if format == EmbeddingFormat::Npy && output.is_none() {
+        return Err(EdgerunnerError::config("npy output needs --output"));
+    }
+
+    let mut engine = Engine::builder().config(args.config.clone()).build()?;
+    let texts = texts.iter().map(String::as_str).collect::<Vec<_>>();
+    let embeddings = engine.embed_batch(&texts, options)?;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match format {
+        EmbeddingFormat::Jsonl => write_jsonl(&embeddings, &mut writer)?,
+        EmbeddingFormat::Npy => write_npy(&embeddings, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn eval(
+    args: &ArgsResult,
+    input: &str,
+    config: &EvalConfig,
+    report_path: Option<&str>,
+) -> Result<()> {
+    let text = std::fs::read_to_string(input)?;
+    let mut engine = Engine::builder().config(args.config.clone()).build()?;
+    let report = engine.perplexity(&text, config)?;
+    println!(
+        "{} tokens in {} windows of {} tokens: mean nll {:.4}, perplexity {:.4}",
+        report.tokens, report.windows, report.window, report.mean_nll, report.perplexity
+    );
+    if let Some(path) = report_path {
+        let report = serde_json::json!({
+            "model": args.config.model.clone().unwrap_or_else(|| args.config.which.to_string()),
+            "input": input,
+            "eval": report,
+        });
+        std::fs::write(
+            path,
+            serde_json::to_string_pretty(&report).kind(EdgerunnerError::Io)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn rag_ask(
+    args: &ArgsResult,
+    question: &str,
+    index: &str,
+    top_k: usize,
+    stop_flag: Arc<AtomicBool>,
+) -> Result<()> {
+    let index = RagIndex::load(index)?;
+    let mut engine = Engine::builder()
+        .config(args.config.clone())
+        .stop_flag(stop_flag)
+        .build()?;
+    let params = engine.params();
+    let answer = rag::ask(&mut engine, &index, question, top_k, &params, |t| {
+        print!("{t}");
+        let _ = std::io::stdout().flush();
+    })?;
+    println!("\n\nSources:");
+    for (i, citation) in answer.citations.iter().enumerate() {
+        println!("[{}] {}", i + 1, citation);
+    }
+    Ok(())
+}
+
+fn bench_history(path: Option<&str>) -> Result<BenchHistory> {
+    match path {
+        Some(path) => Ok(BenchHistory::new(path)),
+        None => BenchHistory::open_default(),
+    }
+}
+
+fn record_history(record: &BenchRecord, path: Option<&str>) -> Result<()> {
+    let history = bench_history(path)?;
+    let id = history.append(record)?;
+    debug!(
+        "Recorded benchmark run {} in {}",
+        id,
+        history.path().display()
+    );
+    Ok(())
+}
+
+fn compare_runs(
+    baseline: Option<usize>,
+    candidate: Option<usize>,
+    threshold_percent: f64,
+    history: Option<&str>,
+) -> Result<()> {
+    let (baseline, candidate) = bench_history(history)?.pair(baseline, candidate)?;
+    let comparison = compare(&baseline, &candidate, threshold_percent)?;
+
+    println!(
+        "run {} ({}, {}) -> run {} ({}, {})",
+        baseline.id,
+        baseline.timestamp,
+        baseline.crate_version,
+        candidate.id,
+        candidate.timestamp,
+        candidate.crate_version
+    );
+    for warning in &comparison.warnings {
+        println!("warning: {warning}");
+    }
+    for change in &comparison.changes {
         println!(
-            "\n\n{:4} prompt tokens processed: {:.2} token/s",
-            prompt_tokens,
-            prompt_tokens / prompt_secs,
+            "{:<40} {:>14.2} {:>14.2} {:>+8.2}%{}",
+            change.name,
+            change.baseline,
+            change.candidate,
+            change.change_percent,
+            if change.regression {
+                "  REGRESSION"
+            } else {
+                ""
+            }
         );
+    }
+
+    let regressions = comparison.regressions().count();
+    if regressions > 0 {
         println!(
-            "{sampled:4} tokens generated: {:.2} token/s",
-            sampled / sampled_secs
+            "{} metrics regressed by more than {}%",
+            regressions, threshold_percent
         );
-        println!("Full response was {} bytes", response.as_bytes().len());
-    }) {
-        println!("Error: {}", e);
+        std::process::exit(1);
     }
+    Ok(())
+}
+
+fn recommend(request: &RecommendationRequest, report_path: Option<&str>) -> Result<()> {
+    log_simd_flags();
+    let models = Which::value_variants()
+        .iter()
+        .copied()
+        .filter(|which| !request.only_available || which.is_available())
+        .collect::<Vec<_>>();
+    let hardware = HardwareProfile::detect(&models)?;
+    let recommendation = recommend_model(&hardware, request);
+
+    for line in &recommendation.explanation {
+        println!("{line}");
+    }
+    if let Some(path) = report_path {
+        let report = serde_json::json!({
+            "hardware": hardware,
+            "request": request,
+            "recommendation": recommendation,
+        });
+        let json = serde_json::to_string_pretty(&report).kind(EdgerunnerError::Io)?;
+        std::fs::write(path, json)?;
+    }
+    Ok(())
+}
+
+fn write_report(json: &str, path: Option<&str>) -> Result<()> {
+    if let Some(path) = path {
+        std::fs::write(path, json)?;
+    }
+    println!("{json}");
+    Ok(())
+}
+
+fn log_simd_flags() {
+    debug!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle_core::utils::with_avx(),
+        candle_core::utils::with_neon(),
+        candle_core::utils::with_simd128(),
+        candle_core::utils::with_f16c()
+    );
 }
 
 #[allow(dead_code)]
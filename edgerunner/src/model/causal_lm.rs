@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use candle_core::{DType, Result, Tensor};
+use candle_transformers::models::{
+    mistral::Model as Mistral,
+    quantized_mixformer::MixFormerSequentialForCausalLM as QuantizedMixFormer,
+    quantized_stable_lm::Model as QuantizedStableLm,
+};
+
+use super::{llama::ModelWeights, types::ModelMetadata};
+
+/// The keys and values of every layer for the tokens run so far, each with shape
+/// `(batch, kv_heads, seq_len, head_dim)`. Restoring it skips running these tokens again.
+#[derive(Clone, Debug)]
+pub struct KvCache {
+    pub layers: Vec<(Tensor, Tensor)>,
+}
+
+impl KvCache {
+    /// The number of cached tokens.
+    pub fn len(&self) -> usize {
+        self.layers
+            .first()
+            .and_then(|(k, _)| k.dim(2).ok())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The tensors to save, named `k.{layer}` and `v.{layer}`.
+    pub fn to_tensors(&self) -> HashMap<String, Tensor> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (k, v))| [(format!("k.{i}"), k.clone()), (format!("v.{i}"), v.clone())])
+            .collect()
+    }
+
+    /// Takes the layers saved by [`to_tensors`](Self::to_tensors) out of `tensors`.
+    pub fn from_tensors(tensors: &mut HashMap<String, Tensor>) -> Self {
+        let mut layers = Vec::new();
+        while let (Some(k), Some(v)) = (
+            tensors.remove(&format!("k.{}", layers.len())),
+            tensors.remove(&format!("v.{}", layers.len())),
+        ) {
+            layers.push((k, v));
+        }
+        Self { layers }
+    }
+
+    /// The cache of the first `len` tokens, which only depends on these tokens.
+    pub fn truncate(&self, len: usize) -> Result<Self> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|(k, v)| Ok((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self { layers })
+    }
+}
+
+/// A decoder only language model that predicts the next token, implemented for every
+/// architecture edgerunner can load.
+pub trait CausalLm: Send {
+    /// Runs `input` of shape `(batch, seq_len)` starting at `position` in the context and
+    /// returns the f32 logits of the last position with shape `(batch, vocab_size)`.
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor>;
+
+    /// Drops the kv cache so that the next forward pass starts from an empty context.
+    fn reset_cache(&mut self);
+
+    fn metadata(&self) -> &ModelMetadata;
+
+    /// Runs `input` of shape `(batch, seq_len)` starting at `position` and returns the f32 logits
+    /// of every position with shape `(batch, seq_len, vocab_size)`, used to score text and to
+    /// check several drafted tokens at once.
+    fn forward_all(&mut self, _input: &Tensor, _position: usize) -> Result<Tensor> {
+        candle_core::bail!(
+            "{:?} models do not expose the logits of every position",
+            self.metadata().architecture
+        )
+    }
+
+    /// Runs `input` of shape `(batch, seq_len)` from an empty context and returns the normalized
+    /// hidden states of the last layer with shape `(batch, seq_len, hidden_size)`.
+    fn hidden_states(&mut self, _input: &Tensor) -> Result<Tensor> {
+        candle_core::bail!(
+            "{:?} models do not expose their hidden states",
+            self.metadata().architecture
+        )
+    }
+
+    /// The kv cache of the tokens run so far, `None` when the architecture does not expose it.
+    fn kv_cache(&self) -> Option<KvCache> {
+        None
+    }
+
+    /// Restores a cache taken by [`kv_cache`](Self::kv_cache), the next forward pass starts at
+    /// the position following its tokens.
+    fn set_kv_cache(&mut self, _cache: KvCache) -> Result<()> {
+        candle_core::bail!(
+            "{:?} models do not expose their kv cache",
+            self.metadata().architecture
+        )
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.metadata().vocab_size
+    }
+
+    /// The maximum number of tokens the model can attend to.
+    fn context_length(&self) -> usize {
+        self.metadata().context_length
+    }
+
+    /// The token ids ending a generation according to the model file, may be empty.
+    fn eos_token_ids(&self) -> &[u32] {
+        &self.metadata().eos_token_ids
+    }
+}
+
+/// Quantized llama, mistral and mixtral models.
+pub struct QuantizedLlama {
+    model: ModelWeights,
+    metadata: ModelMetadata,
+}
+
+impl QuantizedLlama {
+    pub fn new(model: ModelWeights, metadata: ModelMetadata) -> Self {
+        Self { model, metadata }
+    }
+}
+
+impl CausalLm for QuantizedLlama {
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        self.model.forward(input, position)
+    }
+
+    // the model overwrites its cache when called with `position == 0`
+    fn reset_cache(&mut self) {}
+
+    fn forward_all(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        self.model.forward_all(input, position)
+    }
+
+    fn hidden_states(&mut self, input: &Tensor) -> Result<Tensor> {
+        self.model.hidden_states(input, 0)
+    }
+
+    fn kv_cache(&self) -> Option<KvCache> {
+        self.model.kv_cache().map(|layers| KvCache { layers })
+    }
+
+    fn set_kv_cache(&mut self, cache: KvCache) -> Result<()> {
+        self.model.set_kv_cache(cache.layers)
+    }
+
+    fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+}
+
+/// Full precision mistral loaded from safetensors.
+pub struct FullMistral {
+    model: Mistral,
+    metadata: ModelMetadata,
+}
+
+impl FullMistral {
+    pub fn new(model: Mistral, metadata: ModelMetadata) -> Self {
+        Self { model, metadata }
+    }
+}
+
+impl CausalLm for FullMistral {
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        self.model
+            .forward(input, position)?
+            .squeeze(1)?
+            .to_dtype(DType::F32)
+    }
+
+    fn reset_cache(&mut self) {
+        self.model.clear_kv_cache()
+    }
+
+    fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+}
+
+/// Quantized phi-2, the mixformer implementation tracks positions through its kv cache.
+pub struct QuantizedPhi2 {
+    model: QuantizedMixFormer,
+    metadata: ModelMetadata,
+}
+
+impl QuantizedPhi2 {
+    pub fn new(model: QuantizedMixFormer, metadata: ModelMetadata) -> Self {
+        Self { model, metadata }
+    }
+}
+
+impl CausalLm for QuantizedPhi2 {
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        if position == 0 {
+            self.model.clear_kv_cache();
+        }
+        self.model.forward(input)
+    }
+
+    fn reset_cache(&mut self) {
+        self.model.clear_kv_cache()
+    }
+
+    fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+}
+
+/// Quantized stablelm, which has no way to clear its cache so a pristine copy of the model is
+/// kept around to reset it. Cloning only copies the reference counted weights.
+pub struct QuantizedStableLmModel {
+    model: QuantizedStableLm,
+    pristine: QuantizedStableLm,
+    metadata: ModelMetadata,
+}
+
+impl QuantizedStableLmModel {
+    pub fn new(model: QuantizedStableLm, metadata: ModelMetadata) -> Self {
+        Self {
+            pristine: model.clone(),
+            model,
+            metadata,
+        }
+    }
+}
+
+impl CausalLm for QuantizedStableLmModel {
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        if position == 0 {
+            self.reset_cache();
+        }
+        self.model.forward(input, position)?.squeeze(1)
+    }
+
+    fn reset_cache(&mut self) {
+        self.model = self.pristine.clone()
+    }
+
+    fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+}
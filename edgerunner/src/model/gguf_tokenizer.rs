@@ -0,0 +1,426 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Error, Result};
+use candle_core::quantized::gguf_file::{Content, Value};
+use serde_json::{json, Value as Json};
+use tokenizers::Tokenizer;
+
+use crate::error::{EdgerunnerError, ResultExt};
+
+/// The sentencepiece word boundary marker.
+const SPIECE_UNDERLINE: &str = "▁";
+
+// https://github.com/ggerganov/llama.cpp/blob/master/gguf-py/gguf/constants.py
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// Builds a tokenizer from the vocabulary embedded in the GGUF metadata, returns `None` when the
+/// file does not carry one.
+///
+/// `llama` vocabularies are sentencepiece BPE models with byte fallback, the merges are derived
+/// from the token scores like the HuggingFace slow to fast conversion does. `gpt2` vocabularies
+/// are byte level BPE models shipping their merges.
+pub fn tokenizer_from_gguf(content: &Content) -> crate::error::Result<Option<Tokenizer>> {
+    build_tokenizer(content).kind(EdgerunnerError::Tokenizer)
+}
+
+/// Reads the embedded tokenizer of a GGUF file on disk.
+pub fn tokenizer_from_gguf_file(path: &std::path::Path) -> crate::error::Result<Option<Tokenizer>> {
+    let mut file = std::fs::File::open(path).kind(EdgerunnerError::Load)?;
+    let content = Content::read(&mut file).kind(EdgerunnerError::Load)?;
+    tokenizer_from_gguf(&content)
+}
+
+fn build_tokenizer(content: &Content) -> Result<Option<Tokenizer>> {
+    let Some(tokens) = metadata(content, "tokenizer.ggml.tokens") else {
+        return Ok(None);
+    };
+    let tokens = tokens
+        .to_vec()?
+        .iter()
+        .map(|t| t.to_string().cloned())
+        .collect::<candle_core::Result<Vec<_>>>()?;
+
+    let model = metadata(content, "tokenizer.ggml.model")
+        .map(|v| v.to_string().cloned())
+        .transpose()?
+        .unwrap_or_else(|| "llama".to_string());
+    // sentencepiece models prepend the bos token by default, byte level ones do not
+    let vocab = GgufVocab::new(content, tokens, model == "llama")?;
+
+    let tokenizer = match model.as_str() {
+        "llama" => vocab.sentencepiece_json(),
+        "gpt2" => vocab.byte_level_json(content)?,
+        model => {
+            return Err(Error::msg(format!(
+                "unsupported gguf tokenizer model: {}",
+                model
+            )))
+        }
+    };
+
+    Tokenizer::from_str(&tokenizer.to_string())
+        .map(Some)
+        .map_err(Error::msg)
+}
+
+fn metadata<'a>(content: &'a Content, key: &str) -> Option<&'a Value> {
+    content.metadata.get(key)
+}
+
+fn metadata_u32(content: &Content, key: &str) -> Result<Option<u32>> {
+    Ok(metadata(content, key).map(|v| v.to_u32()).transpose()?)
+}
+
+struct GgufVocab {
+    tokens: Vec<String>,
+    scores: Vec<f32>,
+    token_types: Vec<i32>,
+    unk: Option<u32>,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    add_bos: bool,
+    add_eos: bool,
+}
+
+impl GgufVocab {
+    fn new(content: &Content, tokens: Vec<String>, default_add_bos: bool) -> Result<Self> {
+        let scores = match metadata(content, "tokenizer.ggml.scores") {
+            Some(scores) => scores
+                .to_vec()?
+                .iter()
+                .map(|s| s.to_f32())
+                .collect::<candle_core::Result<Vec<_>>>()?,
+            None => vec![0.; tokens.len()],
+        };
+        let token_types = match metadata(content, "tokenizer.ggml.token_type") {
+            Some(types) => types
+                .to_vec()?
+                .iter()
+                .map(|t| t.to_i32())
+                .collect::<candle_core::Result<Vec<_>>>()?,
+            None => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+        };
+        if scores.len() != tokens.len() || token_types.len() != tokens.len() {
+            return Err(Error::msg(
+                "gguf tokenizer scores and token types do not match the vocabulary size",
+            ));
+        }
+
+        let add_bos = match metadata(content, "tokenizer.ggml.add_bos_token") {
+            Some(v) => v.to_bool()?,
+            None => default_add_bos,
+        };
+        let add_eos = match metadata(content, "tokenizer.ggml.add_eos_token") {
+            Some(v) => v.to_bool()?,
+            None => false,
+        };
+
+        Ok(Self {
+            unk: metadata_u32(content, "tokenizer.ggml.unknown_token_id")?,
+            bos: metadata_u32(content, "tokenizer.ggml.bos_token_id")?,
+            eos: metadata_u32(content, "tokenizer.ggml.eos_token_id")?,
+            tokens,
+            scores,
+            token_types,
+            add_bos,
+            add_eos,
+        })
+    }
+
+    fn token(&self, id: Option<u32>) -> Option<&str> {
+        id.and_then(|id| self.tokens.get(id as usize))
+            .map(|t| t.as_str())
+    }
+
+    fn vocab_json(&self) -> Json {
+        let vocab = self
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), json!(id)))
+            .collect::<serde_json::Map<_, _>>();
+        Json::Object(vocab)
+    }
+
+    /// Control and unknown tokens are special, user defined ones are matched before the model.
+    fn added_tokens_json(&self) -> Vec<Json> {
+        self.token_types
+            .iter()
+            .enumerate()
+            .filter(|(_, &token_type)| {
+                matches!(
+                    token_type,
+                    TOKEN_TYPE_UNKNOWN | TOKEN_TYPE_CONTROL | TOKEN_TYPE_USER_DEFINED
+                )
+            })
+            .map(|(id, &token_type)| {
+                json!({
+                    "id": id,
+                    "content": self.tokens[id],
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": token_type != TOKEN_TYPE_USER_DEFINED,
+                })
+            })
+            .collect()
+    }
+
+    /// Derives the BPE merges of a sentencepiece vocabulary: every split of a normal token into
+    /// two known tokens is a merge, ranked by the score of the merged token.
+    fn sentencepiece_merges(&self) -> Vec<String> {
+        let ids = self
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.as_str(), id))
+            .collect::<HashMap<_, _>>();
+
+        let mut merges = Vec::new();
+        for (id, token) in self.tokens.iter().enumerate() {
+            if self.token_types[id] != TOKEN_TYPE_NORMAL {
+                continue;
+            }
+            let mut local = token
+                .char_indices()
+                .skip(1)
+                .filter_map(|(split, _)| {
+                    let (left, right) = token.split_at(split);
+                    Some((*ids.get(left)?, *ids.get(right)?, self.scores[id]))
+                })
+                .collect::<Vec<_>>();
+            local.sort_by_key(|&(left, right, _)| (left, right));
+            merges.extend(local);
+        }
+        merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+        merges
+            .into_iter()
+            .map(|(left, right, _)| format!("{} {}", self.tokens[left], self.tokens[right]))
+            .collect()
+    }
+
+    fn template_processing_json(&self) -> Json {
+        let mut single = Vec::new();
+        let mut special_tokens = serde_json::Map::new();
+        let mut push_special = |id: Option<u32>, single: &mut Vec<Json>| {
+            if let Some(token) = self.token(id) {
+                single.push(json!({ "SpecialToken": { "id": token, "type_id": 0 } }));
+                special_tokens.insert(
+                    token.to_string(),
+                    json!({ "id": token, "ids": [id], "tokens": [token] }),
+                );
+            }
+        };
+
+        if self.add_bos {
+            push_special(self.bos, &mut single);
+        }
+        single.push(json!({ "Sequence": { "id": "A", "type_id": 0 } }));
+        if self.add_eos {
+            push_special(self.eos, &mut single);
+        }
+
+        let mut pair = single.clone();
+        pair.extend(single.iter().map(|piece| match piece.get("Sequence") {
+            Some(_) => json!({ "Sequence": { "id": "B", "type_id": 1 } }),
+            None => piece.clone(),
+        }));
+
+        json!({
+            "type": "TemplateProcessing",
+            "single": single,
+            "pair": pair,
+            "special_tokens": special_tokens,
+        })
+    }
+
+    fn sentencepiece_json(&self) -> Json {
+        json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": self.added_tokens_json(),
+            "normalizer": {
+                "type": "Sequence",
+                "normalizers": [
+                    { "type": "Prepend", "prepend": SPIECE_UNDERLINE },
+                    { "type": "Replace", "pattern": { "String": " " }, "content": SPIECE_UNDERLINE },
+                ],
+            },
+            "pre_tokenizer": null,
+            "post_processor": self.template_processing_json(),
+            "decoder": {
+                "type": "Sequence",
+                "decoders": [
+                    { "type": "Replace", "pattern": { "String": SPIECE_UNDERLINE }, "content": " " },
+                    { "type": "ByteFallback" },
+                    { "type": "Fuse" },
+                    { "type": "Strip", "content": " ", "start": 1, "stop": 0 },
+                ],
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": self.token(self.unk),
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": true,
+                "byte_fallback": true,
+                "vocab": self.vocab_json(),
+                "merges": self.sentencepiece_merges(),
+            },
+        })
+    }
+
+    fn byte_level_json(&self, content: &Content) -> Result<Json> {
+        let merges = match metadata(content, "tokenizer.ggml.merges") {
+            Some(merges) => merges
+                .to_vec()?
+                .iter()
+                .map(|m| m.to_string().cloned())
+                .collect::<candle_core::Result<Vec<_>>>()?,
+            None => return Err(Error::msg("gpt2 gguf tokenizer without merges")),
+        };
+
+        Ok(json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": self.added_tokens_json(),
+            "normalizer": null,
+            "pre_tokenizer": {
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": true,
+            },
+            "post_processor": self.template_processing_json(),
+            "decoder": {
+                "type": "ByteLevel",
+                "add_prefix_space": true,
+                "trim_offsets": true,
+                "use_regex": true,
+            },
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": self.token(self.unk),
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": self.vocab_json(),
+                "merges": merges,
+            },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::gguf_file::VersionedMagic;
+
+    const PIECES: [(&str, f32); 16] = [
+        ("▁", -1.),
+        ("he", -2.),
+        ("ll", -3.),
+        ("llo", -4.),
+        ("hello", -5.),
+        ("▁hello", -6.),
+        ("or", -7.),
+        ("wor", -8.),
+        ("ld", -9.),
+        ("world", -10.),
+        ("▁world", -11.),
+        ("h", -20.),
+        ("e", -21.),
+        ("l", -22.),
+        ("o", -23.),
+        ("w", -24.),
+    ];
+
+    /// A sentencepiece vocabulary laid out like llama's: specials, byte tokens, then pieces.
+    fn spm_content() -> Content {
+        let mut tokens = vec![
+            ("<unk>".to_string(), 0., TOKEN_TYPE_UNKNOWN),
+            ("<s>".to_string(), 0., TOKEN_TYPE_CONTROL),
+            ("</s>".to_string(), 0., TOKEN_TYPE_CONTROL),
+        ];
+        tokens.extend((0..=255u8).map(|b| (format!("<0x{b:02X}>"), 0., 6)));
+        tokens.extend(
+            PIECES
+                .iter()
+                .chain(&[("r", -25.), ("d", -26.)])
+                .map(|(t, s)| (t.to_string(), *s, TOKEN_TYPE_NORMAL)),
+        );
+
+        let array = |values: Vec<Value>| Value::Array(values);
+        let metadata = [
+            ("tokenizer.ggml.model", Value::String("llama".to_string())),
+            (
+                "tokenizer.ggml.tokens",
+                array(tokens.iter().map(|t| Value::String(t.0.clone())).collect()),
+            ),
+            (
+                "tokenizer.ggml.scores",
+                array(tokens.iter().map(|t| Value::F32(t.1)).collect()),
+            ),
+            (
+                "tokenizer.ggml.token_type",
+                array(tokens.iter().map(|t| Value::I32(t.2)).collect()),
+            ),
+            ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+            ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+            ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+        ];
+        Content {
+            magic: VersionedMagic::GgufV3,
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+            tensor_infos: HashMap::new(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_no_embedded_vocabulary() {
+        let mut content = spm_content();
+        content.metadata.remove("tokenizer.ggml.tokens");
+        assert!(tokenizer_from_gguf(&content).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sentencepiece_encode() {
+        let tokenizer = tokenizer_from_gguf(&spm_content()).unwrap().unwrap();
+        let encoding = tokenizer.encode("hello world", true).unwrap();
+        assert_eq!(encoding.get_tokens(), ["<s>", "▁hello", "▁world"]);
+    }
+
+    #[test]
+    fn test_sentencepiece_round_trip() {
+        let tokenizer = tokenizer_from_gguf(&spm_content()).unwrap().unwrap();
+        for text in [
+            "hello world",
+            "world hello hello",
+            "héllo wörld ✓",
+            "  hello",
+        ] {
+            let ids = tokenizer.encode(text, true).unwrap().get_ids().to_vec();
+            assert_eq!(tokenizer.decode(&ids, true).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_special_tokens_are_not_split() {
+        let tokenizer = tokenizer_from_gguf(&spm_content()).unwrap().unwrap();
+        let encoding = tokenizer.encode("hello</s>", false).unwrap();
+        assert_eq!(encoding.get_tokens(), ["▁hello", "</s>"]);
+    }
+}
@@ -1,80 +1,280 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     conf::{model::InferenceConfig, which::Which},
-    runner::device::device,
+    error::{EdgerunnerError, Result, ResultExt},
+    runner::{
+        device::resolve_device,
+        events::{EngineEvent, EngineObserver, NoopObserver},
+    },
     util::format_size,
 };
-use anyhow::{Error, Result};
 use candle_core::{
     quantized::{ggml_file, gguf_file},
-    Device,
+    DType, Device,
+};
+use candle_nn::VarBuilder;
+use candle_transformers::{
+    models::{
+        mistral::{Config as MistralConfig, Model as Mistral},
+        mixformer::Config as MixFormerConfig,
+        quantized_mixformer::MixFormerSequentialForCausalLM as QuantizedMixFormer,
+        quantized_stable_lm::Model as QuantizedStableLm,
+        stable_lm::Config as StableLmConfig,
+    },
+    quantized_var_builder,
 };
-use candle_transformers::models::quantized_llama::ModelWeights;
 use hf_hub::{Cache, Repo, RepoType};
+use log::{debug, info};
+use serde::Deserialize;
 use tokenizers::Tokenizer;
 
+use super::{
+    causal_lm::{CausalLm, FullMistral, QuantizedLlama, QuantizedPhi2, QuantizedStableLmModel},
+    gguf_tokenizer::tokenizer_from_gguf,
+    llama::{self, ModelWeights},
+    types::{ModelArchitecture, ModelMetadata},
+};
+
+const SAFETENSORS_CONFIG_FILE: &str = "config.json";
+const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+const SAFETENSORS_SINGLE_FILE: &str = "model.safetensors";
+const SAFETENSORS_TOKENIZER_FILE: &str = "tokenizer.json";
+
+const LLAMA_VOCAB_SIZE: usize = 32000;
+// candle only ships these configurations for the quantized phi-2 and stablelm models
+const PHI2_VOCAB_SIZE: usize = 51200;
+const PHI2_CONTEXT_LENGTH: usize = 2048;
+const STABLELM_VOCAB_SIZE: usize = 50304;
+const STABLELM_CONTEXT_LENGTH: usize = 4096;
+
 pub struct LoadModel;
 
 impl LoadModel {
     pub fn load_model(config: &InferenceConfig) -> Result<Model> {
+        Self::load_model_with_observer(config, &NoopObserver)
+    }
+
+    /// Loads the model, reporting the loading progress to `observer`.
+    pub fn load_model_with_observer(
+        config: &InferenceConfig,
+        observer: &dyn EngineObserver,
+    ) -> Result<Model> {
+        let start = std::time::Instant::now();
         let model_path = config.model()?;
+        observer.on_event(&EngineEvent::ModelLoading {
+            path: model_path.display().to_string(),
+        });
 
-        let device = device(false).expect("Could not get device");
+        let device = resolve_device(config.device);
 
-        let model_weights = Self::load_model_weights(&model_path, &device)?;
+        // an explicit tokenizer wins, otherwise prefer the vocabulary embedded in a gguf file or
+        // the tokenizer shipped with a checkpoint
+        let embed_tokenizer = config.tokenizer.is_none();
+        let (model_weights, embedded_tokenizer) = if model_path.is_dir() {
+            let weights = Self::load_safetensors_weights(
+                &model_path,
+                config.dtype.into(),
+                &device,
+                observer,
+            )?;
+            let tokenizer = if embed_tokenizer {
+                checkpoint_tokenizer(&model_path)?
+            } else {
+                None
+            };
+            (weights, tokenizer)
+        } else {
+            Self::load_model_weights(&model_path, &device, embed_tokenizer, observer)?
+        };
 
-        let tokenizer = config.tokenizer()?;
+        let embedded = embedded_tokenizer.is_some();
+        let tokenizer = match embedded_tokenizer {
+            Some(tokenizer) => tokenizer,
+            None => config.tokenizer_for(model_weights.metadata().architecture)?,
+        };
+        observer.on_event(&EngineEvent::ModelLoaded {
+            architecture: model_weights.metadata().architecture,
+            embedded_tokenizer: embedded,
+            seconds: start.elapsed().as_secs_f64(),
+        });
 
-        Ok(Model::new(tokenizer, model_weights, device))
+        Ok(Model {
+            path: Some(model_path),
+            ..Model::new(tokenizer, model_weights, device)
+        })
     }
 
-    fn load_model_weights(model_path: &PathBuf, device: &Device) -> Result<ModelWeights> {
-        let mut file = std::fs::File::open(model_path)?;
+    /// Loads quantized weights, along with the tokenizer embedded in gguf files when
+    /// `embed_tokenizer` is set and the file carries a vocabulary.
+    fn load_model_weights(
+        model_path: &PathBuf,
+        device: &Device,
+        embed_tokenizer: bool,
+        observer: &dyn EngineObserver,
+    ) -> Result<(Box<dyn CausalLm>, Option<Tokenizer>)> {
+        let mut file = std::fs::File::open(model_path).kind(EdgerunnerError::Load)?;
         let start = std::time::Instant::now();
 
         match model_path.extension().and_then(|v| v.to_str()) {
             Some("gguf") => {
-                let model = gguf_file::Content::read(&mut file)?;
+                let model = gguf_file::Content::read(&mut file).kind(EdgerunnerError::Load)?;
                 let mut total_size_in_bytes = 0;
                 for (_, tensor) in model.tensor_infos.iter() {
                     let elem_count = tensor.shape.elem_count();
                     total_size_in_bytes +=
                         elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
                 }
-                println!(
-                    "loaded {:?} tensors ({}) in {:.2}s",
-                    model.tensor_infos.len(),
-                    &format_size(total_size_in_bytes),
-                    start.elapsed().as_secs_f32(),
-                );
-                Ok(ModelWeights::from_gguf(model, &mut file, device)?)
+                let what = format!("{} tensors", model.tensor_infos.len());
+                Self::weights_loaded(observer, &what, total_size_in_bytes, start);
+
+                let tokenizer = if embed_tokenizer {
+                    tokenizer_from_gguf(&model)?
+                } else {
+                    None
+                };
+                if tokenizer.is_some() {
+                    info!("using the tokenizer embedded in the gguf file");
+                }
+
+                let architecture = ModelArchitecture::from_gguf(&model)?;
+                info!("architecture: {}", architecture);
+                let weights =
+                    Self::build_gguf_model(model, architecture, &mut file, model_path, device)?;
+                Ok((weights, tokenizer))
             }
             Some("ggml" | "bin") | Some(_) | None => {
-                let model = ggml_file::Content::read(&mut file, device)?;
+                let model =
+                    ggml_file::Content::read(&mut file, device).kind(EdgerunnerError::Load)?;
                 let mut total_size_in_bytes = 0;
                 for (_, tensor) in model.tensors.iter() {
                     let elem_count = tensor.shape().elem_count();
                     total_size_in_bytes +=
                         elem_count * tensor.dtype().type_size() / tensor.dtype().block_size();
                 }
-                println!(
-                    "loaded {:?} tensors ({}) in {:.2}s",
-                    model.tensors.len(),
-                    &format_size(total_size_in_bytes),
-                    start.elapsed().as_secs_f32(),
-                );
-                println!("params: {:?}", model.hparams);
+                let what = format!("{} tensors", model.tensors.len());
+                Self::weights_loaded(observer, &what, total_size_in_bytes, start);
+                debug!("params: {:?}", model.hparams);
 
+                let metadata = ModelMetadata::from_ggml(&model.hparams);
                 let default_gqa = 8;
-                Ok(ModelWeights::from_ggml(model, default_gqa)?)
+                let weights =
+                    ModelWeights::from_ggml(model, default_gqa).kind(EdgerunnerError::Load)?;
+                Ok((Box::new(QuantizedLlama::new(weights, metadata)), None))
             }
         }
     }
 
+    /// Builds the model implementation matching the architecture of a GGUF file.
+    fn build_gguf_model(
+        model: gguf_file::Content,
+        architecture: ModelArchitecture,
+        file: &mut std::fs::File,
+        model_path: &Path,
+        device: &Device,
+    ) -> Result<Box<dyn CausalLm>> {
+        match architecture {
+            ModelArchitecture::Llama | ModelArchitecture::Mixtral | ModelArchitecture::Mistral => {
+                let metadata = ModelMetadata::from_gguf(
+                    &model,
+                    architecture,
+                    LLAMA_VOCAB_SIZE,
+                    llama::MAX_SEQ_LEN,
+                );
+                let weights =
+                    ModelWeights::from_gguf(model, file, device).kind(EdgerunnerError::Load)?;
+                Ok(Box::new(QuantizedLlama::new(weights, metadata)))
+            }
+            ModelArchitecture::Phi2 => {
+                let metadata = ModelMetadata::from_gguf(
+                    &model,
+                    architecture,
+                    PHI2_VOCAB_SIZE,
+                    PHI2_CONTEXT_LENGTH,
+                );
+                let vb = quantized_var_builder::VarBuilder::from_gguf(model_path, device)
+                    .kind(EdgerunnerError::Load)?;
+                let weights = QuantizedMixFormer::new_v2(&MixFormerConfig::v2(), vb)
+                    .kind(EdgerunnerError::Load)?;
+                Ok(Box::new(QuantizedPhi2::new(weights, metadata)))
+            }
+            ModelArchitecture::StableLm => {
+                let metadata = ModelMetadata::from_gguf(
+                    &model,
+                    architecture,
+                    STABLELM_VOCAB_SIZE,
+                    STABLELM_CONTEXT_LENGTH,
+                );
+                let vb = quantized_var_builder::VarBuilder::from_gguf(model_path, device)
+                    .kind(EdgerunnerError::Load)?;
+                let weights = QuantizedStableLm::new(&StableLmConfig::stablelm_3b_4e1t(false), vb)
+                    .kind(EdgerunnerError::Load)?;
+                Ok(Box::new(QuantizedStableLmModel::new(weights, metadata)))
+            }
+        }
+    }
+
+    /// Loads a full precision Mistral model from a HuggingFace checkpoint directory holding a
+    /// `config.json` and either a single `model.safetensors` or sharded safetensors files.
+    fn load_safetensors_weights(
+        model_dir: &Path,
+        dtype: DType,
+        device: &Device,
+        observer: &dyn EngineObserver,
+    ) -> Result<Box<dyn CausalLm>> {
+        let start = std::time::Instant::now();
+
+        let config_file = std::fs::File::open(model_dir.join(SAFETENSORS_CONFIG_FILE))
+            .kind(EdgerunnerError::Load)?;
+        let config: SafetensorsConfig =
+            serde_json::from_reader(config_file).kind(EdgerunnerError::Load)?;
+        let metadata = config.metadata();
+        let config = config.mistral_config()?;
+
+        let filenames = safetensors_files(model_dir)?;
+        let total_size_in_bytes = filenames
+            .iter()
+            .map(|f| std::fs::metadata(f).map(|m| m.len() as usize))
+            .sum::<std::io::Result<usize>>()
+            .kind(EdgerunnerError::Load)?;
+
+        // safety: the files are memory mapped and must not be modified while the model is alive
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&filenames, dtype, device)
+                .kind(EdgerunnerError::Load)?
+        };
+        let model = Mistral::new(&config, vb).kind(EdgerunnerError::Load)?;
+        let what = format!("{} safetensors files as {:?}", filenames.len(), dtype);
+        Self::weights_loaded(observer, &what, total_size_in_bytes, start);
+        Ok(Box::new(FullMistral::new(model, metadata)))
+    }
+
+    fn weights_loaded(
+        observer: &dyn EngineObserver,
+        what: &str,
+        size_bytes: usize,
+        start: std::time::Instant,
+    ) {
+        let seconds = start.elapsed().as_secs_f64();
+        info!(
+            "loaded {} ({}) in {:.2}s",
+            what,
+            format_size(size_bytes),
+            seconds
+        );
+        observer.on_event(&EngineEvent::WeightsLoaded {
+            size_bytes,
+            seconds,
+        });
+    }
+
     pub fn check_cache(which: &Which, _revision: Option<&str>) -> Result<bool> {
-        let home_dir =
-            dirs::home_dir().ok_or_else(|| Error::msg("Could not find home directory."))?;
+        let home_dir = dirs::home_dir().ok_or_else(|| {
+            EdgerunnerError::Cache(anyhow::Error::msg("Could not find home directory."))
+        })?;
 
         let cache_dir = home_dir.join(".cache/huggingface/hub");
 
@@ -88,19 +288,191 @@ impl LoadModel {
     }
 }
 
-#[derive(Debug)]
+/// The files the weights at `model_path` are read from, the `config.json` and the safetensors
+/// files of a checkpoint directory or the weight file itself.
+pub fn weight_files(model_path: &Path) -> Result<Vec<PathBuf>> {
+    if !model_path.is_dir() {
+        return Ok(vec![model_path.to_path_buf()]);
+    }
+    let mut files = vec![model_path.join(SAFETENSORS_CONFIG_FILE)];
+    files.extend(safetensors_files(model_path)?);
+    Ok(files)
+}
+
+/// The `tokenizer.json` of a checkpoint directory, if it has one.
+fn checkpoint_tokenizer(model_dir: &Path) -> Result<Option<Tokenizer>> {
+    let path = model_dir.join(SAFETENSORS_TOKENIZER_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Tokenizer::from_file(&path)
+        .map(Some)
+        .map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))
+        .kind(EdgerunnerError::Tokenizer)
+}
+
+/// Lists the safetensors files of a checkpoint directory, following the shard index if present.
+fn safetensors_files(model_dir: &Path) -> Result<Vec<PathBuf>> {
+    let index_path = model_dir.join(SAFETENSORS_INDEX_FILE);
+    if !index_path.exists() {
+        let single = model_dir.join(SAFETENSORS_SINGLE_FILE);
+        if !single.exists() {
+            return Err(EdgerunnerError::load(format!(
+                "no {} or {} found in {}",
+                SAFETENSORS_INDEX_FILE,
+                SAFETENSORS_SINGLE_FILE,
+                model_dir.display()
+            )));
+        }
+        return Ok(vec![single]);
+    }
+
+    let index_file = std::fs::File::open(&index_path).kind(EdgerunnerError::Load)?;
+    let index: serde_json::Value =
+        serde_json::from_reader(index_file).kind(EdgerunnerError::Load)?;
+    let weight_map = index
+        .get("weight_map")
+        .and_then(|m| m.as_object())
+        .ok_or_else(|| {
+            EdgerunnerError::load(format!("no weight_map in {}", index_path.display()))
+        })?;
+    let shards = weight_map
+        .values()
+        .filter_map(|v| v.as_str())
+        .collect::<BTreeSet<_>>();
+    Ok(shards.into_iter().map(|f| model_dir.join(f)).collect())
+}
+
+/// The subset of a HuggingFace `config.json` needed to pick the candle Mistral configuration.
+#[derive(Debug, Deserialize)]
+struct SafetensorsConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    hidden_act: String,
+    max_position_embeddings: usize,
+    rope_theta: f64,
+    sliding_window: Option<usize>,
+    eos_token_id: Option<u32>,
+}
+
+impl SafetensorsConfig {
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            architecture: ModelArchitecture::Mistral,
+            vocab_size: self.vocab_size,
+            context_length: self.max_position_embeddings,
+            eos_token_ids: self.eos_token_id.into_iter().collect(),
+        }
+    }
+
+    /// candle only exposes the Mistral 7B configurations, which differ by their vocabulary size.
+    fn mistral_config(&self) -> Result<MistralConfig> {
+        let is_mistral_7b = self.hidden_size == 4096
+            && self.intermediate_size == 14336
+            && self.num_hidden_layers == 32
+            && self.num_attention_heads == 32
+            && self.num_key_value_heads == 8
+            && self.hidden_act == "silu"
+            && self.max_position_embeddings == 32768
+            && self.rope_theta == 10_000.
+            && self.sliding_window == Some(4096);
+        if !is_mistral_7b {
+            return Err(EdgerunnerError::load(format!(
+                "unsupported safetensors model configuration: {:?}",
+                self
+            )));
+        }
+
+        match self.vocab_size {
+            32000 => Ok(MistralConfig::config_7b_v0_1(false)),
+            32002 => Ok(MistralConfig::config_chat_ml(false)),
+            32003 => Ok(MistralConfig::config_amazon_mistral_lite(false)),
+            vocab_size => Err(EdgerunnerError::load(format!(
+                "unsupported vocabulary size for a mistral model: {}",
+                vocab_size
+            ))),
+        }
+    }
+}
+
 pub struct Model {
     pub tokenizer: Tokenizer,
-    pub weights: ModelWeights,
+    pub weights: Box<dyn CausalLm>,
     pub device: Device,
+    /// The weight file or checkpoint directory the model was loaded from, downloaded models
+    /// included, `None` for models built in memory.
+    pub path: Option<PathBuf>,
 }
 
 impl Model {
-    pub fn new(tokenizer: Tokenizer, weights: ModelWeights, device: Device) -> Self {
+    pub fn new(tokenizer: Tokenizer, weights: Box<dyn CausalLm>, device: Device) -> Self {
         Self {
             tokenizer,
             weights,
             device,
+            path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mistral_7b_config(vocab_size: usize) -> SafetensorsConfig {
+        SafetensorsConfig {
+            vocab_size,
+            hidden_size: 4096,
+            intermediate_size: 14336,
+            num_hidden_layers: 32,
+            num_attention_heads: 32,
+            num_key_value_heads: 8,
+            hidden_act: "silu".to_string(),
+            max_position_embeddings: 32768,
+            rope_theta: 10_000.,
+            sliding_window: Some(4096),
+            eos_token_id: Some(2),
         }
     }
+
+    #[test]
+    fn test_mistral_config_by_vocab_size() {
+        assert_eq!(
+            mistral_7b_config(32000).mistral_config().unwrap(),
+            MistralConfig::config_7b_v0_1(false)
+        );
+        assert_eq!(
+            mistral_7b_config(32002).mistral_config().unwrap(),
+            MistralConfig::config_chat_ml(false)
+        );
+        assert!(mistral_7b_config(50000).mistral_config().is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_tokenizer() {
+        let dir = std::env::temp_dir().join(format!(
+            "edgerunner-checkpoint-tokenizer-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(checkpoint_tokenizer(&dir).unwrap().is_none());
+
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tokenizer.json");
+        std::fs::copy(fixture, dir.join(SAFETENSORS_TOKENIZER_FILE)).unwrap();
+        let tokenizer = checkpoint_tokenizer(&dir).unwrap().unwrap();
+        assert_eq!(tokenizer.token_to_id("</s>"), Some(2));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mistral_config_rejects_other_architectures() {
+        let mut config = mistral_7b_config(32000);
+        config.sliding_window = None;
+        assert!(config.mistral_config().is_err());
+    }
 }
@@ -1,3 +1,7 @@
+pub mod causal_lm;
+pub mod gguf_tokenizer;
+pub mod llama;
 pub mod loader;
 pub mod prompt;
+pub mod tools;
 pub mod types;
@@ -1,5 +1,10 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value as Json};
+
+use super::tools::{ChatMessage, Role, Tool, ToolCall};
 use crate::conf::which::Which;
-use anyhow::Result;
+use crate::error::{EdgerunnerError, Result};
 
 #[allow(dead_code)]
 const DEFAULT_PROMPT: &str = "My favorite theorem is ";
@@ -31,7 +36,7 @@ impl Prompt {
         which: &Which,
         conversation_history: Option<&[String]>,
         system_prompt: Option<&String>,
-    ) -> anyhow::Result<GeneratedPrompt> {
+    ) -> Result<GeneratedPrompt> {
         match self {
             Prompt::One(prompt) => Ok(GeneratedPrompt(prompt.clone())),
             Prompt::Chat(prompt) => {
@@ -79,7 +84,7 @@ impl Prompt {
         text_from_chat: &str,
         conversation_history: Option<&[String]>,
         system_prompt: Option<&String>,
-    ) -> anyhow::Result<GeneratedPrompt> {
+    ) -> Result<GeneratedPrompt> {
         let s_prompt = match system_prompt {
             Some(prompt) => prompt.to_string(),
             None => String::new(),
@@ -95,7 +100,8 @@ impl Prompt {
                 .map(|x| x.to_string())
                 .collect::<Vec<String>>();
 
-            if history_split.is_empty() {
+            // an exchange needs both the user and the assistant message
+            if history_split.len() < 2 {
                 format!(
                     "<s>[INST]{}[/INST]</s>\n[INST] {} [/INST] ",
                     s_prompt, text_from_chat
@@ -128,9 +134,257 @@ pub fn handle_user_input(
     prompt.generate_prompt(&which, None, system_prompt)
 }
 
+/// A retrieved passage given to the model as context, cited by its position in the prompt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContextSource {
+    /// Where the passage comes from, such as a file path.
+    pub citation: String,
+    pub text: String,
+}
+
+/// Prefixes `input` with the numbered `sources` and asks the model to cite them as `[n]`.
+pub fn with_context(input: &str, sources: &[ContextSource]) -> String {
+    if sources.is_empty() {
+        return input.to_string();
+    }
+    let sources = sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| format!("[{}] {}\n{}", i + 1, source.citation, source.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    format!(
+        "Answer the question using the sources below and cite the ones you use as [1], [2].\n\n\
+         {sources}\n\nQuestion: {input}"
+    )
+}
+
+/// Replaces the `{{name}}` placeholders of `template` with the value of `name` in `vars`, the
+/// spaces around a name are ignored. A placeholder without a value is a config error.
+pub fn fill_template(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder
+            .find("}}")
+            .ok_or_else(|| EdgerunnerError::config("unclosed {{ in the prompt template"))?;
+        let name = placeholder[..end].trim();
+        let value = vars.get(name).ok_or_else(|| {
+            EdgerunnerError::config(format!(
+                "no value for {{{{{name}}}}} in the prompt template"
+            ))
+        })?;
+        filled.push_str(value);
+        rest = &placeholder[end + 2..];
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+/// Builds the chat prompt of `input` after the `history` of alternating user and assistant
+/// messages, of which the templates keep the last exchange.
+pub fn handle_chat_input(
+    which: Which,
+    input: &str,
+    history: &[String],
+    system_prompt: Option<&String>,
+) -> Result<GeneratedPrompt> {
+    let prompt = Prompt::Chat(input.to_string());
+    let history = (!history.is_empty()).then_some(history);
+
+    prompt.generate_prompt(&which, history, system_prompt)
+}
+
+/// The text of the tool result of `message`, with the name of the call it answers.
+fn tool_result(message: &ChatMessage, messages: &[ChatMessage]) -> Json {
+    let name = messages
+        .iter()
+        .flat_map(|message| &message.tool_calls)
+        .find(|call| Some(&call.id) == message.tool_call_id.as_ref())
+        .map(|call| call.function.name.as_str());
+    json!({"name": name, "content": message.content()})
+}
+
+/// The calls of an assistant message in `<tool_call>` tags.
+fn tagged_calls(calls: &[ToolCall]) -> String {
+    calls
+        .iter()
+        .map(|call| {
+            let call =
+                json!({"name": call.function.name, "arguments": call.function.arguments_json()});
+            format!("<tool_call>{call}</tool_call>")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Describes `tools` in the system prompt of the models without a tool calling format.
+fn tool_instructions(tools: &[Tool]) -> String {
+    let functions = tools
+        .iter()
+        .map(|tool| json!(tool.function).to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "You can call the following tools, described as JSON:\n{functions}\n\n\
+         To call tools, reply with <tool_call>{{\"name\": <tool name>, \"arguments\": <arguments \
+         object>}}</tool_call> for each call and nothing else. The results come back in \
+         <tool_response></tool_response> tags."
+    )
+}
+
+/// Builds the prompt of a whole conversation whose answer may call `tools`. Mistral models get
+/// their `[AVAILABLE_TOOLS]` and `[TOOL_CALLS]` format, the others are told to write calls in
+/// `<tool_call>` tags and get the results in `<tool_response>` tags.
+pub fn handle_tool_chat(
+    which: Which,
+    messages: &[ChatMessage],
+    tools: &[Tool],
+) -> Result<GeneratedPrompt> {
+    let system = messages
+        .iter()
+        .filter(|message| message.role == Role::System)
+        .map(ChatMessage::content)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let turns = messages
+        .iter()
+        .filter(|message| message.role != Role::System)
+        .collect::<Vec<_>>();
+
+    if which.is_mistral() && !which.is_zephyr() && !which.is_open_chat() {
+        return Ok(GeneratedPrompt(mistral_tool_chat(&system, &turns, tools)));
+    }
+
+    let system = match (system.is_empty(), tools.is_empty()) {
+        (_, true) => system,
+        (true, false) => tool_instructions(tools),
+        (false, false) => format!("{system}\n\n{}", tool_instructions(tools)),
+    };
+    let text = |message: &ChatMessage| match message.role {
+        Role::Tool => format!(
+            "<tool_response>{}</tool_response>",
+            tool_result(message, messages)
+        ),
+        Role::Assistant if !message.tool_calls.is_empty() => tagged_calls(&message.tool_calls),
+        _ => message.content().to_string(),
+    };
+
+    let mut prompt = String::new();
+    if which.is_zephyr() {
+        if !system.is_empty() {
+            prompt += &format!("<|system|>\n{system}</s>\n");
+        }
+        for message in turns {
+            // tool results come back as user messages
+            let role = match message.role {
+                Role::Assistant => "assistant",
+                _ => "user",
+            };
+            prompt += &format!("<|{role}|>\n{}</s>\n", text(message));
+        }
+        prompt += "<|assistant|>";
+    } else if which.is_open_chat() {
+        let mut system = (!system.is_empty()).then(|| format!("{system}\n\n"));
+        for message in turns {
+            let role = match message.role {
+                Role::Assistant => "Assistant",
+                _ => "User",
+            };
+            let system = system.take().unwrap_or_default();
+            prompt += &format!(
+                "GPT4 Correct {role}: {system}{}<|end_of_turn|>",
+                text(message)
+            );
+        }
+        prompt += "GPT4 Correct Assistant:";
+    } else {
+        if !system.is_empty() {
+            prompt += &format!("{system}\n\n");
+        }
+        for message in turns {
+            let role = match message.role {
+                Role::Assistant => "Assistant",
+                Role::Tool => "Tool",
+                _ => "User",
+            };
+            prompt += &format!("{role}: {}\n", text(message));
+        }
+        prompt += "Assistant:";
+    }
+    Ok(GeneratedPrompt(prompt))
+}
+
+/// The mistral v3 tool calling format: the tools come right before the last user message,
+/// which also carries the system prompt.
+fn mistral_tool_chat(system: &str, turns: &[&ChatMessage], tools: &[Tool]) -> String {
+    let last_user = turns.iter().rposition(|message| message.role == Role::User);
+    let mut prompt = "<s>".to_string();
+    for (i, message) in turns.iter().enumerate() {
+        match message.role {
+            Role::User if Some(i) == last_user => {
+                if !tools.is_empty() {
+                    prompt += &format!("[AVAILABLE_TOOLS] {}[/AVAILABLE_TOOLS]", json!(tools));
+                }
+                let content = if system.is_empty() {
+                    message.content().to_string()
+                } else {
+                    format!("{system}\n\n{}", message.content())
+                };
+                prompt += &format!("[INST] {content} [/INST]");
+            }
+            Role::User | Role::System => prompt += &format!("[INST] {} [/INST]", message.content()),
+            Role::Assistant if !message.tool_calls.is_empty() => {
+                let calls = message
+                    .tool_calls
+                    .iter()
+                    .map(|call| {
+                        json!({
+                            "name": call.function.name,
+                            "arguments": call.function.arguments_json(),
+                            "id": call.id,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                prompt += &format!("[TOOL_CALLS] {}</s>", json!(calls));
+            }
+            Role::Assistant => prompt += &format!(" {}</s>", message.content()),
+            Role::Tool => {
+                let result = json!({
+                    "call_id": message.tool_call_id,
+                    "content": message.content(),
+                });
+                prompt += &format!("[TOOL_RESULTS] {result}[/TOOL_RESULTS]");
+            }
+        }
+    }
+    prompt
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::tools::FunctionCall;
+
+    #[test]
+    fn test_fill_template() {
+        let vars = HashMap::from([
+            ("name".to_string(), "Ada".to_string()),
+            ("topic".to_string(), "{{name}}".to_string()),
+        ]);
+        assert_eq!(
+            fill_template("Hi {{name}}, tell me about {{ topic }}.", &vars).unwrap(),
+            "Hi Ada, tell me about {{name}}."
+        );
+        assert_eq!(
+            fill_template("no placeholders", &vars).unwrap(),
+            "no placeholders"
+        );
+        assert!(fill_template("{{missing}}", &vars).is_err());
+        assert!(fill_template("{{name", &vars).is_err());
+    }
 
     // Test for 'One' prompt type
     #[test]
@@ -271,4 +525,102 @@ mod tests {
             )
         );
     }
+
+    // A lone user message in the history is not an exchange to replay
+    #[test]
+    fn test_chat_input_with_incomplete_history() {
+        let generated_prompt = handle_chat_input(
+            Which::Mistral7bInstruct,
+            "User question",
+            &["Previous user question".to_string()],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            generated_prompt.as_str(),
+            "<s>[INST][/INST]</s>\n[INST] User question [/INST] "
+        );
+    }
+
+    fn tool_conversation() -> (Vec<ChatMessage>, Vec<Tool>) {
+        let call = ToolCall {
+            id: "call_0".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "get_weather".to_string(),
+                arguments: r#"{"city":"Paris"}"#.to_string(),
+            },
+        };
+        let messages = vec![
+            ChatMessage::system("Be brief."),
+            ChatMessage::user("Weather in Paris?"),
+            ChatMessage {
+                content: None,
+                tool_calls: vec![call],
+                ..ChatMessage::assistant("")
+            },
+            ChatMessage::tool("call_0", "21C"),
+        ];
+        let tools = vec![Tool::function(
+            "get_weather",
+            "The weather of a city",
+            json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+        )];
+        (messages, tools)
+    }
+
+    #[test]
+    fn test_tool_chat_mistral_formatting() {
+        let (messages, tools) = tool_conversation();
+        let prompt = handle_tool_chat(Which::Mistral7bInstruct, &messages, &tools).unwrap();
+        assert_eq!(
+            prompt.as_str(),
+            format!(
+                "<s>[AVAILABLE_TOOLS] {}[/AVAILABLE_TOOLS][INST] Be brief.\n\nWeather in Paris? [/INST]\
+                 [TOOL_CALLS] [{{\"arguments\":{{\"city\":\"Paris\"}},\"id\":\"call_0\",\"name\":\"get_weather\"}}]</s>\
+                 [TOOL_RESULTS] {{\"call_id\":\"call_0\",\"content\":\"21C\"}}[/TOOL_RESULTS]",
+                json!(tools)
+            )
+        );
+    }
+
+    #[test]
+    fn test_tool_chat_zephyr_formatting() {
+        let (messages, tools) = tool_conversation();
+        let prompt = handle_tool_chat(Which::Zephyr7bBeta, &messages, &tools).unwrap();
+        let prompt = prompt.as_str();
+        assert!(prompt.starts_with("<|system|>\nBe brief.\n\nYou can call the following tools"));
+        assert!(prompt.contains(r#"{"description":"The weather of a city","name":"get_weather""#));
+        assert!(prompt.ends_with(
+            "</s>\n<|user|>\nWeather in Paris?</s>\n\
+             <|assistant|>\n<tool_call>{\"arguments\":{\"city\":\"Paris\"},\"name\":\"get_weather\"}</tool_call></s>\n\
+             <|user|>\n<tool_response>{\"content\":\"21C\",\"name\":\"get_weather\"}</tool_response></s>\n\
+             <|assistant|>"
+        ));
+
+        // without tools the conversation keeps its plain form
+        let prompt = handle_tool_chat(Which::OpenChat35, &messages[..2], &[]).unwrap();
+        assert_eq!(
+            prompt.as_str(),
+            "GPT4 Correct User: Be brief.\n\nWeather in Paris?<|end_of_turn|>GPT4 Correct Assistant:"
+        );
+    }
+
+    #[test]
+    fn test_with_context_numbers_the_sources() {
+        let sources = [
+            ContextSource {
+                citation: "docs/a.md".to_string(),
+                text: "Alpha".to_string(),
+            },
+            ContextSource {
+                citation: "b.txt".to_string(),
+                text: "Beta".to_string(),
+            },
+        ];
+        let prompt = with_context("What is it?", &sources);
+        assert!(prompt.contains("[1] docs/a.md\nAlpha\n\n[2] b.txt\nBeta"));
+        assert!(prompt.ends_with("Question: What is it?"));
+        assert_eq!(with_context("What is it?", &[]), "What is it?");
+    }
 }
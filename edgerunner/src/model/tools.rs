@@ -0,0 +1,337 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as Json};
+
+use crate::error::{EdgerunnerError, Result, ResultExt};
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+/// A tool the model may call, shaped like the tools of the OpenAI chat API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    /// Always `function`.
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// The JSON schema of the arguments object.
+    #[serde(default = "empty_object")]
+    pub parameters: Json,
+}
+
+fn empty_object() -> Json {
+    json!({"type": "object", "properties": {}})
+}
+
+impl Tool {
+    pub fn function(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: Json,
+    ) -> Self {
+        Self {
+            kind: function_type(),
+            function: FunctionDefinition {
+                name: name.into(),
+                description: description.into(),
+                parameters,
+            },
+        }
+    }
+}
+
+/// A call of a tool made by the model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    /// Always `function`.
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as JSON text, which the model may have gotten wrong.
+    pub arguments: String,
+}
+
+impl FunctionCall {
+    pub fn parsed_arguments(&self) -> Result<Json> {
+        serde_json::from_str(&self.arguments)
+            .map_err(|e| anyhow::Error::msg(format!("arguments of {}: {}", self.name, e)))
+            .kind(EdgerunnerError::Inference)
+    }
+
+    /// The arguments as a JSON value for the prompt, or as a string when they are not JSON.
+    pub(crate) fn arguments_json(&self) -> Json {
+        serde_json::from_str(&self.arguments).unwrap_or_else(|_| Json::from(&*self.arguments))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool call, answering the call of `tool_call_id`.
+    Tool,
+}
+
+/// A message of a conversation, shaped like the messages of the OpenAI chat API.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    #[serde(default)]
+    pub content: Option<String>,
+    /// The tools an assistant message calls.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a tool message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: Some(content.into()),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+
+    /// The `content` returned by the call `tool_call_id`.
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+
+    pub fn content(&self) -> &str {
+        self.content.as_deref().unwrap_or_default()
+    }
+}
+
+/// Whether the model may, may not or has to call a tool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoice {
+    #[default]
+    Auto,
+    /// The tools are left out of the prompt.
+    None,
+    /// The reply is constrained to a single call of one of the tools.
+    Required,
+}
+
+/// A conversation to answer with the tools the answer may call.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    #[serde(default)]
+    pub tool_choice: ToolChoice,
+}
+
+/// A call as the model writes it, `{"name": ..., "arguments": {...}}`.
+fn tool_call(value: &Json, id: String) -> Option<ToolCall> {
+    let value = value.get("function").unwrap_or(value);
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = match value.get("arguments").or_else(|| value.get("parameters")) {
+        Some(Json::String(arguments)) => arguments.clone(),
+        Some(arguments) => arguments.to_string(),
+        None => "{}".to_string(),
+    };
+    Some(ToolCall {
+        id,
+        kind: function_type(),
+        function: FunctionCall { name, arguments },
+    })
+}
+
+/// The calls of a JSON object or array, `None` when any of them is not a call.
+fn tool_calls(value: &Json, first_id: usize) -> Option<Vec<ToolCall>> {
+    let values = match value {
+        Json::Array(values) => values.as_slice(),
+        value => std::slice::from_ref(value),
+    };
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let id = value
+                .get("id")
+                .and_then(Json::as_str)
+                .map_or_else(|| format!("call_{}", first_id + i), str::to_string);
+            tool_call(value, id)
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|calls| !calls.is_empty())
+}
+
+/// The first JSON value of `text` and the text after it.
+fn leading_json(text: &str) -> Option<(Json, &str)> {
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Json>();
+    let value = values.next()?.ok()?;
+    Some((value, &text[values.byte_offset()..]))
+}
+
+/// Splits a reply into its text and the tools it calls, numbering the calls without an id
+/// from `first_id`. Calls are recognized in `<tool_call>` tags, after a `[TOOL_CALLS]` marker,
+/// or as a reply made only of a call object or an array of them.
+pub fn parse_tool_calls(text: &str, first_id: usize) -> (Option<String>, Vec<ToolCall>) {
+    let mut content = String::new();
+    let mut calls = Vec::new();
+    if text.contains("<tool_call>") {
+        let mut parts = text.split("<tool_call>");
+        content += parts.next().unwrap_or_default();
+        for part in parts {
+            let (call, rest) = part.split_once("</tool_call>").unwrap_or((part, ""));
+            match leading_json(call.trim())
+                .and_then(|(value, _)| tool_calls(&value, first_id + calls.len()))
+            {
+                Some(found) => calls.extend(found),
+                None => content += call,
+            }
+            content += rest;
+        }
+    } else if let Some((before, after)) = text.split_once("[TOOL_CALLS]") {
+        content += before;
+        match leading_json(after.trim_start())
+            .and_then(|(value, rest)| Some((tool_calls(&value, first_id)?, rest)))
+        {
+            Some((found, rest)) => {
+                calls = found;
+                content += rest;
+            }
+            None => content += after,
+        }
+    } else {
+        let trimmed = text.trim();
+        let unfenced = trimmed
+            .strip_prefix("```json")
+            .or_else(|| trimmed.strip_prefix("```"))
+            .and_then(|json| json.strip_suffix("```"))
+            .unwrap_or(trimmed);
+        match leading_json(unfenced)
+            .filter(|(_, rest)| rest.trim().is_empty())
+            .and_then(|(value, _)| tool_calls(&value, first_id))
+        {
+            Some(found) => calls = found,
+            None => content += text,
+        }
+    }
+    let content = content.trim();
+    ((!content.is_empty()).then(|| content.to_string()), calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            kind: function_type(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_tool_calls_in_tags_and_after_markers() {
+        let (content, calls) = parse_tool_calls(
+            "Let me check.\n<tool_call>{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}</tool_call>\n<tool_call>{\"name\": \"get_time\"}</tool_call>",
+            2,
+        );
+        assert_eq!(content.as_deref(), Some("Let me check."));
+        assert_eq!(
+            calls,
+            vec![
+                call("call_2", "get_weather", r#"{"city":"Paris"}"#),
+                call("call_3", "get_time", "{}"),
+            ]
+        );
+
+        let (content, calls) = parse_tool_calls(
+            r#"[TOOL_CALLS] [{"name": "get_time", "arguments": {"zone": "UTC"}, "id": "abc123XYZ"}]"#,
+            0,
+        );
+        assert_eq!(content, None);
+        assert_eq!(
+            calls,
+            vec![call("abc123XYZ", "get_time", r#"{"zone":"UTC"}"#)]
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_calls_of_a_json_reply() {
+        let (content, calls) = parse_tool_calls(
+            "```json\n{\"name\": \"get_time\", \"arguments\": \"{}\"}\n```",
+            0,
+        );
+        assert_eq!(content, None);
+        assert_eq!(calls, vec![call("call_0", "get_time", "{}")]);
+
+        // plain text and JSON that is not a call stay text
+        for text in ["It is sunny.", r#"{"temperature": 21}"#, "[1, 2]"] {
+            assert_eq!(parse_tool_calls(text, 0), (Some(text.to_string()), vec![]));
+        }
+    }
+
+    #[test]
+    fn test_messages_have_the_openai_shape() {
+        let json = r#"{
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "call_0", "type": "function",
+                     "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}}
+                ]},
+                {"role": "tool", "tool_call_id": "call_0", "content": "21C"}
+            ],
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}],
+            "tool_choice": "required"
+        }"#;
+        let request: ChatRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(request.messages[2], ChatMessage::tool("call_0", "21C"));
+        assert_eq!(request.tool_choice, ToolChoice::Required);
+        assert_eq!(request.tools[0].function.parameters, empty_object());
+        let arguments = request.messages[1].tool_calls[0]
+            .function
+            .parsed_arguments()
+            .unwrap();
+        assert_eq!(arguments, json!({"city": "Paris"}));
+        assert_eq!(
+            serde_json::to_value(ChatMessage::user("hi")).unwrap(),
+            json!({"role": "user", "content": "hi"})
+        );
+    }
+}
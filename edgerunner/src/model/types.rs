@@ -1,7 +1,213 @@
-use candle_transformers::models::mistral::Model as Mistral;
-use candle_transformers::models::quantized_llama::ModelWeights;
+use std::{collections::HashMap, fmt};
 
-pub enum ModelType {
-    Mistral(Mistral),
-    Quantized(ModelWeights),
+use candle_core::quantized::{ggml_file, gguf_file};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EdgerunnerError, Result, ResultExt};
+
+const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+const DEFAULT_EOS_TOKEN_ID: u32 = 2;
+
+/// The model architectures edgerunner knows how to run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModelArchitecture {
+    /// Quantized llama and mistral models.
+    Llama,
+    /// Quantized mixtral, a llama architecture with mixture of experts feed forward layers.
+    Mixtral,
+    /// Quantized phi-2 in the candle (mixformer) tensor layout.
+    Phi2,
+    /// Quantized stablelm-3b-4e1t.
+    StableLm,
+    /// Full precision mistral loaded from safetensors.
+    Mistral,
+}
+
+impl fmt::Display for ModelArchitecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Llama => write!(f, "llama"),
+            Self::Mixtral => write!(f, "mixtral"),
+            Self::Phi2 => write!(f, "phi2"),
+            Self::StableLm => write!(f, "stablelm"),
+            Self::Mistral => write!(f, "mistral"),
+        }
+    }
+}
+
+impl ModelArchitecture {
+    /// Picks the architecture from the `general.architecture` metadata of a GGUF file, falling
+    /// back to the tensor names for files converted by candle that do not set it.
+    pub fn from_gguf(content: &gguf_file::Content) -> Result<Self> {
+        let architecture = match content.metadata.get("general.architecture") {
+            Some(value) => value.to_string().kind(EdgerunnerError::Load)?.clone(),
+            None if content
+                .tensor_infos
+                .contains_key("transformer.embd.wte.weight") =>
+            {
+                "phi2".to_string()
+            }
+            None if content
+                .tensor_infos
+                .contains_key("model.embed_tokens.weight") =>
+            {
+                "stablelm".to_string()
+            }
+            None => "llama".to_string(),
+        };
+
+        match architecture.as_str() {
+            "llama" if gguf_u32(&content.metadata, "llama.expert_count").unwrap_or(0) > 1 => {
+                Ok(Self::Mixtral)
+            }
+            "llama" => Ok(Self::Llama),
+            "phi2" | "phi-msft" | "mixformer" => Ok(Self::Phi2),
+            "stablelm" => Ok(Self::StableLm),
+            architecture => Err(EdgerunnerError::load(format!(
+                "unsupported model architecture: {}",
+                architecture
+            ))),
+        }
+    }
+
+    /// The repository of the tokenizer of architectures whose vocabulary is not the llama one,
+    /// the candle GGUF files of phi-2 and stablelm do not embed it.
+    pub fn tokenizer_repo(&self) -> Option<&'static str> {
+        match self {
+            Self::Llama | Self::Mixtral | Self::Mistral => None,
+            Self::Phi2 => Some("microsoft/phi-2"),
+            Self::StableLm => Some("stabilityai/stablelm-3b-4e1t"),
+        }
+    }
+
+    /// The key prefix used for the architecture specific GGUF metadata.
+    fn gguf_prefix(&self) -> &'static str {
+        match self {
+            Self::Llama | Self::Mixtral | Self::Mistral => "llama",
+            Self::Phi2 => "phi2",
+            Self::StableLm => "stablelm",
+        }
+    }
+}
+
+/// Architecture independent facts about a loaded model.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ModelMetadata {
+    pub architecture: ModelArchitecture,
+    pub vocab_size: usize,
+    pub context_length: usize,
+    pub eos_token_ids: Vec<u32>,
+}
+
+impl ModelMetadata {
+    /// Reads the metadata from a GGUF file, `default_vocab_size` and `max_context_length` come
+    /// from the architecture configuration used to build the model.
+    pub fn from_gguf(
+        content: &gguf_file::Content,
+        architecture: ModelArchitecture,
+        default_vocab_size: usize,
+        max_context_length: usize,
+    ) -> Self {
+        let metadata = &content.metadata;
+        let prefix = architecture.gguf_prefix();
+
+        let vocab_size = match metadata.get("tokenizer.ggml.tokens") {
+            Some(gguf_file::Value::Array(tokens)) => tokens.len(),
+            _ => gguf_u32(metadata, &format!("{prefix}.vocab_size"))
+                .map(|v| v as usize)
+                .or_else(|| {
+                    content
+                        .tensor_infos
+                        .get("token_embd.weight")
+                        .map(|t| t.shape.dims()[0])
+                })
+                .unwrap_or(default_vocab_size),
+        };
+        let context_length = gguf_u32(metadata, &format!("{prefix}.context_length"))
+            .map(|v| v as usize)
+            .unwrap_or(max_context_length)
+            .min(max_context_length);
+        let eos_token_ids = gguf_u32(metadata, "tokenizer.ggml.eos_token_id")
+            .map(|id| vec![id])
+            .unwrap_or_default();
+
+        Self {
+            architecture,
+            vocab_size,
+            context_length,
+            eos_token_ids,
+        }
+    }
+
+    /// GGML files only carry the llama hyper parameters.
+    pub fn from_ggml(hparams: &ggml_file::HParams) -> Self {
+        Self {
+            architecture: ModelArchitecture::Llama,
+            vocab_size: hparams.n_vocab as usize,
+            context_length: DEFAULT_CONTEXT_LENGTH,
+            eos_token_ids: vec![DEFAULT_EOS_TOKEN_ID],
+        }
+    }
+}
+
+fn gguf_u32(metadata: &HashMap<String, gguf_file::Value>, key: &str) -> Option<u32> {
+    metadata.get(key).and_then(|v| v.to_u32().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::gguf_file::{Content, Value, VersionedMagic};
+
+    fn gguf_content(metadata: Vec<(&str, Value)>) -> Content {
+        Content {
+            magic: VersionedMagic::GgufV3,
+            metadata: metadata
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+            tensor_infos: HashMap::new(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_architecture_from_gguf() {
+        let llama = gguf_content(vec![(
+            "general.architecture",
+            Value::String("llama".to_string()),
+        )]);
+        assert_eq!(
+            ModelArchitecture::from_gguf(&llama).unwrap(),
+            ModelArchitecture::Llama
+        );
+
+        let mixtral = gguf_content(vec![
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.expert_count", Value::U32(8)),
+        ]);
+        assert_eq!(
+            ModelArchitecture::from_gguf(&mixtral).unwrap(),
+            ModelArchitecture::Mixtral
+        );
+
+        let unknown = gguf_content(vec![(
+            "general.architecture",
+            Value::String("gpt2".to_string()),
+        )]);
+        assert!(ModelArchitecture::from_gguf(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_metadata_from_gguf() {
+        let content = gguf_content(vec![
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.context_length", Value::U32(32768)),
+            ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+        ]);
+        let metadata = ModelMetadata::from_gguf(&content, ModelArchitecture::Llama, 32000, 4096);
+        assert_eq!(metadata.vocab_size, 32000);
+        assert_eq!(metadata.context_length, 4096);
+        assert_eq!(metadata.eos_token_ids, vec![2]);
+    }
 }
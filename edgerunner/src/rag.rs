@@ -0,0 +1,333 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::model::SamplingParams,
+    engine::Engine,
+    error::{EdgerunnerError, Result, ResultExt},
+    model::prompt::{handle_chat_input, with_context, ContextSource},
+    runner::{
+        embeddings::EmbeddingOptions, session::ModelFingerprint, text_generation::Generation,
+    },
+};
+
+const INDEX_VERSION: u32 = 2;
+const DOCUMENT_EXTENSIONS: [&str; 4] = ["txt", "md", "markdown", "text"];
+
+/// How documents are split into the chunks that are embedded and retrieved.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkConfig {
+    pub chunk_words: usize,
+    /// The words shared by consecutive chunks, so that a sentence cut in two is found in either.
+    pub overlap_words: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            chunk_words: 200,
+            overlap_words: 40,
+        }
+    }
+}
+
+/// Splits `text` in windows of `chunk_words` words.
+pub fn chunk_text(text: &str, config: &ChunkConfig) -> Result<Vec<String>> {
+    if config.chunk_words == 0 || config.overlap_words >= config.chunk_words {
+        return Err(EdgerunnerError::config(format!(
+            "chunks of {} words cannot overlap by {} words",
+            config.chunk_words, config.overlap_words
+        )));
+    }
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    let step = config.chunk_words - config.overlap_words;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let end = (start + config.chunk_words).min(words.len());
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        start += step;
+    }
+    Ok(chunks)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedChunk {
+    /// The document path relative to the ingested directory.
+    pub source: String,
+    /// The position of the chunk in its document.
+    pub chunk: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+impl IndexedChunk {
+    pub fn citation(&self) -> String {
+        format!("{}#{}", self.source, self.chunk)
+    }
+}
+
+/// Embedded document chunks stored as a JSON file, searched by cosine similarity.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RagIndex {
+    pub version: u32,
+    /// The model that embedded the chunks, queries have to be embedded by the same one.
+    pub model: ModelFingerprint,
+    pub chunking: ChunkConfig,
+    pub embedding: EmbeddingOptions,
+    pub chunks: Vec<IndexedChunk>,
+}
+
+/// A chunk found for a query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit<'a> {
+    pub chunk: &'a IndexedChunk,
+    pub score: f32,
+}
+
+/// The answer of [`ask`] and the chunks it was given, in citation order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RagAnswer {
+    pub generation: Generation,
+    pub citations: Vec<String>,
+}
+
+fn collect_documents(dir: &Path, documents: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            collect_documents(&path, documents)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| DOCUMENT_EXTENSIONS.contains(&ext.to_string_lossy().as_ref()))
+        {
+            documents.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+impl RagIndex {
+    /// Chunks and embeds the text and markdown files under `dir`.
+    pub fn ingest(
+        engine: &mut Engine,
+        dir: impl AsRef<Path>,
+        chunking: &ChunkConfig,
+        embedding: &EmbeddingOptions,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut documents = Vec::new();
+        collect_documents(dir, &mut documents)?;
+        documents.sort();
+
+        let mut chunks = Vec::new();
+        for path in &documents {
+            let text = fs::read_to_string(path)
+                .map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))
+                .kind(EdgerunnerError::Io)?;
+            let source = path
+                .strip_prefix(dir)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .to_string();
+            let texts = chunk_text(&text, chunking)?;
+            debug!("{} has {} chunks", source, texts.len());
+            let embeddings = engine.embed_batch(
+                &texts.iter().map(String::as_str).collect::<Vec<_>>(),
+                embedding,
+            )?;
+            chunks.extend(texts.into_iter().zip(embeddings).enumerate().map(
+                |(chunk, (text, embedding))| IndexedChunk {
+                    source: source.clone(),
+                    chunk,
+                    text,
+                    embedding,
+                },
+            ));
+        }
+        info!(
+            "Indexed {} chunks of {} documents",
+            chunks.len(),
+            documents.len()
+        );
+
+        Ok(Self {
+            version: INDEX_VERSION,
+            model: engine.fingerprint(),
+            chunking: chunking.clone(),
+            embedding: embedding.clone(),
+            chunks,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(self).kind(EdgerunnerError::Io)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))
+            .kind(EdgerunnerError::Io)?;
+        let index: Self = serde_json::from_str(&json).kind(EdgerunnerError::Io)?;
+        if index.version != INDEX_VERSION {
+            return Err(EdgerunnerError::config(format!(
+                "{} is a version {} index, expected version {}",
+                path.display(),
+                index.version,
+                INDEX_VERSION
+            )));
+        }
+        Ok(index)
+    }
+
+    /// The `top_k` chunks most similar to `query`, best first.
+    pub fn search(
+        &self,
+        engine: &mut Engine,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit<'_>>> {
+        let model = engine.fingerprint();
+        if model != self.model {
+            return Err(EdgerunnerError::config(format!(
+                "the index was embedded with {} ({} bytes, weights {:016x}), not {} ({} bytes, \
+                 weights {:016x})",
+                self.model.model,
+                self.model.size_bytes,
+                self.model.weights_hash,
+                model.model,
+                model.size_bytes,
+                model.weights_hash
+            )));
+        }
+        let query = engine.embed(query, &self.embedding)?;
+        if let Some(chunk) = self
+            .chunks
+            .iter()
+            .find(|chunk| chunk.embedding.len() != query.len())
+        {
+            return Err(EdgerunnerError::config(format!(
+                "the index embeds {} in {} dimensions, the query has {}",
+                chunk.citation(),
+                chunk.embedding.len(),
+                query.len()
+            )));
+        }
+        let mut hits = self
+            .chunks
+            .iter()
+            .map(|chunk| SearchHit {
+                chunk,
+                score: cosine(&query, &chunk.embedding),
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(top_k);
+        Ok(hits)
+    }
+}
+
+/// Answers `question` with the `top_k` most similar chunks of `index` as cited context. The
+/// least similar chunks are left out when the prompt and the sample length exceed the context.
+pub fn ask(
+    engine: &mut Engine,
+    index: &RagIndex,
+    question: &str,
+    top_k: usize,
+    params: &SamplingParams,
+    on_token: impl Fn(&str),
+) -> Result<RagAnswer> {
+    let hits = index.search(engine, question, top_k)?;
+    let sources = hits
+        .iter()
+        .map(|hit| ContextSource {
+            citation: hit.chunk.citation(),
+            text: hit.chunk.text.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let budget = engine.context_length().saturating_sub(params.sample_len);
+    let mut used = sources.len();
+    while used > 0 {
+        let message = with_context(question, &sources[..used]);
+        let prompt = handle_chat_input(engine.config().which, &message, &[], None)?;
+        if engine.count_tokens(prompt.as_str())? <= budget {
+            break;
+        }
+        used -= 1;
+    }
+    if used < sources.len() {
+        info!(
+            "Only {} of {} chunks fit in the context",
+            used,
+            sources.len()
+        );
+    }
+
+    let sources = &sources[..used];
+    let message = with_context(question, sources);
+    let generation = engine.chat_stream(&message, &[], None, params, on_token)?;
+    Ok(RagAnswer {
+        generation,
+        citations: sources.iter().map(|s| s.citation.clone()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_text_overlaps() {
+        let config = ChunkConfig {
+            chunk_words: 4,
+            overlap_words: 1,
+        };
+        let chunks = chunk_text("a b c d e f g", &config).unwrap();
+        assert_eq!(chunks, vec!["a b c d", "d e f g"]);
+        let chunks = chunk_text("a b\n\nc", &config).unwrap();
+        assert_eq!(chunks, vec!["a b c"]);
+        assert!(chunk_text("", &config).unwrap().is_empty());
+        assert!(chunk_text(
+            "a",
+            &ChunkConfig {
+                chunk_words: 2,
+                overlap_words: 2
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_cosine() {
+        assert!((cosine(&[1., 0.], &[2., 0.]) - 1.).abs() < 1e-6);
+        assert!(cosine(&[1., 0.], &[0., 3.]).abs() < 1e-6);
+        assert_eq!(cosine(&[0., 0.], &[1., 0.]), 0.);
+    }
+}
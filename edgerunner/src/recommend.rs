@@ -0,0 +1,403 @@
+use std::fmt;
+
+use candle_core::quantized::GgmlDType;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    conf::which::{ModelProfile, Which},
+    error::Result,
+    system_benchmark::{
+        device_performance, BenchDeviceHandler, BenchmarkConfig, DeviceName, DevicePerformance,
+    },
+    util::{available_memory_bytes, format_size, total_memory_bytes},
+};
+
+/// Headroom for the activations, the tokenizer and the runtime on top of weights and kv cache.
+const MEMORY_OVERHEAD: f64 = 1.1;
+/// Without SIMD the quantized kernels are compute bound well below the memory bandwidth.
+const NO_SIMD_PENALTY: f64 = 0.5;
+
+/// The SIMD extensions candle was compiled with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SimdFeatures {
+    pub avx: bool,
+    pub neon: bool,
+    pub simd128: bool,
+    pub f16c: bool,
+}
+
+impl SimdFeatures {
+    pub fn detect() -> Self {
+        Self {
+            avx: candle_core::utils::with_avx(),
+            neon: candle_core::utils::with_neon(),
+            simd128: candle_core::utils::with_simd128(),
+            f16c: candle_core::utils::with_f16c(),
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.avx || self.neon || self.simd128
+    }
+}
+
+impl fmt::Display for SimdFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let enabled = [
+            ("avx", self.avx),
+            ("neon", self.neon),
+            ("simd128", self.simd128),
+            ("f16c", self.f16c),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+        match enabled.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", enabled.join(" ")),
+        }
+    }
+}
+
+/// What the machine can do, as measured by the system benchmarks.
+#[derive(Clone, Debug, Serialize)]
+pub struct HardwareProfile {
+    pub performance: DevicePerformance,
+    pub total_memory_bytes: Option<u64>,
+    pub available_memory_bytes: Option<u64>,
+    /// Only known for cuda devices when `nvidia-smi` is installed.
+    pub vram_bytes: Option<u64>,
+    pub simd: SimdFeatures,
+}
+
+impl HardwareProfile {
+    /// Benchmarks the device models run on, the first one of the [`BenchDeviceHandler`], with
+    /// the kernels of the given models.
+    pub fn detect(models: &[Which]) -> Result<Self> {
+        let handler = BenchDeviceHandler::new()?;
+        let device = &handler.devices[0];
+
+        let mut quantized_dtypes = Vec::new();
+        for which in models {
+            let dtype = which.profile().kernel_dtype;
+            if !quantized_dtypes.contains(&dtype) {
+                quantized_dtypes.push(dtype);
+            }
+        }
+        let config = BenchmarkConfig {
+            quantized_dtypes,
+            thread_counts: Vec::new(),
+            ..BenchmarkConfig::default()
+        };
+        let performance = device_performance(device, &config)?;
+        let vram_bytes = match performance.device {
+            DeviceName::GPU if device.is_cuda() => cuda_vram_bytes(),
+            _ => None,
+        };
+
+        Ok(Self {
+            performance,
+            total_memory_bytes: total_memory_bytes(),
+            available_memory_bytes: available_memory_bytes(),
+            vram_bytes,
+            simd: SimdFeatures::detect(),
+        })
+    }
+
+    /// The memory models can use: the vram of a gpu, else the available ram.
+    pub fn memory_bytes(&self) -> Option<u64> {
+        match self.performance.device {
+            DeviceName::GPU => self.vram_bytes.or(self.available_memory_bytes),
+            DeviceName::CPU => self.available_memory_bytes,
+        }
+    }
+
+    /// The measured bandwidth of the quantized kernel, falling back to the raw memory bandwidth.
+    fn weights_gbps(&self, dtype: GgmlDType) -> (f64, bool) {
+        let kernel = format!("{:?}", dtype);
+        match self
+            .performance
+            .quantized_matmul
+            .iter()
+            .find(|q| q.dtype == kernel)
+        {
+            Some(q) => (q.weights_gbps, true),
+            None => (self.performance.memory_bandwidth_gbps, false),
+        }
+    }
+}
+
+/// The total memory of the first cuda device reported by `nvidia-smi`.
+fn cuda_vram_bytes() -> Option<u64> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args(["--query-gpu=memory.total", "--format=csv,noheader,nounits"])
+        .output()
+        .ok()?;
+    let mib = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(mib << 20)
+}
+
+/// The requirements a recommended model has to meet.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecommendationRequest {
+    pub min_tokens_per_second: f64,
+    /// Overrides the memory detected on the machine.
+    pub memory_budget_bytes: Option<u64>,
+    /// The context the kv cache is sized for.
+    pub context_length: usize,
+    /// Only consider the models [`Which::is_available`] reports as supported.
+    pub only_available: bool,
+}
+
+impl Default for RecommendationRequest {
+    fn default() -> Self {
+        Self {
+            min_tokens_per_second: 5.,
+            memory_budget_bytes: None,
+            context_length: 4096,
+            only_available: true,
+        }
+    }
+}
+
+/// How a model is expected to run on the machine.
+#[derive(Clone, Debug, Serialize)]
+pub struct ModelEstimate {
+    pub which: Which,
+    pub profile: ModelProfile,
+    pub required_memory_bytes: u64,
+    pub estimated_tokens_per_second: f64,
+    pub fits_memory: bool,
+    pub meets_speed: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Recommendation {
+    /// `None` when no model fits in memory.
+    pub which: Option<Which>,
+    /// Every considered model, best first.
+    pub estimates: Vec<ModelEstimate>,
+    pub explanation: Vec<String>,
+}
+
+/// Estimates the memory use and the decode speed of a model. Decoding reads all the active
+/// weights for every token, so the speed is bounded by the bandwidth of the quantized kernel.
+pub fn estimate_model(
+    which: Which,
+    hardware: &HardwareProfile,
+    request: &RecommendationRequest,
+    memory_bytes: Option<u64>,
+) -> ModelEstimate {
+    let profile = which.profile();
+    let required_memory_bytes = ((profile.file_size_bytes
+        + profile.kv_cache_bytes_per_token * request.context_length as u64)
+        as f64
+        * MEMORY_OVERHEAD) as u64;
+
+    let (gbps, measured_kernel) = hardware.weights_gbps(profile.kernel_dtype);
+    let penalty = match measured_kernel || hardware.simd.any() {
+        true => 1.,
+        false => NO_SIMD_PENALTY,
+    };
+    let estimated_tokens_per_second = gbps * 1e9 / profile.active_weights_bytes() as f64 * penalty;
+
+    ModelEstimate {
+        which,
+        profile,
+        required_memory_bytes,
+        estimated_tokens_per_second,
+        fits_memory: memory_bytes.is_none_or(|m| required_memory_bytes <= m),
+        meets_speed: estimated_tokens_per_second >= request.min_tokens_per_second,
+    }
+}
+
+/// Picks the largest, least quantized model that fits in memory and meets the target speed, or
+/// the fastest model that fits when none meets it.
+pub fn recommend_model(
+    hardware: &HardwareProfile,
+    request: &RecommendationRequest,
+) -> Recommendation {
+    let memory_bytes = request.memory_budget_bytes.or(hardware.memory_bytes());
+
+    let mut estimates = Which::value_variants()
+        .iter()
+        .filter(|which| !request.only_available || which.is_available())
+        .map(|which| estimate_model(*which, hardware, request, memory_bytes))
+        .collect::<Vec<_>>();
+    // bigger models first, then more bits per weight, then faster
+    estimates.sort_by(|a, b| {
+        b.profile
+            .parameters
+            .cmp(&a.profile.parameters)
+            .then(b.profile.file_size_bytes.cmp(&a.profile.file_size_bytes))
+            .then(
+                b.estimated_tokens_per_second
+                    .total_cmp(&a.estimated_tokens_per_second),
+            )
+    });
+
+    let mut explanation = vec![
+        format!(
+            "{}: {:.2} TFLOPS, {:.2} GB/s memory bandwidth, simd: {}",
+            hardware.performance.device,
+            hardware.performance.tflops,
+            hardware.performance.memory_bandwidth_gbps,
+            hardware.simd
+        ),
+        match (request.memory_budget_bytes, memory_bytes) {
+            (Some(budget), _) => format!("memory budget: {}", format_size(budget as usize)),
+            (None, Some(memory)) => format!("available memory: {}", format_size(memory as usize)),
+            (None, None) => "available memory unknown, assuming every model fits".to_string(),
+        },
+    ];
+    for estimate in &estimates {
+        explanation.push(format!(
+            "{} ({}, {}): needs {} for a {} token context, ~{:.2} token/s{}{}",
+            estimate.which,
+            estimate.profile.quantization,
+            format_size(estimate.profile.file_size_bytes as usize),
+            format_size(estimate.required_memory_bytes as usize),
+            request.context_length,
+            estimate.estimated_tokens_per_second,
+            if estimate.fits_memory {
+                ""
+            } else {
+                ", does not fit in memory"
+            },
+            if estimate.meets_speed {
+                ""
+            } else {
+                ", below the target speed"
+            },
+        ));
+    }
+
+    let best = estimates
+        .iter()
+        .find(|e| e.fits_memory && e.meets_speed)
+        .or_else(|| {
+            estimates.iter().filter(|e| e.fits_memory).max_by(|a, b| {
+                a.estimated_tokens_per_second
+                    .total_cmp(&b.estimated_tokens_per_second)
+            })
+        });
+    explanation.push(match best {
+        Some(e) if e.meets_speed => format!(
+            "recommending {}, the largest model that fits in memory and reaches {:.1} token/s",
+            e.which, request.min_tokens_per_second
+        ),
+        Some(e) => format!(
+            "no model reaches {:.1} token/s, recommending {}, the fastest model that fits in memory",
+            request.min_tokens_per_second, e.which
+        ),
+        None => "no model fits in memory".to_string(),
+    });
+
+    Recommendation {
+        which: best.map(|e| e.which),
+        estimates,
+        explanation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_benchmark::QuantizedMatMulPerformance;
+
+    const GB: u64 = 1_000_000_000;
+
+    fn hardware(q4k_gbps: f64, available_memory_bytes: u64) -> HardwareProfile {
+        let quantized = |dtype: &str, weights_gbps| QuantizedMatMulPerformance {
+            dtype: dtype.to_string(),
+            ms_per_matmul: 1.,
+            gflops: 1.,
+            weights_gbps,
+        };
+        HardwareProfile {
+            performance: DevicePerformance {
+                device: DeviceName::CPU,
+                tflops: 0.5,
+                memory_bandwidth_gbps: q4k_gbps,
+                quantized_matmul: vec![
+                    quantized("Q4K", q4k_gbps),
+                    quantized("Q2K", q4k_gbps * 0.8),
+                ],
+                thread_scaling: Vec::new(),
+            },
+            total_memory_bytes: Some(available_memory_bytes),
+            available_memory_bytes: Some(available_memory_bytes),
+            vram_bytes: None,
+            simd: SimdFeatures::default(),
+        }
+    }
+
+    fn request(min_tokens_per_second: f64) -> RecommendationRequest {
+        RecommendationRequest {
+            min_tokens_per_second,
+            only_available: false,
+            ..RecommendationRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_recommends_largest_model_meeting_targets() {
+        let recommendation = recommend_model(&hardware(100., 64 * GB), &request(5.));
+        assert_eq!(recommendation.which, Some(Which::Mixtral));
+        assert_eq!(
+            recommendation.estimates.len(),
+            Which::value_variants().len()
+        );
+    }
+
+    #[test]
+    fn test_recommends_smaller_model_within_memory_budget() {
+        let recommendation = recommend_model(&hardware(100., 64 * GB), &request(5.));
+        let small_budget = RecommendationRequest {
+            memory_budget_bytes: Some(5 * GB),
+            ..request(5.)
+        };
+        let constrained = recommend_model(&hardware(100., 64 * GB), &small_budget);
+        assert_ne!(constrained.which, recommendation.which);
+        assert_eq!(constrained.which, Some(Which::Mistral7bInstructQ2));
+    }
+
+    #[test]
+    fn test_falls_back_to_fastest_model_below_target_speed() {
+        let recommendation = recommend_model(&hardware(10., 64 * GB), &request(50.));
+        assert_eq!(recommendation.which, Some(Which::Mistral7bInstructQ2));
+        assert!(recommendation.estimates.iter().all(|e| !e.meets_speed));
+        assert!(recommendation
+            .explanation
+            .last()
+            .unwrap()
+            .starts_with("no model reaches"));
+    }
+
+    #[test]
+    fn test_no_model_fits_in_memory() {
+        let recommendation = recommend_model(&hardware(100., GB), &request(5.));
+        assert_eq!(recommendation.which, None);
+    }
+
+    #[test]
+    fn test_only_available_models() {
+        let only_available = RecommendationRequest {
+            only_available: true,
+            ..request(5.)
+        };
+        let recommendation = recommend_model(&hardware(100., 64 * GB), &only_available);
+        assert_eq!(recommendation.which, Some(Which::Mistral7bInstruct));
+        assert!(recommendation
+            .estimates
+            .iter()
+            .all(|e| e.which.is_available()));
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+use super::events::FinishReason;
+
+/// Deterministic decoding that keeps the most likely continuations instead of sampling one.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BeamSearchConfig {
+    /// The continuations kept at every step, and the beams returned.
+    pub beam_width: usize,
+    /// The exponent of the length dividing the log probability of a beam, above 0 favors longer
+    /// beams and below 0 shorter ones.
+    pub length_penalty: f64,
+    /// Stops once `beam_width` beams are finished, rather than once no running beam can beat
+    /// them.
+    pub early_stopping: bool,
+    /// Bans the tokens that would repeat an n-gram of this size, 0 allows every repeat.
+    pub no_repeat_ngram_size: usize,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.,
+            early_stopping: false,
+            no_repeat_ngram_size: 0,
+        }
+    }
+}
+
+impl BeamSearchConfig {
+    /// The score of a beam of `len` tokens, its log probability normalized by its length.
+    pub fn score(&self, logprob: f64, len: usize) -> f64 {
+        logprob / (len.max(1) as f64).powf(self.length_penalty)
+    }
+}
+
+/// A finished continuation of a beam search.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Beam {
+    pub text: String,
+    /// The generated tokens, with the end of sequence token if there is one.
+    pub tokens: Vec<u32>,
+    /// The log probability of the tokens under the model, after the repeat penalty.
+    pub logprob: f64,
+    /// The log probability normalized by the length, which ranks the beams.
+    pub score: f64,
+    pub finish_reason: FinishReason,
+}
+
+/// The tokens that would repeat an n-gram of `tokens` of size `n`.
+pub fn banned_tokens(tokens: &[u32], n: usize) -> Vec<u32> {
+    if n == 0 || tokens.len() + 1 < n {
+        return Vec::new();
+    }
+    let prefix = &tokens[tokens.len() + 1 - n..];
+    tokens
+        .windows(n)
+        .filter(|ngram| &ngram[..n - 1] == prefix)
+        .map(|ngram| ngram[n - 1])
+        .collect()
+}
+
+/// The indices of the `k` largest values, largest first.
+pub fn top_k(values: &[f32], k: usize) -> Vec<usize> {
+    let mut indices = (0..values.len()).collect::<Vec<_>>();
+    let k = k.min(values.len());
+    if k == 0 {
+        return Vec::new();
+    }
+    indices.select_nth_unstable_by(k - 1, |&i, &j| values[j].total_cmp(&values[i]));
+    indices.truncate(k);
+    indices.sort_by(|&i, &j| values[j].total_cmp(&values[i]));
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banned_tokens_complete_seen_ngrams() {
+        let tokens = [1, 2, 3, 1, 2, 4, 1];
+        assert_eq!(banned_tokens(&tokens, 3), Vec::<u32>::new());
+        assert_eq!(banned_tokens(&[1, 2, 3, 1, 2], 3), vec![3]);
+        assert_eq!(banned_tokens(&tokens, 2), vec![2, 2]);
+        assert_eq!(banned_tokens(&[5, 6], 1), vec![5, 6]);
+        assert_eq!(banned_tokens(&tokens, 0), Vec::<u32>::new());
+        assert_eq!(banned_tokens(&[1], 3), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_top_k_and_scores() {
+        assert_eq!(top_k(&[0.1, 0.7, f32::NEG_INFINITY, 0.5], 2), vec![1, 3]);
+        assert_eq!(top_k(&[0.1, 0.2], 5), vec![1, 0]);
+        let config = BeamSearchConfig::default();
+        assert_eq!(config.score(-6., 3), -2.);
+        let shorter = BeamSearchConfig {
+            length_penalty: 0.,
+            ..config
+        };
+        assert_eq!(shorter.score(-6., 3), -6.);
+    }
+}
@@ -0,0 +1,278 @@
+use std::time::Instant;
+
+use candle_core::{Device, Tensor, D};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::{
+    error::{EdgerunnerError, Result},
+    model::{causal_lm::CausalLm, types::ModelArchitecture},
+    runner::threads::{EffectiveThreads, ThreadPools},
+    system_benchmark::{BenchDevice, BenchmarkReport, DeviceName},
+};
+
+/// Settings of the token level inference benchmark.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BenchConfig {
+    /// The prompt lengths, in tokens, the prefill throughput is measured at.
+    pub prompt_lengths: Vec<usize>,
+    /// The number of tokens generated to measure the decode throughput.
+    pub decode_tokens: usize,
+    /// The number of measured runs of every scenario.
+    pub repetitions: usize,
+    /// The number of unmeasured runs done first to warm up caches and allocators.
+    pub warmup_repetitions: usize,
+    /// Seeds the synthetic prompts so that runs are comparable.
+    pub seed: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            prompt_lengths: vec![16, 64, 256],
+            decode_tokens: 64,
+            repetitions: 5,
+            warmup_repetitions: 1,
+            seed: 299792458,
+        }
+    }
+}
+
+/// Summary statistics of the samples of a measurement.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    /// Nearest rank percentiles over the samples, `None` without samples.
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+        Some(Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: percentile(50.),
+            p95: percentile(95.),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PrefillResult {
+    pub prompt_tokens: usize,
+    pub tokens_per_second: Stats,
+    pub time_to_first_token_ms: Stats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DecodeResult {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub tokens_per_second: Stats,
+}
+
+/// The results of [`run_inference_benchmark`].
+#[derive(Clone, Debug, Serialize)]
+pub struct InferenceBenchmark {
+    pub repetitions: usize,
+    pub prefill: Vec<PrefillResult>,
+    pub decode: DecodeResult,
+    pub threads: EffectiveThreads,
+    /// The peak resident memory of the process once the benchmark is done, when the platform
+    /// reports it. The `bench` command runs the system microbenchmarks afterwards so that their
+    /// buffers do not count towards it.
+    pub peak_memory_bytes: Option<u64>,
+}
+
+/// The JSON report of the `bench` command.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    pub model: String,
+    pub architecture: ModelArchitecture,
+    pub device: DeviceName,
+    /// The system microbenchmarks of every device, `None` when they were skipped.
+    pub system: Option<BenchmarkReport>,
+    pub inference: InferenceBenchmark,
+}
+
+/// Deterministic prompt tokens that avoid the model's eos tokens.
+fn synthetic_prompt(model: &dyn CausalLm, len: usize, seed: u64) -> Vec<u32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let vocab_size = model.vocab_size() as u32;
+    (0..len)
+        .map(|_| loop {
+            let token = rng.gen_range(0..vocab_size);
+            if !model.eos_token_ids().contains(&token) {
+                break token;
+            }
+        })
+        .collect()
+}
+
+/// The statistics of a measurement, whose samples come from at least one repetition.
+fn stats(samples: &[f64]) -> Result<Stats> {
+    Stats::from_samples(samples)
+        .ok_or_else(|| EdgerunnerError::config("the benchmark needs at least one repetition"))
+}
+
+fn greedy_token(logits: &Tensor) -> Result<u32> {
+    Ok(logits.squeeze(0)?.argmax(D::Minus1)?.to_scalar::<u32>()?)
+}
+
+/// Runs the prompt through a fresh cache, returns the prefill time and the first token.
+fn prefill(
+    model: &mut dyn CausalLm,
+    device: &Device,
+    threads: &ThreadPools,
+    prompt: &[u32],
+) -> Result<(f64, u32)> {
+    model.reset_cache();
+    let start = Instant::now();
+    let input = Tensor::new(prompt, device)?.unsqueeze(0)?;
+    let token = greedy_token(&threads.prefill(|| model.forward(&input, 0))?)?;
+    device.sync()?;
+    Ok((start.elapsed().as_secs_f64(), token))
+}
+
+/// Greedily generates `tokens` tokens after the prompt, returns the decode time.
+fn decode(
+    model: &mut dyn CausalLm,
+    device: &Device,
+    threads: &ThreadPools,
+    prompt: &[u32],
+    tokens: usize,
+) -> Result<f64> {
+    let (_, mut next_token) = prefill(model, device, threads, prompt)?;
+    let start = Instant::now();
+    for index in 0..tokens {
+        let input = Tensor::new(&[next_token], device)?.unsqueeze(0)?;
+        let logits = threads.decode(|| model.forward(&input, prompt.len() + index))?;
+        next_token = greedy_token(&logits)?;
+    }
+    device.sync()?;
+    Ok(start.elapsed().as_secs_f64())
+}
+
+/// Measures prefill throughput and time to first token at every prompt length, and decode
+/// throughput after the shortest prompt, on synthetic prompts over the model vocabulary.
+pub fn run_inference_benchmark(
+    model: &mut dyn CausalLm,
+    device: &Device,
+    threads: &ThreadPools,
+    config: &BenchConfig,
+) -> Result<InferenceBenchmark> {
+    let decode_prompt_len = match config.prompt_lengths.iter().min() {
+        Some(len) if *len > 0 => *len,
+        _ => {
+            return Err(EdgerunnerError::config(
+                "the benchmark needs non empty prompt lengths",
+            ))
+        }
+    };
+    if config.repetitions == 0 {
+        return Err(EdgerunnerError::config(
+            "the benchmark needs at least one repetition",
+        ));
+    }
+    let context_length = model.context_length();
+    if let Some(len) = config
+        .prompt_lengths
+        .iter()
+        .find(|len| **len > context_length)
+    {
+        return Err(EdgerunnerError::ContextOverflow {
+            tokens: *len,
+            context_length,
+        });
+    }
+    if decode_prompt_len + config.decode_tokens > context_length {
+        return Err(EdgerunnerError::ContextOverflow {
+            tokens: decode_prompt_len + config.decode_tokens,
+            context_length,
+        });
+    }
+
+    let mut prefill_results = Vec::with_capacity(config.prompt_lengths.len());
+    for &prompt_len in &config.prompt_lengths {
+        let prompt = synthetic_prompt(model, prompt_len, config.seed);
+        for _ in 0..config.warmup_repetitions {
+            prefill(model, device, threads, &prompt)?;
+        }
+        let mut throughput = Vec::with_capacity(config.repetitions);
+        let mut ttft = Vec::with_capacity(config.repetitions);
+        for _ in 0..config.repetitions {
+            let (secs, _) = prefill(model, device, threads, &prompt)?;
+            throughput.push(prompt_len as f64 / secs);
+            ttft.push(secs * 1e3);
+        }
+        log::debug!("prefill of {prompt_len} tokens: {throughput:?} token/s");
+        prefill_results.push(PrefillResult {
+            prompt_tokens: prompt_len,
+            tokens_per_second: stats(&throughput)?,
+            time_to_first_token_ms: stats(&ttft)?,
+        });
+    }
+
+    let prompt = synthetic_prompt(model, decode_prompt_len, config.seed);
+    for _ in 0..config.warmup_repetitions {
+        decode(model, device, threads, &prompt, config.decode_tokens)?;
+    }
+    let mut throughput = Vec::with_capacity(config.repetitions);
+    for _ in 0..config.repetitions {
+        let secs = decode(model, device, threads, &prompt, config.decode_tokens)?;
+        throughput.push(config.decode_tokens as f64 / secs);
+    }
+    log::debug!(
+        "decode of {} tokens: {throughput:?} token/s",
+        config.decode_tokens
+    );
+    model.reset_cache();
+
+    Ok(InferenceBenchmark {
+        repetitions: config.repetitions,
+        prefill: prefill_results,
+        decode: DecodeResult {
+            prompt_tokens: decode_prompt_len,
+            generated_tokens: config.decode_tokens,
+            tokens_per_second: stats(&throughput)?,
+        },
+        threads: threads.effective().clone(),
+        peak_memory_bytes: crate::util::peak_memory_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_from_samples() {
+        let samples = (1..=20).rev().map(f64::from).collect::<Vec<_>>();
+        let stats = Stats::from_samples(&samples).unwrap();
+        assert_eq!(stats.mean, 10.5);
+        assert_eq!(stats.p50, 10.);
+        assert_eq!(stats.p95, 19.);
+        assert_eq!(stats.min, 1.);
+        assert_eq!(stats.max, 20.);
+    }
+
+    #[test]
+    fn test_stats_single_sample() {
+        let stats = Stats::from_samples(&[3.]).unwrap();
+        assert_eq!((stats.p50, stats.p95, stats.mean), (3., 3., 3.));
+        assert!(Stats::from_samples(&[]).is_none());
+    }
+}
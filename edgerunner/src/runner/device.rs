@@ -1,27 +1,195 @@
-use anyhow::Result;
+use std::{fmt, str::FromStr};
+
 use candle_core::{
     utils::{cuda_is_available, metal_is_available},
     Device,
 };
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-pub fn device(cpu: bool) -> Result<Device> {
-    if cpu {
-        Ok(Device::Cpu)
-    } else if cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
+use crate::error::{EdgerunnerError, Result, ResultExt};
+
+/// Probing stops at the first missing ordinal, this bounds it on odd drivers.
+const MAX_DEVICE_ORDINALS: usize = 16;
+
+/// The device to run a model on, written `auto`, `cpu`, `cuda:N` or `metal:N`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DeviceSpec {
+    /// The first gpu when edgerunner is built with cuda or metal, else the cpu.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{}", ordinal),
+            Self::Metal(ordinal) => write!(f, "metal:{}", ordinal),
+        }
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = EdgerunnerError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        let (kind, ordinal) = match s.split_once(':') {
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal.parse::<usize>().map_err(|_| {
+                    EdgerunnerError::config(format!("invalid device ordinal in {}", s))
+                })?;
+                (kind, Some(ordinal))
+            }
+            None => (s.as_str(), None),
+        };
+        match (kind, ordinal) {
+            ("auto", None) => Ok(Self::Auto),
+            ("cpu", None) => Ok(Self::Cpu),
+            ("cuda", ordinal) => Ok(Self::Cuda(ordinal.unwrap_or(0))),
+            ("metal", ordinal) => Ok(Self::Metal(ordinal.unwrap_or(0))),
+            _ => Err(EdgerunnerError::config(format!(
+                "unknown device {}, expected auto, cpu, cuda:N or metal:N",
+                s
+            ))),
+        }
+    }
+}
+
+impl Serialize for DeviceSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl DeviceSpec {
+    /// Opens the device without any fallback.
+    pub fn open(&self) -> Result<Device> {
+        match self {
+            Self::Auto => Ok(auto_device()),
+            Self::Cpu => Ok(Device::Cpu),
+            Self::Cuda(ordinal) => Device::new_cuda(*ordinal).kind(EdgerunnerError::Device),
+            Self::Metal(ordinal) => Device::new_metal(*ordinal).kind(EdgerunnerError::Device),
+        }
+    }
+}
+
+fn auto_device() -> Device {
+    let gpu = if cuda_is_available() {
+        Device::new_cuda(0).ok()
     } else if metal_is_available() {
-        Ok(Device::new_metal(0)?)
+        Device::new_metal(0).ok()
     } else {
+        None
+    };
+    gpu.unwrap_or_else(|| {
         #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
         {
-            println!(
+            info!(
                 "Running on CPU, to run on GPU(metal), build this example with `--features metal`"
             );
         }
         #[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
         {
-            println!("Running on CPU, to run on GPU, build this example with `--features cuda`");
+            info!("Running on CPU, to run on GPU, build this example with `--features cuda`");
+        }
+        Device::Cpu
+    })
+}
+
+/// The devices that can be opened, gpus first and the cpu last.
+pub fn available_devices() -> Vec<DeviceSpec> {
+    let probe = |available: bool, spec: fn(usize) -> DeviceSpec| {
+        (0..MAX_DEVICE_ORDINALS)
+            .take_while(move |ordinal| available && spec(*ordinal).open().is_ok())
+            .map(spec)
+            .collect::<Vec<_>>()
+    };
+    let mut devices = probe(cuda_is_available(), DeviceSpec::Cuda);
+    devices.extend(probe(metal_is_available(), DeviceSpec::Metal));
+    devices.push(DeviceSpec::Cpu);
+    devices
+}
+
+/// Opens the requested device, falling back to the cpu with a warning when it is missing.
+pub fn resolve_device(spec: DeviceSpec) -> Device {
+    match spec.open() {
+        Ok(device) => device,
+        Err(e) => {
+            warn!(
+                "Device {} is not available, falling back to cpu: {}",
+                spec, e
+            );
+            Device::Cpu
+        }
+    }
+}
+
+pub fn device(cpu: bool) -> Result<Device> {
+    let spec = if cpu {
+        DeviceSpec::Cpu
+    } else {
+        DeviceSpec::Auto
+    };
+    Ok(resolve_device(spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_spec_round_trip() {
+        for (text, spec) in [
+            ("auto", DeviceSpec::Auto),
+            ("cpu", DeviceSpec::Cpu),
+            ("cuda:1", DeviceSpec::Cuda(1)),
+            ("metal:0", DeviceSpec::Metal(0)),
+        ] {
+            assert_eq!(text.parse::<DeviceSpec>().unwrap(), spec);
+            assert_eq!(spec.to_string(), text);
+        }
+        assert_eq!("CUDA".parse::<DeviceSpec>().unwrap(), DeviceSpec::Cuda(0));
+        assert!("tpu:0".parse::<DeviceSpec>().is_err());
+        assert!("cuda:x".parse::<DeviceSpec>().is_err());
+        assert!("cpu:1".parse::<DeviceSpec>().is_err());
+    }
+
+    #[test]
+    fn test_device_spec_serde() {
+        let spec: DeviceSpec = serde_json::from_str("\"cuda:2\"").unwrap();
+        assert_eq!(spec, DeviceSpec::Cuda(2));
+        assert_eq!(serde_json::to_string(&spec).unwrap(), "\"cuda:2\"");
+        assert!(serde_json::from_str::<DeviceSpec>("\"gpu\"").is_err());
+    }
+
+    #[test]
+    fn test_available_devices_end_with_cpu() {
+        assert_eq!(available_devices().last(), Some(&DeviceSpec::Cpu));
+    }
+
+    #[test]
+    fn test_missing_device_falls_back_to_cpu() {
+        if !cuda_is_available() {
+            assert!(DeviceSpec::Cuda(0).open().is_err());
+            assert!(resolve_device(DeviceSpec::Cuda(0)).is_cpu());
+        }
+        if !metal_is_available() {
+            assert!(resolve_device(DeviceSpec::Metal(3)).is_cpu());
         }
-        Ok(Device::Cpu)
+        assert!(resolve_device(DeviceSpec::Cpu).is_cpu());
     }
 }
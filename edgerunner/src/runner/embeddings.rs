@@ -0,0 +1,166 @@
+use std::io::Write;
+
+use candle_core::{IndexOp, Tensor, D};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EdgerunnerError, Result};
+
+/// How the hidden states of the tokens of a text are reduced to one vector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pooling {
+    /// The mean over all tokens.
+    #[default]
+    Mean,
+    /// The hidden state of the last token, which attended to the whole text.
+    LastToken,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddingOptions {
+    pub pooling: Pooling,
+    /// Scale the vectors to unit length, so that the dot product is the cosine similarity.
+    pub normalize: bool,
+    /// The number of texts run through the model at once.
+    pub batch_size: usize,
+}
+
+impl Default for EmbeddingOptions {
+    fn default() -> Self {
+        Self {
+            pooling: Pooling::Mean,
+            normalize: true,
+            batch_size: 8,
+        }
+    }
+}
+
+/// Pools the hidden states of shape `(batch, seq_len, hidden_size)` of texts right padded from
+/// their `lengths`. The attention is causal so the padding never reaches the text tokens.
+pub fn pool(
+    hidden: &Tensor,
+    lengths: &[usize],
+    options: &EmbeddingOptions,
+) -> Result<Vec<Vec<f32>>> {
+    lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| {
+            let states = hidden.i((i, ..length, ..))?;
+            let pooled = match options.pooling {
+                Pooling::Mean => states.mean(0)?,
+                Pooling::LastToken => states.i(length - 1)?,
+            };
+            let pooled = if options.normalize {
+                let norm = pooled.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+                pooled.broadcast_div(&norm)?
+            } else {
+                pooled
+            };
+            Ok(pooled.to_vec1::<f32>()?)
+        })
+        .collect()
+}
+
+/// The file formats of the `embed` command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum EmbeddingFormat {
+    /// One `{"index", "embedding"}` object per line.
+    #[default]
+    Jsonl,
+    /// A numpy `(texts, hidden_size)` float32 array.
+    Npy,
+}
+
+pub fn write_jsonl(embeddings: &[Vec<f32>], writer: &mut impl Write) -> Result<()> {
+    for (index, embedding) in embeddings.iter().enumerate() {
+        let line = serde_json::json!({ "index": index, "embedding": embedding });
+        writeln!(writer, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Writes the embeddings as a version 1.0 `.npy` file.
+pub fn write_npy(embeddings: &[Vec<f32>], writer: &mut impl Write) -> Result<()> {
+    let dim = embeddings.first().map_or(0, Vec::len);
+    if embeddings.iter().any(|e| e.len() != dim) {
+        return Err(EdgerunnerError::inference(
+            "the embeddings do not have the same length",
+        ));
+    }
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        embeddings.len(),
+        dim
+    );
+    // the magic, version and header length take 10 bytes, the header ends the 64 byte aligned
+    // preamble with a newline
+    let padding = 63 - (10 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in embeddings.iter().flatten() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn hidden() -> Tensor {
+        // two texts of 2 and 1 tokens, the second one padded
+        Tensor::new(
+            &[[[1f32, 0.], [3., 4.]], [[0., 2.], [9., 9.]]],
+            &Device::Cpu,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pool_ignores_padding() {
+        let options = EmbeddingOptions {
+            normalize: false,
+            ..EmbeddingOptions::default()
+        };
+        let mean = pool(&hidden(), &[2, 1], &options).unwrap();
+        assert_eq!(mean, vec![vec![2., 2.], vec![0., 2.]]);
+
+        let options = EmbeddingOptions {
+            pooling: Pooling::LastToken,
+            ..options
+        };
+        let last = pool(&hidden(), &[2, 1], &options).unwrap();
+        assert_eq!(last, vec![vec![3., 4.], vec![0., 2.]]);
+
+        let options = EmbeddingOptions {
+            normalize: true,
+            ..options
+        };
+        let normalized = pool(&hidden(), &[2, 1], &options).unwrap();
+        assert_eq!(normalized, vec![vec![0.6, 0.8], vec![0., 1.]]);
+    }
+
+    #[test]
+    fn test_write_npy_header() {
+        let mut npy = Vec::new();
+        write_npy(&[vec![1., 2., 3.], vec![4., 5., 6.]], &mut npy).unwrap();
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!(&npy[..6], b"\x93NUMPY");
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3)"), "{header}");
+        assert!(header.ends_with('\n'));
+        assert_eq!(npy.len(), 10 + header_len + 6 * 4);
+        assert_eq!(
+            &npy[10 + header_len..10 + header_len + 4],
+            &1f32.to_le_bytes()
+        );
+    }
+}
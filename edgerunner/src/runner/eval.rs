@@ -0,0 +1,159 @@
+use candle_core::{Tensor, D};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EdgerunnerError, Result};
+
+use super::text_generation::TextGeneration;
+
+/// The sliding window the perplexity is computed over.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvalConfig {
+    /// The tokens the model sees at once, defaults to the context length.
+    pub window: Option<usize>,
+    /// The tokens the window moves by, defaults to half the window. Only the tokens the previous
+    /// window did not reach are scored, with the overlap as their context.
+    pub stride: Option<usize>,
+}
+
+/// The negative log-likelihood of a token given the tokens before it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct TokenLoss {
+    pub id: u32,
+    pub nll: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PerplexityReport {
+    /// The scored tokens, every token but the first one.
+    pub tokens: usize,
+    pub windows: usize,
+    pub window: usize,
+    pub stride: usize,
+    pub mean_nll: f64,
+    pub perplexity: f64,
+    pub token_losses: Vec<TokenLoss>,
+}
+
+/// How likely the model finds a continuation of a prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ContinuationScore {
+    /// The sum of the log probabilities of the continuation tokens.
+    pub log_likelihood: f64,
+    pub tokens: usize,
+    /// Whether greedy decoding produces the continuation.
+    pub is_greedy: bool,
+}
+
+impl ContinuationScore {
+    /// The log-likelihood per token, which does not favour short continuations.
+    pub fn mean_log_likelihood(&self) -> f64 {
+        self.log_likelihood / self.tokens.max(1) as f64
+    }
+}
+
+/// The log probabilities of `targets` from the rows of `log_probs` predicting them.
+fn target_log_probs(log_probs: &Tensor, targets: &[u32]) -> Result<Vec<f32>> {
+    let targets = Tensor::new(targets, log_probs.device())?.unsqueeze(1)?;
+    Ok(log_probs
+        .gather(&targets, 1)?
+        .squeeze(1)?
+        .to_vec1::<f32>()?)
+}
+
+/// Scores every token of `tokens` but the first with a sliding window.
+pub fn perplexity(
+    pipeline: &mut TextGeneration,
+    tokens: &[u32],
+    config: &EvalConfig,
+) -> Result<PerplexityReport> {
+    if tokens.len() < 2 {
+        return Err(EdgerunnerError::config(
+            "the perplexity needs at least two tokens",
+        ));
+    }
+    let context_length = pipeline.context_length();
+    let window = config.window.unwrap_or(context_length);
+    if window > context_length {
+        return Err(EdgerunnerError::ContextOverflow {
+            tokens: window,
+            context_length,
+        });
+    }
+    let stride = config.stride.unwrap_or(window / 2);
+    if window < 2 || stride == 0 || stride >= window {
+        return Err(EdgerunnerError::config(format!(
+            "a window of {} tokens cannot move by {} tokens",
+            window, stride
+        )));
+    }
+
+    let mut token_losses = Vec::with_capacity(tokens.len() - 1);
+    let mut windows = 0;
+    let mut begin = 0;
+    // the first token not scored yet, the first token of the text has no context
+    let mut scored = 1;
+    loop {
+        let end = (begin + window).min(tokens.len());
+        let logits = pipeline.logits(&tokens[begin..end])?;
+        // row i predicts token begin + i + 1
+        let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.narrow(
+            0,
+            scored - 1 - begin,
+            end - scored,
+        )?;
+        let targets = &tokens[scored..end];
+        let log_probs = target_log_probs(&log_probs, targets)?;
+        token_losses.extend(
+            targets
+                .iter()
+                .zip(log_probs)
+                .map(|(&id, log_prob)| TokenLoss { id, nll: -log_prob }),
+        );
+        windows += 1;
+        scored = end;
+        if end == tokens.len() {
+            break;
+        }
+        begin += stride;
+    }
+
+    let mean_nll =
+        token_losses.iter().map(|t| t.nll as f64).sum::<f64>() / token_losses.len() as f64;
+    Ok(PerplexityReport {
+        tokens: token_losses.len(),
+        windows,
+        window,
+        stride,
+        mean_nll,
+        perplexity: mean_nll.exp(),
+        token_losses,
+    })
+}
+
+/// Scores `continuation` after `prompt`, which has to hold at least one token such as bos.
+pub fn score(
+    pipeline: &mut TextGeneration,
+    prompt: &[u32],
+    continuation: &[u32],
+) -> Result<ContinuationScore> {
+    if prompt.is_empty() || continuation.is_empty() {
+        return Err(EdgerunnerError::config(
+            "scoring needs a prompt and a continuation",
+        ));
+    }
+    let tokens = [prompt, continuation].concat();
+    let logits = pipeline
+        .logits(&tokens)?
+        .narrow(0, prompt.len() - 1, continuation.len())?;
+    let is_greedy = logits.argmax(D::Minus1)?.to_vec1::<u32>()? == continuation;
+    let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+    let log_likelihood = target_log_probs(&log_probs, continuation)?
+        .iter()
+        .map(|&p| p as f64)
+        .sum();
+    Ok(ContinuationScore {
+        log_likelihood,
+        tokens: continuation.len(),
+        is_greedy,
+    })
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::model::types::ModelArchitecture;
+
+/// Why the generation ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced an end of sequence token.
+    Eos,
+    /// The sample length was reached.
+    Length,
+    /// The reply calls tools.
+    ToolCalls,
+}
+
+/// What the engine is doing, reported to an [`EngineObserver`] as it happens.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EngineEvent {
+    /// The model file or checkpoint directory starts loading.
+    ModelLoading { path: String },
+    /// The weights were read from disk.
+    WeightsLoaded { size_bytes: usize, seconds: f64 },
+    /// The model is ready to run.
+    ModelLoaded {
+        architecture: ModelArchitecture,
+        /// The tokenizer came with the weights, from the gguf vocabulary or the checkpoint.
+        embedded_tokenizer: bool,
+        seconds: f64,
+    },
+    /// The prompt went through the model, `tokens` is the length after truncation to the context.
+    PromptProcessed { tokens: usize, seconds: f64 },
+    /// A token was sampled, `text` is the text it completes, if any.
+    TokenGenerated {
+        index: usize,
+        id: u32,
+        text: Option<String>,
+    },
+    /// The stop flag was raised.
+    Stopped,
+    Finished {
+        prompt_tokens: usize,
+        generated_tokens: usize,
+        prompt_seconds: f64,
+        generation_seconds: f64,
+        reason: FinishReason,
+    },
+}
+
+/// Receives the [`EngineEvent`]s, closures taking an event are observers.
+pub trait EngineObserver: Send + Sync {
+    fn on_event(&self, event: &EngineEvent);
+}
+
+impl<F: Fn(&EngineEvent) + Send + Sync> EngineObserver for F {
+    fn on_event(&self, event: &EngineEvent) {
+        self(event)
+    }
+}
+
+/// Ignores every event.
+pub struct NoopObserver;
+
+impl EngineObserver for NoopObserver {
+    fn on_event(&self, _event: &EngineEvent) {}
+}
+
+pub type SharedObserver = Arc<dyn EngineObserver>;
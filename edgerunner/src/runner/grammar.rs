@@ -0,0 +1,417 @@
+/// The containers a JSON value is nested in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Container {
+    Object,
+    Array,
+}
+
+/// The parts of a JSON number, following the grammar of RFC 8259.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Number {
+    Minus,
+    Zero,
+    Integer,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl Number {
+    fn push(self, c: char) -> Option<Self> {
+        use Number::*;
+        match (self, c) {
+            (Minus, '0') => Some(Zero),
+            (Minus, '1'..='9') => Some(Integer),
+            (Integer, '0'..='9') => Some(Integer),
+            (Zero | Integer, '.') => Some(Dot),
+            (Dot | Fraction, '0'..='9') => Some(Fraction),
+            (Zero | Integer | Fraction, 'e' | 'E') => Some(Exponent),
+            (Exponent, '+' | '-') => Some(ExponentSign),
+            (Exponent | ExponentSign | ExponentDigits, '0'..='9') => Some(ExponentDigits),
+            _ => None,
+        }
+    }
+
+    fn is_complete(self) -> bool {
+        matches!(
+            self,
+            Number::Zero | Number::Integer | Number::Fraction | Number::ExponentDigits
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum State {
+    /// Before a value, or the first value of an array which may also close.
+    Value {
+        or_close: bool,
+    },
+    /// Before a key, or the first key of an object which may also close.
+    Key {
+        or_close: bool,
+    },
+    Colon,
+    /// In a string, `escape` counts the characters left of an escape sequence, with 5 right after
+    /// the backslash.
+    String {
+        key: bool,
+        escape: u8,
+    },
+    Number(Number),
+    /// The rest of `true`, `false` or `null`.
+    Literal(&'static str),
+    /// After a value, before a comma or the end of its container.
+    AfterValue,
+    Done,
+}
+
+/// Checks JSON text one character at a time, telling whether it can still become a valid value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonPrefix {
+    stack: Vec<Container>,
+    state: State,
+}
+
+impl Default for JsonPrefix {
+    fn default() -> Self {
+        Self {
+            stack: Vec::new(),
+            state: State::Value { or_close: false },
+        }
+    }
+}
+
+impl JsonPrefix {
+    /// Whether the text so far is a whole JSON value.
+    pub fn is_complete(&self) -> bool {
+        match self.state {
+            State::Done => true,
+            State::Number(number) => self.stack.is_empty() && number.is_complete(),
+            _ => false,
+        }
+    }
+
+    /// Whether nothing but whitespace went in yet.
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty() && self.state == State::Value { or_close: false }
+    }
+
+    /// Adds `text`, returning false when it cannot continue a JSON value, which leaves the
+    /// checker in an unspecified state.
+    pub fn push_str(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.push(c))
+    }
+
+    pub fn push(&mut self, c: char) -> bool {
+        match self.state.clone() {
+            State::String { key, escape } => self.push_string(key, escape, c),
+            State::Number(number) => match number.push(c) {
+                Some(number) => {
+                    self.state = State::Number(number);
+                    true
+                }
+                None if number.is_complete() => {
+                    self.end_value();
+                    self.push(c)
+                }
+                None => false,
+            },
+            State::Literal(rest) => match rest.strip_prefix(c) {
+                Some("") => {
+                    self.end_value();
+                    true
+                }
+                Some(rest) => {
+                    self.state = State::Literal(rest);
+                    true
+                }
+                None => false,
+            },
+            _ if c.is_ascii_whitespace() => true,
+            State::Value { or_close } => self.push_value(or_close, c),
+            State::Key { or_close } => match c {
+                '"' => {
+                    self.state = State::String {
+                        key: true,
+                        escape: 0,
+                    };
+                    true
+                }
+                '}' if or_close => self.close(Container::Object),
+                _ => false,
+            },
+            State::Colon => {
+                self.state = State::Value { or_close: false };
+                c == ':'
+            }
+            State::AfterValue => match (c, self.stack.last()) {
+                (',', Some(Container::Object)) => {
+                    self.state = State::Key { or_close: false };
+                    true
+                }
+                (',', Some(Container::Array)) => {
+                    self.state = State::Value { or_close: false };
+                    true
+                }
+                ('}', Some(Container::Object)) => self.close(Container::Object),
+                (']', Some(Container::Array)) => self.close(Container::Array),
+                _ => false,
+            },
+            State::Done => false,
+        }
+    }
+
+    fn push_value(&mut self, or_close: bool, c: char) -> bool {
+        self.state = match c {
+            '{' => {
+                self.stack.push(Container::Object);
+                State::Key { or_close: true }
+            }
+            '[' => {
+                self.stack.push(Container::Array);
+                State::Value { or_close: true }
+            }
+            ']' if or_close => return self.close(Container::Array),
+            '"' => State::String {
+                key: false,
+                escape: 0,
+            },
+            '-' => State::Number(Number::Minus),
+            '0' => State::Number(Number::Zero),
+            '1'..='9' => State::Number(Number::Integer),
+            't' => State::Literal("rue"),
+            'f' => State::Literal("alse"),
+            'n' => State::Literal("ull"),
+            _ => return false,
+        };
+        true
+    }
+
+    fn push_string(&mut self, key: bool, escape: u8, c: char) -> bool {
+        let escape = match (escape, c) {
+            (0, '"') => {
+                if key {
+                    self.state = State::Colon;
+                } else {
+                    self.end_value();
+                }
+                return true;
+            }
+            (0, '\\') => 5,
+            (0, c) if c.is_control() => return false,
+            (0, _) => 0,
+            (5, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => 0,
+            (5, 'u') => 4,
+            (1..=4, c) if c.is_ascii_hexdigit() => escape - 1,
+            _ => return false,
+        };
+        self.state = State::String { key, escape };
+        true
+    }
+
+    fn close(&mut self, container: Container) -> bool {
+        if self.stack.pop() != Some(container) {
+            return false;
+        }
+        self.end_value();
+        true
+    }
+
+    fn end_value(&mut self) {
+        self.state = if self.stack.is_empty() {
+            State::Done
+        } else {
+            State::AfterValue
+        };
+    }
+}
+
+const CALL_OPEN: &str = r#"{"name": ""#;
+const CALL_ARGUMENTS: &str = r#"", "arguments": "#;
+const CALL_CLOSE: &str = "}";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Stage {
+    /// The characters of [`CALL_OPEN`] matched so far.
+    Open(usize),
+    Name(String),
+    /// The characters of [`CALL_ARGUMENTS`] matched so far.
+    Separator(usize),
+    Arguments(JsonPrefix),
+    Done,
+}
+
+/// Constrains a reply to a single tool call, `{"name": "<tool>", "arguments": {...}}`, naming
+/// one of the given tools with a JSON object of arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ToolCallGrammar {
+    names: Vec<String>,
+    stage: Stage,
+}
+
+impl ToolCallGrammar {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            stage: Stage::Open(0),
+        }
+    }
+
+    /// Whether the text so far is a whole tool call.
+    pub fn is_complete(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// Whether `text` can follow the text so far.
+    pub fn allows(&self, text: &str) -> bool {
+        !text.is_empty() && self.clone().push_str(text)
+    }
+
+    /// Adds `text`, returning false when it breaks the grammar, which leaves it in an unspecified
+    /// state.
+    pub fn push_str(&mut self, text: &str) -> bool {
+        text.chars().all(|c| self.push(c))
+    }
+
+    pub fn push(&mut self, c: char) -> bool {
+        match &mut self.stage {
+            Stage::Open(0) if c.is_ascii_whitespace() => true,
+            Stage::Open(matched) => {
+                if !CALL_OPEN[*matched..].starts_with(c) {
+                    return false;
+                }
+                *matched += c.len_utf8();
+                if *matched == CALL_OPEN.len() {
+                    self.stage = Stage::Name(String::new());
+                }
+                true
+            }
+            Stage::Name(name) => {
+                if c == '"' {
+                    let known = self.names.contains(name);
+                    self.stage = Stage::Separator(1);
+                    return known;
+                }
+                name.push(c);
+                self.names
+                    .iter()
+                    .any(|known| known.starts_with(name.as_str()))
+            }
+            Stage::Separator(matched) => {
+                if !CALL_ARGUMENTS[*matched..].starts_with(c) {
+                    return false;
+                }
+                *matched += c.len_utf8();
+                if *matched == CALL_ARGUMENTS.len() {
+                    self.stage = Stage::Arguments(JsonPrefix::default());
+                }
+                true
+            }
+            Stage::Arguments(arguments) if arguments.is_complete() => {
+                if c.is_ascii_whitespace() {
+                    return true;
+                }
+                self.stage = Stage::Done;
+                CALL_CLOSE.starts_with(c)
+            }
+            Stage::Arguments(arguments) => {
+                // the arguments are an object
+                if arguments.is_empty() && !c.is_ascii_whitespace() && c != '{' {
+                    return false;
+                }
+                arguments.push(c)
+            }
+            Stage::Done => c.is_ascii_whitespace(),
+        }
+    }
+}
+
+/// The text of every token of a vocabulary, as the grammar sees it. Sentencepiece and byte
+/// level markers become spaces and newlines, byte tokens outside ASCII and special tokens are
+/// left empty so that no grammar allows them.
+pub fn token_pieces(tokenizer: &tokenizers::Tokenizer) -> Vec<String> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let special = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, _)| id)
+        .collect::<std::collections::HashSet<_>>();
+    (0..vocab_size as u32)
+        .map(|id| match tokenizer.id_to_token(id) {
+            Some(_) if special.contains(&id) => String::new(),
+            Some(token) => match token
+                .strip_prefix("<0x")
+                .and_then(|hex| hex.strip_suffix('>'))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) if byte.is_ascii() => (byte as char).to_string(),
+                Some(_) => String::new(),
+                None => token.replace(['▁', 'Ġ'], " ").replace('Ċ', "\n"),
+            },
+            None => String::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_prefix(text: &str) -> bool {
+        JsonPrefix::default().push_str(text)
+    }
+
+    fn is_json(text: &str) -> bool {
+        let mut json = JsonPrefix::default();
+        json.push_str(text) && json.is_complete()
+    }
+
+    #[test]
+    fn test_json_prefix_follows_the_json_grammar() {
+        for text in [
+            r#"{"a": [1, -2.5e+3, "x\"é"], "b": {"c": null}, "d": true}"#,
+            "[]",
+            "{ }",
+            "0",
+            r#""""#,
+        ] {
+            assert!(is_json(text), "{text}");
+        }
+        for text in [r#"{"a": [1, "#, r#"{"a"#, "-", "[tr", r#""\u00"#] {
+            assert!(is_prefix(text) && !is_json(text), "{text}");
+        }
+        for text in [
+            r#"{"a" 1"#,
+            "[1,]",
+            "{,",
+            "01",
+            r#"{"a": 1}}"#,
+            "tru e",
+            "[1}",
+        ] {
+            assert!(!is_prefix(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_tool_call_grammar_names_a_known_tool() {
+        let grammar = ToolCallGrammar::new(vec!["get_weather".to_string(), "get_time".to_string()]);
+        assert!(grammar.allows(r#" {"name": "get_"#));
+        assert!(!grammar.allows(r#"{"name": "set"#));
+        assert!(!grammar.allows(r#"{"name": "get""#));
+        assert!(!grammar.allows(r#"{"name": "get_time", "arguments": []"#));
+        assert!(!grammar.allows("Sure"));
+        assert!(!grammar.allows(""));
+
+        let mut call = grammar.clone();
+        assert!(call.push_str(r#"{"name": "get_weather", "arguments": {"city": "Paris"}"#));
+        assert!(!call.is_complete());
+        assert!(call.push_str("}"));
+        assert!(call.is_complete());
+        assert!(call.allows("\n"));
+        assert!(!call.allows(","));
+    }
+}
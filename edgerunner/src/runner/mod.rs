@@ -1,4 +1,15 @@
+pub mod beam_search;
+pub mod benchmark;
 pub mod device;
+pub mod embeddings;
+pub mod eval;
+pub mod events;
+pub mod grammar;
+pub mod output;
+pub mod prefix_cache;
+pub mod sampler;
+pub mod session;
+pub mod speculative;
 pub mod text_generation;
+pub mod threads;
 pub mod token_output_stream;
-
@@ -0,0 +1,137 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use super::{
+    beam_search::Beam, events::FinishReason, prefix_cache::PrefixCacheStats,
+    speculative::SpeculativeStats, text_generation::Generation,
+};
+
+/// How the `generate` command prints the generation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The text as it is generated, then the stats.
+    #[default]
+    Text,
+    /// A single summary object with the text once the generation is done.
+    Json,
+    /// A `token` event per line as the text is generated, then a `summary` event. A beam search
+    /// only has the summary.
+    JsonlStream,
+}
+
+/// The text, stats and finish reason of a generation, as the `json` output prints them.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GenerationSummary {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub prompt_tokens: usize,
+    /// The sampled tokens, including the end of sequence token.
+    pub generated_tokens: usize,
+    pub cached_tokens: usize,
+    pub prompt_tokens_per_second: f64,
+    /// The decode speed, which leaves out the first token sampled from the prompt.
+    pub tokens_per_second: f64,
+    pub prompt_seconds: f64,
+    pub generation_seconds: f64,
+    pub logprob: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative: Option<SpeculativeStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_cache: Option<PrefixCacheStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub beams: Vec<Beam>,
+}
+
+impl GenerationSummary {
+    pub fn new(generation: &Generation, prefix_cache: Option<PrefixCacheStats>) -> Self {
+        let sampled = generation.generated_tokens.saturating_sub(1);
+        Self {
+            text: generation.text.clone(),
+            finish_reason: generation.finish_reason,
+            prompt_tokens: generation.prompt_tokens,
+            generated_tokens: generation.generated_tokens,
+            cached_tokens: generation.cached_tokens,
+            prompt_tokens_per_second: per_second(
+                generation.prompt_tokens,
+                generation.prompt_seconds,
+            ),
+            tokens_per_second: per_second(sampled, generation.generation_seconds),
+            prompt_seconds: generation.prompt_seconds,
+            generation_seconds: generation.generation_seconds,
+            logprob: generation.logprob,
+            speculative: generation.speculative,
+            prefix_cache,
+            beams: generation.beams.clone(),
+        }
+    }
+}
+
+/// Tokens per second, 0 when nothing was timed so that the JSON stays a number.
+fn per_second(tokens: usize, seconds: f64) -> f64 {
+    if seconds > 0. {
+        tokens as f64 / seconds
+    } else {
+        0.
+    }
+}
+
+/// A line of the `jsonl-stream` output.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum OutputEvent {
+    /// The text completed by the token `id`, the `index`th generated token.
+    Token {
+        delta: String,
+        index: usize,
+        id: u32,
+    },
+    /// Text that no generated token completed: the text held back until the end of a
+    /// generation, or the best beam of a beam search.
+    Text {
+        delta: String,
+    },
+    Summary(GenerationSummary),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_events_are_tagged() {
+        let token = OutputEvent::Token {
+            delta: " world".to_string(),
+            index: 1,
+            id: 42,
+        };
+        assert_eq!(
+            serde_json::to_string(&token).unwrap(),
+            r#"{"event":"token","delta":" world","index":1,"id":42}"#
+        );
+
+        let generation = Generation {
+            text: "hello world".to_string(),
+            prompt_tokens: 4,
+            generated_tokens: 3,
+            cached_tokens: 0,
+            logprob: -1.5,
+            speculative: None,
+            beams: Vec::new(),
+            prompt_seconds: 0.5,
+            generation_seconds: 0.,
+            finish_reason: FinishReason::Eos,
+        };
+        let summary = serde_json::to_value(OutputEvent::Summary(GenerationSummary::new(
+            &generation,
+            None,
+        )))
+        .unwrap();
+        assert_eq!(summary["event"], "summary");
+        assert_eq!(summary["text"], "hello world");
+        assert_eq!(summary["finish_reason"], "eos");
+        assert_eq!(summary["prompt_tokens_per_second"], 8.);
+        assert_eq!(summary["tokens_per_second"], 0.);
+        assert!(summary.get("speculative").is_none());
+        assert!(summary.get("beams").is_none());
+    }
+}
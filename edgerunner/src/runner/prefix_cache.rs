@@ -0,0 +1,409 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use candle_core::Device;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{EdgerunnerError, Result, ResultExt},
+    model::causal_lm::KvCache,
+    util::{fnv1a, FNV_OFFSET_BASIS},
+};
+
+const MANIFEST_FILE: &str = "prefixes.json";
+const MANIFEST_VERSION: u32 = 1;
+
+/// How much of the kv cache of earlier prompts is kept to skip the prefill of shared prefixes,
+/// such as system prompts. The kv cache of a 7b model takes about 256KB per token in f32.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrefixCacheConfig {
+    /// The cached tokens kept in memory, the least recently used prompts are dropped first.
+    pub memory_tokens: usize,
+    /// A directory persisting the cache across runs.
+    pub dir: Option<String>,
+    /// The cached tokens kept in `dir`.
+    pub disk_tokens: usize,
+}
+
+impl Default for PrefixCacheConfig {
+    fn default() -> Self {
+        Self {
+            memory_tokens: 4096,
+            dir: None,
+            disk_tokens: 32768,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PrefixCacheStats {
+    /// The prompts that started from the cache of an earlier prompt.
+    pub hits: usize,
+    pub misses: usize,
+    /// The hits read from disk.
+    pub disk_hits: usize,
+    /// The prompt tokens that did not go through the model again.
+    pub reused_tokens: usize,
+}
+
+struct Entry {
+    tokens: Vec<u32>,
+    cache: KvCache,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DiskEntry {
+    model: String,
+    tokens: Vec<u32>,
+    file: String,
+}
+
+/// The prompts stored in the cache directory, most recently used first.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    entries: Vec<DiskEntry>,
+}
+
+/// The kv cache of recent prompts, keyed by model and tokens. A prompt reuses the cache of the
+/// earlier prompt it shares the longest prefix with, since the cache of a token only depends on
+/// the tokens before it.
+pub struct PrefixCache {
+    model: String,
+    config: PrefixCacheConfig,
+    /// Most recently used first.
+    entries: VecDeque<Entry>,
+    disk: Vec<DiskEntry>,
+    stats: PrefixCacheStats,
+}
+
+fn common_prefix(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn file_name(model: &str, tokens: &[u32]) -> String {
+    let bytes = model
+        .bytes()
+        .chain(tokens.iter().flat_map(|t| t.to_le_bytes()));
+    format!("{:016x}.safetensors", fnv1a(FNV_OFFSET_BASIS, bytes))
+}
+
+impl PrefixCache {
+    /// A cache for `model`, which has to identify the weights, not only name them, so that
+    /// models never share a directory entry.
+    pub fn new(model: impl Into<String>, config: PrefixCacheConfig) -> Result<Self> {
+        let mut cache = Self {
+            model: model.into(),
+            config,
+            entries: VecDeque::new(),
+            disk: Vec::new(),
+            stats: PrefixCacheStats::default(),
+        };
+        if let Some(dir) = cache.dir() {
+            fs::create_dir_all(&dir)?;
+            let manifest = dir.join(MANIFEST_FILE);
+            if manifest.exists() {
+                let json = fs::read_to_string(&manifest)?;
+                match serde_json::from_str::<Manifest>(&json) {
+                    Ok(manifest) if manifest.version == MANIFEST_VERSION => {
+                        cache.disk = manifest.entries
+                    }
+                    _ => warn!("Ignoring the prefix cache in {}", dir.display()),
+                }
+            }
+        }
+        Ok(cache)
+    }
+
+    pub fn stats(&self) -> PrefixCacheStats {
+        self.stats
+    }
+
+    fn dir(&self) -> Option<PathBuf> {
+        self.config.dir.as_ref().map(PathBuf::from)
+    }
+
+    /// The cache of the longest known prefix of `tokens`, leaving at least the last token to
+    /// run, or `None` when no earlier prompt starts like this one.
+    pub fn lookup(&mut self, tokens: &[u32], device: &Device) -> Result<Option<KvCache>> {
+        let limit = tokens.len().saturating_sub(1);
+        let (memory_len, memory_idx) = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (common_prefix(&entry.tokens, tokens).min(limit), i))
+            .max_by_key(|(len, _)| *len)
+            .unwrap_or_default();
+        let (disk_len, disk_idx) = self
+            .disk
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.model == self.model)
+            .map(|(i, entry)| (common_prefix(&entry.tokens, tokens).min(limit), i))
+            .max_by_key(|(len, _)| *len)
+            .unwrap_or_default();
+
+        let len = memory_len.max(disk_len);
+        if len == 0 {
+            self.stats.misses += 1;
+            return Ok(None);
+        }
+        let cache = if memory_len >= disk_len {
+            let entry = self
+                .entries
+                .remove(memory_idx)
+                .expect("the entry was just found");
+            let cache = entry.cache.truncate(len)?;
+            self.entries.push_front(entry);
+            cache
+        } else {
+            let entry = self.disk.remove(disk_idx);
+            let cache = self.read(&entry, device)?;
+            self.disk.insert(0, entry.clone());
+            self.write_manifest()?;
+            self.stats.disk_hits += 1;
+            self.keep(entry.tokens, cache.clone());
+            cache.truncate(len)?
+        };
+        self.stats.hits += 1;
+        self.stats.reused_tokens += len;
+        debug!("Reusing the kv cache of {} prompt tokens", len);
+        Ok(Some(cache))
+    }
+
+    /// Keeps the cache of `tokens`, taken right after they went through the model.
+    pub fn insert(&mut self, tokens: &[u32], cache: KvCache) -> Result<()> {
+        if cache.len() < tokens.len() {
+            return Err(EdgerunnerError::inference(format!(
+                "a kv cache of {} tokens does not cover a prompt of {} tokens",
+                cache.len(),
+                tokens.len()
+            )));
+        }
+        let cache = cache.truncate(tokens.len())?;
+        if let Some(dir) = self.dir() {
+            let covered = self
+                .disk
+                .iter()
+                .any(|entry| entry.model == self.model && entry.tokens.starts_with(tokens));
+            if !covered {
+                self.write(&dir, tokens, &cache)?;
+            }
+        }
+        if let Some(i) = self
+            .entries
+            .iter()
+            .position(|entry| entry.tokens.starts_with(tokens))
+        {
+            let entry = self.entries.remove(i).expect("the entry was just found");
+            self.entries.push_front(entry);
+        } else {
+            self.keep(tokens.to_vec(), cache);
+        }
+        Ok(())
+    }
+
+    /// Adds a memory entry, replacing the ones it extends and dropping the least recently used
+    /// ones beyond the memory budget. Prompts larger than the whole budget are not kept.
+    fn keep(&mut self, tokens: Vec<u32>, cache: KvCache) {
+        if tokens.len() > self.config.memory_tokens {
+            debug!(
+                "Not keeping the kv cache of {} tokens, beyond the memory budget",
+                tokens.len()
+            );
+            return;
+        }
+        self.entries
+            .retain(|entry| !tokens.starts_with(&entry.tokens));
+        self.entries.push_front(Entry { tokens, cache });
+        let mut total = 0;
+        self.entries.retain(|entry| {
+            total += entry.tokens.len();
+            total <= self.config.memory_tokens
+        });
+    }
+
+    /// Reads the cache of an entry of this model, which has to cover the tokens of the entry.
+    fn read(&self, entry: &DiskEntry, device: &Device) -> Result<KvCache> {
+        if entry.model != self.model {
+            return Err(EdgerunnerError::Cache(anyhow::Error::msg(format!(
+                "{} holds the prefix cache of {}, not {}",
+                entry.file, entry.model, self.model
+            ))));
+        }
+        let dir = self.dir().unwrap_or_default();
+        let mut tensors = candle_core::safetensors::load(dir.join(&entry.file), device)
+            .kind(EdgerunnerError::Io)?;
+        let cache = KvCache::from_tensors(&mut tensors);
+        if cache.len() != entry.tokens.len() {
+            return Err(EdgerunnerError::Cache(anyhow::Error::msg(format!(
+                "{} holds the kv cache of {} tokens for {} tokens",
+                entry.file,
+                cache.len(),
+                entry.tokens.len()
+            ))));
+        }
+        Ok(cache)
+    }
+
+    /// Writes an entry, replacing the ones of this model it extends and dropping the least
+    /// recently used ones beyond the disk budget. Prompts larger than the whole budget are not
+    /// written.
+    fn write(&mut self, dir: &Path, tokens: &[u32], cache: &KvCache) -> Result<()> {
+        if tokens.len() > self.config.disk_tokens {
+            debug!(
+                "Not writing the kv cache of {} tokens, beyond the disk budget",
+                tokens.len()
+            );
+            return Ok(());
+        }
+        let file = file_name(&self.model, tokens);
+        let tensors = cache.to_tensors();
+        candle_core::safetensors::save(&tensors, dir.join(&file)).kind(EdgerunnerError::Io)?;
+
+        // the new entry replaces the ones it extends
+        let (replaced, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.disk)
+            .into_iter()
+            .partition(|entry| entry.model == self.model && tokens.starts_with(&entry.tokens));
+        self.disk = kept;
+        self.disk.insert(
+            0,
+            DiskEntry {
+                model: self.model.clone(),
+                tokens: tokens.to_vec(),
+                file,
+            },
+        );
+        let mut total = 0;
+        let (kept, dropped): (Vec<_>, Vec<_>) = std::mem::take(&mut self.disk)
+            .into_iter()
+            .partition(|entry| {
+                total += entry.tokens.len();
+                total <= self.config.disk_tokens
+            });
+        self.disk = kept;
+        for entry in replaced.iter().chain(&dropped) {
+            if self.disk.iter().all(|kept| kept.file != entry.file) {
+                let _ = fs::remove_file(dir.join(&entry.file));
+            }
+        }
+        self.write_manifest()
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let Some(dir) = self.dir() else {
+            return Ok(());
+        };
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            entries: self.disk.clone(),
+        };
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            serde_json::to_string(&manifest).kind(EdgerunnerError::Io)?,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Tensor;
+
+    fn cache(len: usize) -> KvCache {
+        let k = Tensor::arange(0f32, len as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap();
+        KvCache {
+            layers: vec![(k.clone(), k)],
+        }
+    }
+
+    #[test]
+    fn test_lookup_reuses_the_longest_common_prefix() {
+        let mut prefixes = PrefixCache::new("model", PrefixCacheConfig::default()).unwrap();
+        assert!(prefixes.lookup(&[1, 2, 3], &Device::Cpu).unwrap().is_none());
+        prefixes.insert(&[1, 2, 3], cache(3)).unwrap();
+        prefixes.insert(&[1, 5], cache(2)).unwrap();
+
+        let found = prefixes
+            .lookup(&[1, 2, 4, 6], &Device::Cpu)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        // the last token is always left to run
+        let found = prefixes.lookup(&[1, 2, 3], &Device::Cpu).unwrap().unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(prefixes.lookup(&[7, 1], &Device::Cpu).unwrap().is_none());
+        assert_eq!(
+            prefixes.stats(),
+            PrefixCacheStats {
+                hits: 2,
+                misses: 2,
+                disk_hits: 0,
+                reused_tokens: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_memory_keeps_the_most_recent_prompts() {
+        let config = PrefixCacheConfig {
+            memory_tokens: 5,
+            ..Default::default()
+        };
+        let mut prefixes = PrefixCache::new("model", config).unwrap();
+        prefixes.insert(&[1, 2], cache(2)).unwrap();
+        // extends the first prompt, which is dropped
+        prefixes.insert(&[1, 2, 3], cache(3)).unwrap();
+        assert_eq!(prefixes.entries.len(), 1);
+        prefixes.insert(&[4, 5], cache(2)).unwrap();
+        prefixes.insert(&[6, 7], cache(2)).unwrap();
+        let tokens = prefixes
+            .entries
+            .iter()
+            .map(|entry| entry.tokens.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![vec![6, 7], vec![4, 5]]);
+    }
+
+    #[test]
+    fn test_prompts_beyond_the_budget_are_not_cached() {
+        let dir =
+            std::env::temp_dir().join(format!("edgerunner-prefix-budget-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = PrefixCacheConfig {
+            memory_tokens: 4,
+            dir: Some(dir.to_string_lossy().to_string()),
+            disk_tokens: 4,
+        };
+        let mut other = PrefixCache::new("other", config.clone()).unwrap();
+        other.insert(&[8, 9], cache(2)).unwrap();
+        let mut prefixes = PrefixCache::new("model", config).unwrap();
+        prefixes.insert(&[1, 2], cache(2)).unwrap();
+
+        prefixes.insert(&[1, 2, 3, 4, 5], cache(5)).unwrap();
+        assert_eq!(prefixes.entries.len(), 1);
+        assert_eq!(prefixes.entries[0].tokens, vec![1, 2]);
+        let files = prefixes
+            .disk
+            .iter()
+            .map(|entry| entry.file.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![file_name("model", &[1, 2]), file_name("other", &[8, 9])]
+        );
+        assert!(files.iter().all(|file| dir.join(file).exists()));
+        assert!(!dir.join(file_name("model", &[1, 2, 3, 4, 5])).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use candle_core::{DType, Tensor};
+use rand::{distributions::Distribution, Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EdgerunnerError, Result, ResultExt};
+
+/// The position of a [`Sampler`] in its random stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SamplerState {
+    pub seed: u64,
+    /// The stream of the seed, see [`Sampler::fork`].
+    #[serde(default)]
+    pub stream: u64,
+    /// The 32-bit words drawn from the stream so far.
+    pub word_pos: u128,
+}
+
+/// Samples the next token like candle's `LogitsProcessor`, drawing the same tokens for a seed,
+/// with a random state that can be saved and restored. `StdRng` is a ChaCha12 generator.
+pub struct Sampler {
+    seed: u64,
+    rng: ChaCha12Rng,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
+        let temperature = temperature.filter(|t| *t >= 1e-7);
+        Self {
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            temperature,
+            top_p,
+        }
+    }
+
+    pub fn state(&self) -> SamplerState {
+        SamplerState {
+            seed: self.seed,
+            stream: self.rng.get_stream(),
+            word_pos: self.rng.get_word_pos(),
+        }
+    }
+
+    /// Continues the random stream of `state`.
+    pub fn restore(&mut self, state: SamplerState) {
+        self.seed = state.seed;
+        self.rng = ChaCha12Rng::seed_from_u64(state.seed);
+        self.rng.set_stream(state.stream);
+        self.rng.set_word_pos(state.word_pos);
+    }
+
+    /// A sampler with the same settings drawing from the `stream` of the seed, which shares
+    /// nothing with the other streams. New samplers draw from stream 0.
+    pub fn fork(&self, stream: u64) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(self.seed);
+        rng.set_stream(stream);
+        Self {
+            seed: self.seed,
+            rng,
+            temperature: self.temperature,
+            top_p: self.top_p,
+        }
+    }
+
+    /// Whether the most likely token is always picked.
+    pub fn is_greedy(&self) -> bool {
+        self.temperature.is_none()
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        match self.weights(logits)? {
+            None => argmax(logits),
+            Some(weights) => self.sample_weights(&weights),
+        }
+    }
+
+    /// The distribution tokens are sampled from, `None` when sampling is greedy.
+    pub fn probabilities(&self, logits: &Tensor) -> Result<Option<Vec<f32>>> {
+        Ok(self.weights(logits)?.map(|mut weights| {
+            let total = weights.iter().sum::<f32>();
+            weights.iter_mut().for_each(|w| *w /= total);
+            weights
+        }))
+    }
+
+    /// The unnormalized probabilities after the temperature and the nucleus cutoff.
+    fn weights(&self, logits: &Tensor) -> Result<Option<Vec<f32>>> {
+        let Some(temperature) = self.temperature else {
+            return Ok(None);
+        };
+        let logits = logits.to_dtype(DType::F32)?;
+        let prs = candle_nn::ops::softmax_last_dim(&(&logits / temperature)?)?;
+        let mut prs = prs.to_vec1::<f32>()?;
+        let top_p = self.top_p.unwrap_or(1.);
+        if top_p > 0.0 && top_p < 1.0 {
+            clamp_top_p(&mut prs, top_p as f32);
+        }
+        Ok(Some(prs))
+    }
+
+    /// Draws a token with a probability proportional to its weight.
+    pub fn sample_weights(&mut self, weights: &[f32]) -> Result<u32> {
+        let distr = rand::distributions::WeightedIndex::new(weights)
+            .map_err(|e| anyhow::Error::msg(format!("cannot sample: {e}")))
+            .kind(EdgerunnerError::Inference)?;
+        Ok(distr.sample(&mut self.rng) as u32)
+    }
+
+    /// A number uniformly drawn from `[0, 1)`.
+    pub fn uniform(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+/// The most likely token, the last one of a tie like candle picks it.
+pub fn argmax(logits: &Tensor) -> Result<u32> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    Ok(logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, u), (_, v)| u.total_cmp(v))
+        .map_or(0, |(i, _)| i as u32))
+}
+
+/// Zeroes the least likely tokens beyond the `top_p` probability mass (nucleus sampling).
+fn clamp_top_p(prs: &mut [f32], top_p: f32) {
+    let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+    let mut cumsum = 0.;
+    for index in argsort_indices {
+        if cumsum >= top_p {
+            prs[index] = 0.0;
+        } else {
+            cumsum += prs[index];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use candle_transformers::generation::LogitsProcessor;
+
+    fn logits(step: usize) -> Tensor {
+        let logits = (0..16)
+            .map(|i| ((i * 7 + step * 3) % 11) as f32 / 3.)
+            .collect::<Vec<_>>();
+        Tensor::new(logits, &Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn test_sampler_draws_like_the_logits_processor() {
+        for (temperature, top_p) in [(None, None), (Some(0.8), None), (Some(1.2), Some(0.7))] {
+            let mut expected = LogitsProcessor::new(42, temperature, top_p);
+            let mut sampler = Sampler::new(42, temperature, top_p);
+            for step in 0..32 {
+                assert_eq!(
+                    sampler.sample(&logits(step)).unwrap(),
+                    expected.sample(&logits(step)).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sampler_restores_its_stream() {
+        let mut sampler = Sampler::new(7, Some(1.), None);
+        for step in 0..5 {
+            sampler.sample(&logits(step)).unwrap();
+        }
+        let state = sampler.state();
+        let expected = (5..12)
+            .map(|step| sampler.sample(&logits(step)).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut restored = Sampler::new(0, Some(1.), None);
+        restored.restore(state);
+        let sampled = (5..12)
+            .map(|step| restored.sample(&logits(step)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sampled, expected);
+    }
+
+    #[test]
+    fn test_forked_samplers_draw_their_own_streams() {
+        let draw = |sampler: &mut Sampler| {
+            (0..16)
+                .map(|step| sampler.sample(&logits(step)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let sampler = Sampler::new(7, Some(1.), None);
+        let first = draw(&mut sampler.fork(0));
+        let second = draw(&mut sampler.fork(1));
+        assert_eq!(first, draw(&mut Sampler::new(7, Some(1.), None)));
+        assert_ne!(first, second);
+
+        let mut forked = sampler.fork(1);
+        draw(&mut forked);
+        let mut restored = Sampler::new(0, Some(1.), None);
+        restored.restore(forked.state());
+        assert_eq!(draw(&mut restored), draw(&mut forked));
+    }
+}
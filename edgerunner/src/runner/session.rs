@@ -0,0 +1,126 @@
+use std::{fs, path::Path};
+
+use candle_core::{Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::model::SamplingParams,
+    error::{EdgerunnerError, Result, ResultExt},
+    model::{causal_lm::KvCache, types::ModelArchitecture},
+};
+
+use super::sampler::SamplerState;
+
+const SESSION_VERSION: u32 = 2;
+/// The tensor holding the JSON header next to the kv cache tensors.
+const HEADER_TENSOR: &str = "session";
+
+/// Identifies the weights a session was generated with, its kv cache is meaningless to others.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelFingerprint {
+    /// The model file name, or the model name when it is downloaded.
+    pub model: String,
+    /// The total size of the weight files.
+    pub size_bytes: u64,
+    /// A hash of the sizes and of the first and last MiB of the weight files, which hold their
+    /// headers and some of the weights.
+    pub weights_hash: u64,
+    pub architecture: ModelArchitecture,
+    pub vocab_size: usize,
+}
+
+#[derive(Deserialize)]
+struct SessionVersion {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionHeader {
+    version: u32,
+    model: ModelFingerprint,
+    tokens: Vec<u32>,
+    prompt_tokens: usize,
+    params: SamplingParams,
+    sampler: SamplerState,
+}
+
+/// A paused generation, which continues as if it never stopped.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub model: ModelFingerprint,
+    /// The prompt and the generated tokens, the last one is sampled but not run yet.
+    pub tokens: Vec<u32>,
+    /// The tokens of `tokens` that make up the prompt, the rest are subject to the repeat penalty.
+    pub prompt_tokens: usize,
+    pub params: SamplingParams,
+    pub sampler: SamplerState,
+    /// The cache of every token but the last one.
+    pub kv_cache: KvCache,
+}
+
+impl Session {
+    /// Writes the session as a safetensors file, with the tokens and the sampler state in a JSON
+    /// header tensor.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let header = SessionHeader {
+            version: SESSION_VERSION,
+            model: self.model.clone(),
+            tokens: self.tokens.clone(),
+            prompt_tokens: self.prompt_tokens,
+            params: self.params.clone(),
+            sampler: self.sampler,
+        };
+        let header = serde_json::to_vec(&header).kind(EdgerunnerError::Io)?;
+        let mut tensors = self.kv_cache.to_tensors();
+        tensors.insert(
+            HEADER_TENSOR.to_string(),
+            Tensor::from_vec(header.clone(), header.len(), &Device::Cpu)?,
+        );
+        candle_core::safetensors::save(&tensors, path).kind(EdgerunnerError::Io)?;
+        Ok(())
+    }
+
+    /// Reads a session saved by the same version of the format, its kv cache goes to `device`.
+    pub fn load(path: impl AsRef<Path>, device: &Device) -> Result<Self> {
+        let path = path.as_ref();
+        let mut tensors = candle_core::safetensors::load(path, device)
+            .map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))
+            .kind(EdgerunnerError::Io)?;
+        let header = tensors
+            .remove(HEADER_TENSOR)
+            .ok_or_else(|| EdgerunnerError::config(format!("{} is not a session", path.display())))?
+            .to_vec1::<u8>()?;
+        // the version first, the header of other versions may not parse
+        let version: SessionVersion = serde_json::from_slice(&header).kind(EdgerunnerError::Io)?;
+        if version.version != SESSION_VERSION {
+            return Err(EdgerunnerError::config(format!(
+                "{} is a version {} session, expected version {}",
+                path.display(),
+                version.version,
+                SESSION_VERSION
+            )));
+        }
+        let header: SessionHeader = serde_json::from_slice(&header).kind(EdgerunnerError::Io)?;
+        let kv_cache = KvCache::from_tensors(&mut tensors);
+        if header.tokens.is_empty() || kv_cache.len() + 1 != header.tokens.len() {
+            return Err(EdgerunnerError::config(format!(
+                "{} holds the kv cache of {} tokens for {} tokens",
+                path.display(),
+                kv_cache.len(),
+                header.tokens.len()
+            )));
+        }
+        Ok(Self {
+            model: header.model,
+            tokens: header.tokens,
+            prompt_tokens: header.prompt_tokens,
+            params: header.params,
+            sampler: header.sampler,
+            kv_cache,
+        })
+    }
+}
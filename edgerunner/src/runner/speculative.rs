@@ -0,0 +1,156 @@
+use candle_core::Tensor;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{EdgerunnerError, Result},
+    model::causal_lm::CausalLm,
+};
+
+use super::sampler::{argmax, Sampler};
+
+/// Speculative decoding: a small draft model proposes tokens that the model checks at once.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpeculativeConfig {
+    /// The draft model file, which has to share the vocabulary of the model.
+    pub draft_model: String,
+    /// The tokens drafted before every check.
+    #[serde(default = "default_draft_tokens")]
+    pub draft_tokens: usize,
+}
+
+fn default_draft_tokens() -> usize {
+    4
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SpeculativeStats {
+    pub drafted_tokens: usize,
+    /// The drafted tokens the model agreed with.
+    pub accepted_tokens: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        self.accepted_tokens as f64 / self.drafted_tokens.max(1) as f64
+    }
+}
+
+/// The draft model and how far its kv cache follows the tokens of the generation.
+pub struct Draft {
+    pub model: Box<dyn CausalLm>,
+    pub tokens: usize,
+    /// The leading tokens of the generation held by the draft kv cache.
+    pub cached: usize,
+    /// The drafted and accepted tokens of the running generation.
+    pub stats: SpeculativeStats,
+}
+
+impl Draft {
+    pub fn new(model: Box<dyn CausalLm>, tokens: usize) -> Self {
+        Self {
+            model,
+            tokens,
+            cached: 0,
+            stats: SpeculativeStats::default(),
+        }
+    }
+}
+
+/// Drops the keys and values of the tokens following the first `len` ones.
+pub fn truncate_kv_cache(model: &mut dyn CausalLm, len: usize) -> Result<()> {
+    match model.kv_cache() {
+        Some(kv_cache) if kv_cache.len() > len => Ok(model.set_kv_cache(kv_cache.truncate(len)?)?),
+        Some(_) => Ok(()),
+        None => Err(EdgerunnerError::config(format!(
+            "{} models cannot decode speculatively",
+            model.metadata().architecture
+        ))),
+    }
+}
+
+/// Checks the `drafted` tokens, drawn from `draft_probs` unless sampling is greedy, against the
+/// logits the model gives after each of them. Returns the number of accepted tokens and the
+/// token following them: the model's own pick where it disagrees, or a bonus token when it
+/// accepts them all. A drafted token is kept with probability `min(1, p / q)` and a rejected one
+/// is replaced by a draw from `max(0, p - q)`, which makes the tokens follow the distribution of
+/// the model alone.
+pub fn verify(
+    sampler: &mut Sampler,
+    drafted: &[u32],
+    draft_probs: &[Vec<f32>],
+    logits: &[Tensor],
+) -> Result<(usize, u32)> {
+    for (i, &token) in drafted.iter().enumerate() {
+        match sampler.probabilities(&logits[i])? {
+            None => {
+                let best = argmax(&logits[i])?;
+                if best != token {
+                    return Ok((i, best));
+                }
+            }
+            Some(p) => {
+                let q = &draft_probs[i];
+                let (p_token, q_token) = (p[token as usize], q[token as usize]);
+                if sampler.uniform() * q_token < p_token {
+                    continue;
+                }
+                let residual = p
+                    .iter()
+                    .zip(q)
+                    .map(|(p, q)| (p - q).max(0.))
+                    .collect::<Vec<_>>();
+                let next = if residual.iter().any(|r| *r > 0.) {
+                    sampler.sample_weights(&residual)?
+                } else {
+                    sampler.sample_weights(&p)?
+                };
+                return Ok((i, next));
+            }
+        }
+    }
+    Ok((drafted.len(), sampler.sample(&logits[drafted.len()])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn logits(values: &[f32]) -> Tensor {
+        Tensor::new(values, &Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn test_greedy_verify_stops_at_the_first_disagreement() {
+        let mut sampler = Sampler::new(0, None, None);
+        let rows = [
+            logits(&[0., 1., 0.]),
+            logits(&[2., 0., 0.]),
+            logits(&[0., 0., 1.]),
+        ];
+        assert_eq!(verify(&mut sampler, &[1, 0], &[], &rows).unwrap(), (2, 2));
+        assert_eq!(verify(&mut sampler, &[1, 2], &[], &rows).unwrap(), (1, 0));
+        assert_eq!(verify(&mut sampler, &[0, 0], &[], &rows).unwrap(), (0, 1));
+    }
+
+    #[test]
+    fn test_verify_keeps_the_distribution_of_the_model() {
+        // the model prefers token 0, the draft prefers token 1
+        let target = [0.7f32, 0.2, 0.1];
+        let draft = [vec![0.1f32, 0.8, 0.1]];
+        let rows = [logits(&target.map(f32::ln)), logits(&[0., 0., 0.])];
+        let mut sampler = Sampler::new(3, Some(1.), None);
+        let mut counts = [0usize; 3];
+        let runs = 4000;
+        for _ in 0..runs {
+            let drafted = sampler.sample_weights(&draft[0]).unwrap();
+            let (accepted, next) = verify(&mut sampler, &[drafted], &draft, &rows).unwrap();
+            let token = if accepted == 1 { drafted } else { next };
+            counts[token as usize] += 1;
+        }
+        for (count, p) in counts.iter().zip(target) {
+            let freq = *count as f32 / runs as f32;
+            assert!((freq - p).abs() < 0.03, "{counts:?}");
+        }
+    }
+}
@@ -1,45 +1,111 @@
-use std::{
-    io::Write,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
 };
 
-use anyhow::Result;
-use candle_core::{Device, Tensor};
-use candle_transformers::{generation::LogitsProcessor, models::quantized_llama::ModelWeights};
+use candle_core::{DType, Device, Tensor};
+use log::{debug, info, warn};
 use tokenizers::Tokenizer;
 
-use crate::{conf::which::Which, model::prompt::GeneratedPrompt};
+use crate::{
+    conf::{model::SamplingParams, which::Which},
+    error::{EdgerunnerError, Result, ResultExt},
+    model::{
+        causal_lm::{CausalLm, KvCache},
+        prompt::GeneratedPrompt,
+        types::ModelMetadata,
+    },
+};
 
-use super::token_output_stream::TokenOutputStream;
+use super::{
+    beam_search::{banned_tokens, top_k, Beam},
+    events::{EngineEvent, FinishReason, NoopObserver, SharedObserver},
+    grammar::{token_pieces, ToolCallGrammar},
+    prefix_cache::{PrefixCache, PrefixCacheStats},
+    sampler::{argmax, Sampler},
+    session::{ModelFingerprint, Session},
+    speculative::{truncate_kv_cache, verify, Draft, SpeculativeStats},
+    threads::ThreadPools,
+    token_output_stream::TokenOutputStream,
+};
 
-#[derive(Debug, thiserror::Error)]
-pub enum InferenceError {
-    #[error("Operation was stopped by the user")]
-    UserStopped,
+/// The former error of text generation, the stop of the user is now
+/// [`EdgerunnerError::Cancelled`] and candle errors [`EdgerunnerError::Inference`].
+#[deprecated(since = "0.1.0", note = "use `EdgerunnerError`")]
+pub type InferenceError = EdgerunnerError;
 
-    #[error("Candle core error: {0}")]
-    CandleCoreError(#[from] candle_core::Error),
+/// The end of text token of the gpt style vocabularies of phi-2 and stablelm.
+const GPT_EOS_TOKEN: &str = "<|endoftext|>";
 
-    #[error("An error occurred: {0}")]
-    Other(#[from] anyhow::Error),
+/// The outcome of a generation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Generation {
+    pub text: String,
+    /// The prompt length after truncation to the context.
+    pub prompt_tokens: usize,
+    /// The sampled tokens, including the end of sequence token.
+    pub generated_tokens: usize,
+    /// The prompt tokens restored from the prefix cache instead of going through the model.
+    pub cached_tokens: usize,
+    /// The log probability of the generated tokens under the model, after the repeat penalty
+    /// and before the temperature.
+    pub logprob: f64,
+    /// The drafted and accepted tokens when decoding with a draft model.
+    pub speculative: Option<SpeculativeStats>,
+    /// The top beams of a beam search, best first, empty when sampling.
+    pub beams: Vec<Beam>,
+    pub prompt_seconds: f64,
+    pub generation_seconds: f64,
+    pub finish_reason: FinishReason,
+}
+
+/// The log softmax of `logits` at `token`.
+fn log_probability(logits: &Tensor, token: u32) -> Result<f64> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits
+        .iter()
+        .map(|logit| ((logit - max) as f64).exp())
+        .sum::<f64>();
+    Ok((logits[token as usize] - max) as f64 - sum.ln())
+}
+
+/// What the decode loop reports about the prompt.
+#[derive(Clone, Copy)]
+struct PromptStats {
+    tokens: usize,
+    cached_tokens: usize,
+    seconds: f64,
+    /// The tokens generated before the decode loop starts and not counted again.
+    generated: usize,
 }
 
 pub struct TextGeneration {
-    model: ModelWeights,
+    model: Box<dyn CausalLm>,
     device: Device,
     tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
+    sampler: Sampler,
+    params: SamplingParams,
+    threads: ThreadPools,
+    observer: SharedObserver,
+    prefix_cache: Option<PrefixCache>,
+    draft: Option<Draft>,
+    grammar: Option<ToolCallGrammar>,
+    /// The grammar of the running generation, following its tokens.
+    grammar_state: Option<ToolCallGrammar>,
+    /// The text of every token as the grammar sees it, computed on first use.
+    pieces: Vec<String>,
+    /// The log probability of the tokens sampled by the running generation.
+    logprob: f64,
+    /// The prompt and generated tokens of the last generation, the last one is not run yet.
+    tokens: Vec<u32>,
+    prompt_len: usize,
 }
 
 impl TextGeneration {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        model: ModelWeights,
+        model: Box<dyn CausalLm>,
         device: Device,
         tokenizer: Tokenizer,
         repeat_penalty: f32,
@@ -48,26 +114,155 @@ impl TextGeneration {
         temp: Option<f64>,
         top_p: Option<f64>,
     ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
+        let params = SamplingParams {
+            temperature: temp,
+            top_p,
+            seed,
+            repeat_penalty,
+            repeat_last_n,
+            ..Default::default()
+        };
         Self {
             model,
             device,
             tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
+            sampler: Sampler::new(seed, temp, top_p),
+            params,
+            threads: ThreadPools::default(),
+            observer: Arc::new(NoopObserver),
+            prefix_cache: None,
+            draft: None,
+            grammar: None,
+            grammar_state: None,
+            pieces: Vec::new(),
+            logprob: 0.,
+            tokens: Vec::new(),
+            prompt_len: 0,
         }
     }
 
-    fn check_stop_flag(&self, stop_flag: &Arc<AtomicBool>) -> Result<(), InferenceError> {
+    /// Reports the progress of every run to `observer`.
+    pub fn with_observer(mut self, observer: SharedObserver) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Runs the prefill and the decode in the given pools instead of rayon's global pool.
+    pub fn with_threads(mut self, threads: ThreadPools) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Restores the kv cache of the prompt prefixes seen before instead of running them again.
+    pub fn with_prefix_cache(mut self, prefix_cache: PrefixCache) -> Self {
+        self.prefix_cache = Some(prefix_cache);
+        self
+    }
+
+    /// Decodes with speculative decoding, the draft model has to share the vocabulary.
+    pub fn with_draft(mut self, draft: Draft) -> Self {
+        self.draft = Some(draft);
+        self
+    }
+
+    /// The hits and misses of the prefix cache, if there is one.
+    pub fn prefix_cache_stats(&self) -> Option<PrefixCacheStats> {
+        self.prefix_cache.as_ref().map(PrefixCache::stats)
+    }
+
+    /// Samples the next runs with `params`, the sample length is given to each run.
+    pub fn set_sampling(&mut self, params: &SamplingParams) {
+        self.sampler = Sampler::new(params.seed, params.temperature, params.top_p);
+        self.params = params.clone();
+    }
+
+    /// Constrains the next runs to `grammar`, or lifts the constraint.
+    pub fn set_grammar(&mut self, grammar: Option<ToolCallGrammar>) {
+        self.grammar = grammar;
+    }
+
+    pub fn context_length(&self) -> usize {
+        self.model.context_length()
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        self.tokenizer.tokenizer()
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn metadata(&self) -> &ModelMetadata {
+        self.model.metadata()
+    }
+
+    /// The f32 logits of every position of `tokens` with shape `(seq_len, vocab_size)`.
+    pub fn logits(&mut self, tokens: &[u32]) -> Result<Tensor> {
+        self.check_context(tokens.len())?;
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        // the kv cache no longer matches the last generation
+        self.tokens.clear();
+        let model = &mut self.model;
+        let logits = self.threads.prefill(|| model.forward_all(&input, 0))?;
+        Ok(logits.squeeze(0)?.to_dtype(DType::F32)?)
+    }
+
+    fn check_context(&self, tokens: usize) -> Result<()> {
+        let context_length = self.model.context_length();
+        if tokens > context_length {
+            return Err(EdgerunnerError::ContextOverflow {
+                tokens,
+                context_length,
+            });
+        }
+        Ok(())
+    }
+
+    /// Caps `sample_len` to the context length, the prompt is cut to make room for the sampled
+    /// tokens down to the one token that primes the generation.
+    fn fit_context(&self, sample_len: usize) -> usize {
+        let context_length = self.model.context_length();
+        if sample_len > context_length {
+            warn!(
+                "Sampling {} tokens instead of {} to fit the context length",
+                context_length, sample_len
+            );
+        }
+        sample_len.min(context_length)
+    }
+
+    /// The last hidden states of the token sequences, right padded to the longest one, with
+    /// shape `(batch, seq_len, hidden_size)` in f32.
+    pub fn hidden_states(&mut self, batch: &[Vec<u32>]) -> Result<Tensor> {
+        let seq_len = batch.iter().map(Vec::len).max().unwrap_or(0);
+        self.check_context(seq_len)?;
+        let padded = batch
+            .iter()
+            .flat_map(|tokens| {
+                let padding = std::iter::repeat_n(0, seq_len - tokens.len());
+                tokens.iter().copied().chain(padding)
+            })
+            .collect::<Vec<u32>>();
+        let input = Tensor::from_vec(padded, (batch.len(), seq_len), &self.device)?;
+        self.tokens.clear();
+        let model = &mut self.model;
+        let hidden = self.threads.prefill(|| model.hidden_states(&input))?;
+        Ok(hidden.to_dtype(DType::F32)?)
+    }
+
+    fn check_stop_flag(&self, stop_flag: &Arc<AtomicBool>) -> Result<()> {
         if stop_flag.load(Ordering::SeqCst) {
-            println!("Operation was stopped by the user");
-            Err(InferenceError::UserStopped)
+            info!("Operation was stopped by the user");
+            self.observer.on_event(&EngineEvent::Stopped);
+            Err(EdgerunnerError::Cancelled)
         } else {
             Ok(())
         }
     }
 
+    /// Generates up to `sample_len` tokens, returning the response, the prompt tokens and seconds,
+    /// and the tokens sampled after the first one with their seconds.
     pub fn run(
         &mut self,
         prompt: GeneratedPrompt,
@@ -75,126 +270,729 @@ impl TextGeneration {
         which: &Which,
         stop_flag: Arc<AtomicBool>,
         on_token: impl Fn(&str),
-    ) -> Result<(String, f64, f64, f64, f64), InferenceError> {
-        self.check_stop_flag(&stop_flag)?;
+    ) -> Result<(String, f64, f64, f64, f64)> {
+        let generation = self.generate(prompt, sample_len, which, stop_flag, on_token)?;
+        Ok((
+            generation.text,
+            generation.prompt_tokens as f64,
+            generation.prompt_seconds,
+            // the first token comes out of the prompt processing
+            generation.generated_tokens.saturating_sub(1) as f64,
+            generation.generation_seconds,
+        ))
+    }
+
+    /// Generates up to `sample_len` tokens for `prompt`, streaming their text to `on_token`.
+    pub fn generate(
+        &mut self,
+        prompt: GeneratedPrompt,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: Arc<AtomicBool>,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        let sample_len = self.fit_context(sample_len);
+        let (logits, prompt) = self.prefill(prompt, sample_len, which, &stop_flag)?;
+        if self.params.beam_search.is_some() {
+            self.beam_search(&logits, sample_len, which, &stop_flag, prompt, on_token)
+        } else {
+            self.branch(&logits, sample_len, which, &stop_flag, prompt, on_token)
+        }
+    }
+
+    /// Generates `n` completions of `prompt` that share its prefill, streaming the text of each
+    /// to `on_token` with its index. Every completion forks the kv cache of the prompt and
+    /// samples from its own stream of the seed, the first one being the completion
+    /// [`generate`](Self::generate) returns.
+    pub fn generate_n(
+        &mut self,
+        prompt: GeneratedPrompt,
+        n: usize,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: Arc<AtomicBool>,
+        on_token: impl Fn(usize, &str),
+    ) -> Result<Vec<Generation>> {
+        if self.params.beam_search.is_some() {
+            return Err(EdgerunnerError::config(
+                "beam search returns its beams rather than n completions",
+            ));
+        }
+        let sample_len = self.fit_context(sample_len);
+        let (logits, prompt) = self.prefill(prompt, sample_len, which, &stop_flag)?;
+        let prompt_tokens = self.tokens.clone();
+        let kv_cache = match self.model.kv_cache() {
+            Some(kv_cache) => Some(kv_cache),
+            None if n > 1 => {
+                return Err(EdgerunnerError::config(format!(
+                    "{} models cannot fork their kv cache",
+                    self.model.metadata().architecture
+                )))
+            }
+            None => None,
+        };
+        let mut generations = Vec::with_capacity(n);
+        for i in 0..n {
+            if i > 0 {
+                self.model
+                    .set_kv_cache(kv_cache.clone().expect("checked above"))?;
+                self.tokens = prompt_tokens.clone();
+                self.tokenizer.clear();
+                self.sampler = self.sampler.fork(i as u64);
+            }
+            let generation = self.branch(
+                &logits,
+                sample_len,
+                which,
+                &stop_flag,
+                prompt,
+                |text: &str| on_token(i, text),
+            )?;
+            generations.push(generation);
+        }
+        Ok(generations)
+    }
+
+    /// Runs `prompt` through the model, leaving room for `sample_len` tokens, and returns the
+    /// logits of the first token to sample.
+    fn prefill(
+        &mut self,
+        prompt: GeneratedPrompt,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: &Arc<AtomicBool>,
+    ) -> Result<(Tensor, PromptStats)> {
+        self.check_stop_flag(stop_flag)?;
         // check if model is available
         if !which.is_available() {
-            return Err(InferenceError::Other(anyhow::Error::msg(format!(
+            return Err(EdgerunnerError::config(format!(
                 "Model {:?} is not available",
                 which
-            ))));
+            )));
         }
 
         self.tokenizer.clear();
+        self.model.reset_cache();
 
         let pre_prompt_tokens: Vec<u32> = vec![];
         let prompt_str = prompt.as_str();
-        println!("Prompt: {}", prompt_str);
+        debug!("Prompt: {}", prompt_str);
         let tokens = self
             .tokenizer
             .tokenizer()
             .encode(prompt_str, true)
-            .map_err(anyhow::Error::msg)?;
+            .map_err(anyhow::Error::msg)
+            .kind(EdgerunnerError::Tokenizer)?;
 
         let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
 
         let to_sample = sample_len.saturating_sub(1);
-        let prompt_tokens = if prompt_tokens.len() + to_sample > 4096 {
-            let to_remove = prompt_tokens.len() + to_sample + 10 - 4096;
-            prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..].to_vec()
+        let context_length = self.model.context_length();
+        let prompt_tokens = if prompt_tokens.len() + to_sample > context_length {
+            let to_remove = prompt_tokens.len() + to_sample + 10 - context_length;
+            // drop the oldest tokens but always keep the last one to prime the generation
+            let to_remove = to_remove.min(prompt_tokens.len().saturating_sub(1));
+            prompt_tokens[to_remove..].to_vec()
         } else {
             prompt_tokens
         };
 
-        self.check_stop_flag(&stop_flag)?;
-
-        let mut all_tokens = vec![];
+        self.check_stop_flag(stop_flag)?;
 
         let start_prompt_processor = std::time::Instant::now();
-        let mut next_token = {
-            let input = Tensor::new(prompt_tokens.as_slice(), &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            self.logits_processor.sample(&logits)?
+        let cached_tokens = self.restore_prefix(&prompt_tokens)?;
+        let logits = {
+            let input = Tensor::new(&prompt_tokens[cached_tokens..], &self.device)?.unsqueeze(0)?;
+            let model = &mut self.model;
+            let logits = self
+                .threads
+                .prefill(|| model.forward(&input, cached_tokens))?;
+            logits.squeeze(0)?
         };
+        if let (Some(prefix_cache), Some(kv_cache)) =
+            (self.prefix_cache.as_mut(), self.model.kv_cache())
+        {
+            prefix_cache.insert(&prompt_tokens, kv_cache)?;
+        }
 
-        self.check_stop_flag(&stop_flag)?;
-
-        let mut full_response = "".to_string();
+        self.check_stop_flag(stop_flag)?;
 
         let prompt_dt = start_prompt_processor.elapsed();
-        all_tokens.push(next_token);
-        if let Some(t) = self.tokenizer.next_token(next_token)? {
+        self.observer.on_event(&EngineEvent::PromptProcessed {
+            tokens: prompt_tokens.len(),
+            seconds: prompt_dt.as_secs_f64(),
+        });
+        self.prompt_len = prompt_tokens.len();
+        self.tokens = prompt_tokens;
+        let prompt = PromptStats {
+            tokens: self.prompt_len,
+            cached_tokens,
+            seconds: prompt_dt.as_secs_f64(),
+            // the first token is counted with the ones decoded after it
+            generated: 0,
+        };
+        Ok((logits, prompt))
+    }
+
+    /// Samples the first token from the prompt `logits` and decodes the ones after it.
+    fn branch(
+        &mut self,
+        logits: &Tensor,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: &Arc<AtomicBool>,
+        prompt: PromptStats,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        self.grammar_state = self.grammar.clone();
+        self.logprob = 0.;
+        if let Some(draft) = self.draft.as_mut() {
+            draft.cached = 0;
+        }
+        let next_token = self.sample_token(logits)?;
+        self.tokens.push(next_token);
+        let mut full_response = String::new();
+        let text = self.tokenizer.next_token(next_token)?;
+        self.token_generated(0, next_token, &text);
+        if let Some(t) = text {
             on_token(&t);
             full_response += &t;
         }
 
+        self.decode(
+            sample_len.saturating_sub(1),
+            which,
+            stop_flag,
+            full_response,
+            prompt,
+            on_token,
+        )
+    }
+
+    /// The model's own eos tokens as well as the chat template end of turn token and the gpt
+    /// style end of text token.
+    fn eos_tokens(&self, which: &Which) -> Result<Vec<u32>> {
         let eos_token = if which.is_open_chat() {
             "<|end_of_turn|>"
         } else {
             "</s>"
         };
+        let mut eos_tokens = self.model.eos_token_ids().to_vec();
+        for token in [eos_token, GPT_EOS_TOKEN] {
+            eos_tokens.extend(
+                self.tokenizer
+                    .get_token(token)
+                    .filter(|id| !eos_tokens.contains(id)),
+            );
+        }
+        if eos_tokens.is_empty() {
+            return Err(EdgerunnerError::Tokenizer(anyhow::Error::msg(format!(
+                "No eos token {} or {} in the tokenizer vocabulary",
+                eos_token, GPT_EOS_TOKEN
+            ))));
+        }
+        Ok(eos_tokens)
+    }
 
-        let eos_token = *self
-            .tokenizer
-            .tokenizer()
-            .get_vocab(true)
-            .get(eos_token)
-            .unwrap();
+    /// Decodes the most likely continuations of the prompt from its `logits`, stepping every
+    /// running beam at once as a batch over a kv cache reordered after each step. The best beam
+    /// is the text of the generation, sent to `on_token` at the end. It leaves nothing to
+    /// resume.
+    fn beam_search(
+        &mut self,
+        logits: &Tensor,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: &Arc<AtomicBool>,
+        prompt: PromptStats,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        let config = self.params.beam_search.clone().unwrap_or_default();
+        let eos_tokens = self.eos_tokens(which)?;
+        let width = config.beam_width.max(1);
+        let start_post_prompt = std::time::Instant::now();
+
+        // the generated tokens and log probability of the running beams, and their next logits
+        let mut running = vec![(Vec::<u32>::new(), 0f64)];
+        let mut logits = vec![logits.clone()];
+        let mut finished = Vec::new();
+        for step in 0..sample_len {
+            self.check_stop_flag(stop_flag)?;
+            let mut candidates = Vec::new();
+            for ((tokens, logprob), logits) in running.iter().zip(&logits) {
+                let logits = self.penalize(logits, tokens)?;
+                let mut logprobs = candle_nn::ops::log_softmax(&logits, 0)?
+                    .to_dtype(DType::F32)?
+                    .to_vec1::<f32>()?;
+                for token in banned_tokens(tokens, config.no_repeat_ngram_size) {
+                    logprobs[token as usize] = f32::NEG_INFINITY;
+                }
+                // enough candidates for `width` beams even if every best one ends
+                for token in top_k(&logprobs, 2 * width) {
+                    if logprobs[token].is_finite() {
+                        let mut tokens = tokens.clone();
+                        tokens.push(token as u32);
+                        candidates.push((tokens, logprob + logprobs[token] as f64));
+                    }
+                }
+            }
+            candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+            let mut next = Vec::with_capacity(width);
+            for (rank, (tokens, logprob)) in candidates.into_iter().enumerate() {
+                if eos_tokens.contains(tokens.last().expect("a token was pushed")) {
+                    // ends that rank below the running beams are dropped
+                    if rank < width {
+                        finished.push((tokens, logprob, FinishReason::Eos));
+                    }
+                } else if next.len() < width {
+                    next.push((tokens, logprob));
+                } else {
+                    break;
+                }
+            }
+
+            let done = finished.len() >= width
+                && (config.early_stopping || {
+                    let worst = finished
+                        .iter()
+                        .map(|(tokens, logprob, _)| config.score(*logprob, tokens.len()))
+                        .fold(f64::INFINITY, f64::min);
+                    let best = next.first().map_or(f64::NEG_INFINITY, |(tokens, logprob)| {
+                        config.score(*logprob, tokens.len())
+                    });
+                    best <= worst
+                });
+            if done || next.is_empty() {
+                break;
+            }
+            if step + 1 == sample_len {
+                finished.extend(
+                    next.into_iter()
+                        .map(|(tokens, logprob)| (tokens, logprob, FinishReason::Length)),
+                );
+                break;
+            }
+
+            // each beam continues the kv cache of the beam it extends
+            let origins = next
+                .iter()
+                .map(|(tokens, _)| {
+                    running
+                        .iter()
+                        .position(|(running, _)| tokens.starts_with(running))
+                        .expect("candidates extend a running beam") as u32
+                })
+                .collect::<Vec<_>>();
+            let origins = Tensor::new(origins, &self.device)?;
+            let kv_cache = self.model.kv_cache().ok_or_else(|| {
+                EdgerunnerError::config(format!(
+                    "{} models cannot search beams",
+                    self.model.metadata().architecture
+                ))
+            })?;
+            let layers = kv_cache
+                .layers
+                .iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k.contiguous()?.index_select(&origins, 0)?,
+                        v.contiguous()?.index_select(&origins, 0)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.model.set_kv_cache(KvCache { layers })?;
+
+            let input = next
+                .iter()
+                .map(|(tokens, _)| *tokens.last().expect("a token was pushed"))
+                .collect::<Vec<_>>();
+            let input = Tensor::new(input, &self.device)?.unsqueeze(1)?;
+            let position = self.prompt_len + step;
+            let model = &mut self.model;
+            let batch = self.threads.decode(|| model.forward(&input, position))?;
+            logits = (0..next.len())
+                .map(|i| batch.get(i))
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            running = next;
+        }
+
+        let mut beams = finished
+            .into_iter()
+            .map(|(tokens, logprob, finish_reason)| {
+                let text = self
+                    .tokenizer
+                    .tokenizer()
+                    .decode(&tokens, true)
+                    .map_err(anyhow::Error::msg)
+                    .kind(EdgerunnerError::Tokenizer)?;
+                Ok(Beam {
+                    text,
+                    score: config.score(logprob, tokens.len()),
+                    tokens,
+                    logprob,
+                    finish_reason,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        beams.sort_by(|a, b| b.score.total_cmp(&a.score));
+        beams.truncate(width);
+
+        // the beams do not match a single kv cache
+        self.tokens.clear();
+        self.model.reset_cache();
+
+        let best = beams
+            .first()
+            .cloned()
+            .ok_or_else(|| EdgerunnerError::inference("the beam search finished without a beam"))?;
+        if !best.text.is_empty() {
+            on_token(&best.text);
+        }
+        let dt = start_post_prompt.elapsed();
+        self.observer.on_event(&EngineEvent::Finished {
+            prompt_tokens: prompt.tokens,
+            generated_tokens: best.tokens.len(),
+            prompt_seconds: prompt.seconds,
+            generation_seconds: dt.as_secs_f64(),
+            reason: best.finish_reason,
+        });
+        Ok(Generation {
+            text: best.text,
+            prompt_tokens: prompt.tokens,
+            generated_tokens: best.tokens.len(),
+            cached_tokens: prompt.cached_tokens,
+            logprob: best.logprob,
+            speculative: None,
+            beams,
+            prompt_seconds: prompt.seconds,
+            generation_seconds: dt.as_secs_f64(),
+            finish_reason: best.finish_reason,
+        })
+    }
 
+    /// Samples `to_sample` more tokens after the last generation, feeding its last sampled token
+    /// first.
+    fn decode(
+        &mut self,
+        to_sample: usize,
+        which: &Which,
+        stop_flag: &Arc<AtomicBool>,
+        mut full_response: String,
+        prompt: PromptStats,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        let eos_tokens = self.eos_tokens(which)?;
         let start_post_prompt = std::time::Instant::now();
 
+        let mut reason = FinishReason::Length;
         let mut sampled = 0;
-        for index in 0..to_sample {
-            self.check_stop_flag(&stop_flag)?;
-            let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, prompt_tokens.len() + index)?;
-            let logits = logits.squeeze(0)?;
-            let logits = if self.repeat_penalty == 1. {
-                logits
+        if let Some(draft) = self.draft.as_mut() {
+            draft.stats = SpeculativeStats::default();
+        }
+        'decode: while sampled < to_sample {
+            if self
+                .grammar_state
+                .as_ref()
+                .is_some_and(ToolCallGrammar::is_complete)
+            {
+                reason = FinishReason::Eos;
+                break;
+            }
+            self.check_stop_flag(stop_flag)?;
+            // drafted tokens ignore the grammar
+            let next_tokens = if self.draft.is_some() && self.grammar_state.is_none() {
+                self.speculate(to_sample - sampled)?
             } else {
-                let start_at = all_tokens.len().saturating_sub(self.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.repeat_penalty,
-                    &all_tokens[start_at..],
-                )?
+                vec![self.sample_next()?]
             };
-
-            next_token = self.logits_processor.sample(&logits)?;
-            all_tokens.push(next_token);
-            if let Some(t) = self.tokenizer.next_token(next_token)? {
-                on_token(&t);
-                full_response += &t;
-            }
-            sampled += 1;
-            if next_token == eos_token {
-                break;
+            for next_token in next_tokens {
+                sampled += 1;
+                self.tokens.push(next_token);
+                let text = self.tokenizer.next_token(next_token)?;
+                self.token_generated(self.tokens.len() - 1 - self.prompt_len, next_token, &text);
+                if let Some(t) = text {
+                    on_token(&t);
+                    full_response += &t;
+                }
+                if eos_tokens.contains(&next_token) {
+                    reason = FinishReason::Eos;
+                    break 'decode;
+                }
             }
-            self.check_stop_flag(&stop_flag)?;
+            self.check_stop_flag(stop_flag)?;
+        }
+        if self.draft.is_some() {
+            // the model checked drafted tokens beyond an end of sequence
+            truncate_kv_cache(self.model.as_mut(), self.tokens.len() - 1)?;
         }
 
-        if let Some(rest) = self
-            .tokenizer
-            .decode_rest()
-            .map_err(candle_core::Error::msg)?
-        {
+        if let Some(rest) = self.tokenizer.decode_rest()? {
             on_token(&rest);
             full_response += &rest;
         }
 
-        std::io::stdout().flush().map_err(|e| {
-            InferenceError::Other(anyhow::Error::msg(format!("Failed to flush stdout: {}", e)))
-        })?;
-
+        let generated_tokens = self.tokens.len() - self.prompt_len - prompt.generated;
         let dt = start_post_prompt.elapsed();
+        self.observer.on_event(&EngineEvent::Finished {
+            prompt_tokens: prompt.tokens,
+            generated_tokens,
+            prompt_seconds: prompt.seconds,
+            generation_seconds: dt.as_secs_f64(),
+            reason,
+        });
 
-        Ok((
-            full_response,
-            prompt_tokens.len() as f64,
-            prompt_dt.as_secs_f64(),
-            sampled as f64,
-            dt.as_secs_f64(),
-        ))
+        Ok(Generation {
+            text: full_response,
+            prompt_tokens: prompt.tokens,
+            generated_tokens,
+            cached_tokens: prompt.cached_tokens,
+            logprob: self.logprob,
+            speculative: self.draft.as_ref().map(|draft| draft.stats),
+            beams: Vec::new(),
+            prompt_seconds: prompt.seconds,
+            generation_seconds: dt.as_secs_f64(),
+            finish_reason: reason,
+        })
+    }
+
+    /// Runs the last sampled token through the model and samples the one following it.
+    fn sample_next(&mut self) -> Result<u32> {
+        let next_token = *self.tokens.last().expect("the prompt sampled a token");
+        let position = self.tokens.len() - 1;
+        let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
+        let model = &mut self.model;
+        let logits = self.threads.decode(|| model.forward(&input, position))?;
+        let logits = self.penalize(&logits.squeeze(0)?, &[])?;
+        self.sample_token(&logits)
+    }
+
+    /// Samples a token the grammar allows, if there is one, and moves the grammar past it.
+    fn sample_token(&mut self, logits: &Tensor) -> Result<u32> {
+        let Some(grammar) = self.grammar_state.as_ref() else {
+            let token = self.sampler.sample(logits)?;
+            self.logprob += log_probability(logits, token)?;
+            return Ok(token);
+        };
+        if self.pieces.is_empty() {
+            self.pieces = token_pieces(self.tokenizer.tokenizer());
+        }
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let mut allowed = false;
+        for (i, logit) in logits.iter_mut().enumerate() {
+            if self
+                .pieces
+                .get(i)
+                .is_some_and(|piece| grammar.allows(piece))
+            {
+                allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if !allowed {
+            return Err(EdgerunnerError::inference(
+                "the grammar allows none of the tokens",
+            ));
+        }
+        let logits = Tensor::new(logits, &self.device)?;
+        let token = self.sampler.sample(&logits)?;
+        self.logprob += log_probability(&logits, token)?;
+        if let Some(grammar) = self.grammar_state.as_mut() {
+            grammar.push_str(&self.pieces[token as usize]);
+        }
+        Ok(token)
+    }
+
+    /// Applies the repeat penalty to the logits following the generated and `drafted` tokens.
+    fn penalize(&self, logits: &Tensor, drafted: &[u32]) -> Result<Tensor> {
+        if self.params.repeat_penalty == 1. {
+            return Ok(logits.clone());
+        }
+        let generated = [&self.tokens[self.prompt_len..], drafted].concat();
+        let start_at = generated.len().saturating_sub(self.params.repeat_last_n);
+        Ok(candle_transformers::utils::apply_repeat_penalty(
+            logits,
+            self.params.repeat_penalty,
+            &generated[start_at..],
+        )?)
+    }
+
+    /// Lets the draft model propose tokens and keeps the ones the model agrees with, followed by
+    /// the model's own next token, at most `remaining` tokens.
+    fn speculate(&mut self, remaining: usize) -> Result<Vec<u32>> {
+        let last = *self.tokens.last().expect("the prompt sampled a token");
+        let position = self.tokens.len() - 1;
+        let draft = self
+            .draft
+            .as_mut()
+            .expect("speculating needs a draft model");
+
+        // bring the draft kv cache to the tokens before the last one
+        if draft.cached == 0 {
+            draft.model.reset_cache();
+        } else {
+            truncate_kv_cache(draft.model.as_mut(), draft.cached)?;
+        }
+        if draft.cached < position {
+            let input =
+                Tensor::new(&self.tokens[draft.cached..position], &self.device)?.unsqueeze(0)?;
+            let model = &mut draft.model;
+            let start = draft.cached;
+            self.threads.prefill(|| model.forward(&input, start))?;
+        }
+
+        let draft_tokens = draft.tokens.min(remaining - 1);
+        let mut drafted = Vec::with_capacity(draft_tokens);
+        let mut draft_probs = Vec::with_capacity(draft_tokens);
+        let mut input_token = last;
+        for i in 0..draft_tokens {
+            let input = Tensor::new(&[input_token], &self.device)?.unsqueeze(0)?;
+            let model = &mut self.draft.as_mut().expect("checked above").model;
+            let logits = self
+                .threads
+                .decode(|| model.forward(&input, position + i))?;
+            let logits = self.penalize(&logits.squeeze(0)?, &drafted)?;
+            input_token = match self.sampler.probabilities(&logits)? {
+                None => argmax(&logits)?,
+                Some(probs) => {
+                    let token = self.sampler.sample_weights(&probs)?;
+                    draft_probs.push(probs);
+                    token
+                }
+            };
+            drafted.push(input_token);
+        }
+
+        let input =
+            Tensor::new([&[last], drafted.as_slice()].concat(), &self.device)?.unsqueeze(0)?;
+        let model = &mut self.model;
+        let logits = self
+            .threads
+            .prefill(|| model.forward_all(&input, position))?
+            .squeeze(0)?;
+        let rows = (0..=drafted.len())
+            .map(|i| self.penalize(&logits.get(i)?, &drafted[..i]))
+            .collect::<Result<Vec<_>>>()?;
+        let (accepted, next_token) = verify(&mut self.sampler, &drafted, &draft_probs, &rows)?;
+        for (row, &token) in rows
+            .iter()
+            .zip(drafted[..accepted].iter().chain([&next_token]))
+        {
+            self.logprob += log_probability(row, token)?;
+        }
+
+        let draft = self.draft.as_mut().expect("checked above");
+        draft.stats.drafted_tokens += drafted.len();
+        draft.stats.accepted_tokens += accepted;
+        // the draft ran the last token and the drafted ones but the last
+        draft.cached = position + draft_tokens.min(accepted + 1);
+        truncate_kv_cache(self.model.as_mut(), position + accepted + 1)?;
+
+        drafted.truncate(accepted);
+        drafted.push(next_token);
+        Ok(drafted)
+    }
+
+    /// The state of the last generation, to continue it later with [`resume`](Self::resume).
+    pub fn session(&self, model: ModelFingerprint) -> Result<Session> {
+        let kv_cache = match self.model.kv_cache() {
+            Some(kv_cache) if !self.tokens.is_empty() => kv_cache,
+            Some(_) => return Err(EdgerunnerError::config("there is no generation to save")),
+            None => {
+                return Err(EdgerunnerError::config(format!(
+                    "{} models cannot save their state",
+                    self.model.metadata().architecture
+                )))
+            }
+        };
+        Ok(Session {
+            model,
+            tokens: self.tokens.clone(),
+            prompt_tokens: self.prompt_len,
+            params: self.params.clone(),
+            sampler: self.sampler.state(),
+            kv_cache: kv_cache.truncate(self.tokens.len() - 1)?,
+        })
+    }
+
+    /// Makes `session` the last generation, its sampling settings replace the current ones.
+    pub fn restore_session(&mut self, session: Session) -> Result<()> {
+        self.check_context(session.tokens.len())?;
+        self.model.reset_cache();
+        self.model.set_kv_cache(session.kv_cache)?;
+        self.set_sampling(&session.params);
+        self.sampler.restore(session.sampler);
+        self.tokens = session.tokens;
+        self.prompt_len = session.prompt_tokens.min(self.tokens.len());
+        // sessions do not keep the position in a grammar
+        self.grammar_state = None;
+        if let Some(draft) = self.draft.as_mut() {
+            draft.cached = 0;
+        }
+        Ok(())
+    }
+
+    /// Generates `sample_len` more tokens after the last generation or the restored session,
+    /// the same tokens a longer generation would have produced.
+    pub fn resume(
+        &mut self,
+        sample_len: usize,
+        which: &Which,
+        stop_flag: Arc<AtomicBool>,
+        on_token: impl Fn(&str),
+    ) -> Result<Generation> {
+        self.check_stop_flag(&stop_flag)?;
+        if self.tokens.is_empty() {
+            return Err(EdgerunnerError::config("there is no generation to resume"));
+        }
+        self.check_context(self.tokens.len() + sample_len)?;
+
+        // replay the generated tokens so that the text continues where it stopped, the text
+        // held back at the end of the last generation was already returned
+        self.tokenizer.clear();
+        for &token in &self.tokens[self.prompt_len..] {
+            self.tokenizer.next_token(token)?;
+        }
+        self.tokenizer.flush_rest()?;
+
+        self.logprob = 0.;
+        let prompt = PromptStats {
+            tokens: self.tokens.len() - 1,
+            cached_tokens: self.tokens.len() - 1,
+            seconds: 0.,
+            generated: self.tokens.len() - self.prompt_len,
+        };
+        self.decode(
+            sample_len,
+            which,
+            &stop_flag,
+            String::new(),
+            prompt,
+            on_token,
+        )
+    }
+
+    /// Restores the kv cache of the longest cached prefix of `tokens`, returning its length.
+    fn restore_prefix(&mut self, tokens: &[u32]) -> Result<usize> {
+        let Some(prefix_cache) = self.prefix_cache.as_mut() else {
+            return Ok(0);
+        };
+        match prefix_cache.lookup(tokens, &self.device)? {
+            Some(kv_cache) => {
+                let len = kv_cache.len();
+                self.model.set_kv_cache(kv_cache)?;
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn token_generated(&self, index: usize, id: u32, text: &Option<String>) {
+        self.observer.on_event(&EngineEvent::TokenGenerated {
+            index,
+            id,
+            text: text.clone(),
+        });
     }
 }
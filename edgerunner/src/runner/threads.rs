@@ -0,0 +1,328 @@
+use log::{info, warn};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{EdgerunnerError, Result, ResultExt};
+
+/// How many cpu threads the prompt processing and the token generation use, and optionally
+/// which cores they are pinned to. Unset counts use rayon's global pool, sized to all cores or
+/// `RAYON_NUM_THREADS`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadConfig {
+    pub prefill_threads: Option<usize>,
+    pub decode_threads: Option<usize>,
+    /// The cores the threads are pinned to round robin, the thread counts default to its length.
+    #[serde(default)]
+    pub pin_cores: Option<Vec<usize>>,
+}
+
+/// The number of cores a cpu set holds.
+#[cfg(target_os = "linux")]
+const MAX_CORES: usize = libc::CPU_SETSIZE as usize;
+#[cfg(not(target_os = "linux"))]
+const MAX_CORES: usize = 1024;
+
+/// The cores the process may run on, when the platform tells.
+#[cfg(target_os = "linux")]
+pub fn allowed_cores() -> Option<Vec<usize>> {
+    // SAFETY: the cpu set is zeroed and filled by the syscall before it is read
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some(
+            (0..MAX_CORES)
+                .filter(|&core| libc::CPU_ISSET(core, &set))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cores() -> Option<Vec<usize>> {
+    None
+}
+
+/// Checks that every core of `cores` fits in a cpu set.
+fn check_core_bounds(cores: &[usize]) -> Result<()> {
+    match cores.iter().find(|&&core| core >= MAX_CORES) {
+        Some(core) => Err(EdgerunnerError::config(format!(
+            "core {} is beyond the {} cores of a cpu set",
+            core, MAX_CORES
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the process may run on every core of `cores`.
+fn check_cores(cores: &[usize]) -> Result<()> {
+    check_core_bounds(cores)?;
+    if let Some(allowed) = allowed_cores() {
+        if let Some(core) = cores.iter().find(|core| !allowed.contains(core)) {
+            return Err(EdgerunnerError::config(format!(
+                "core {} is not one of the cores {:?} the process may run on",
+                core, allowed
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Parses a core list such as `0-3,8,10-11`.
+pub fn parse_core_list(list: &str) -> Result<Vec<usize>> {
+    let mut cores = Vec::new();
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |core: &str| {
+            core.trim()
+                .parse::<usize>()
+                .map_err(|_| EdgerunnerError::config(format!("invalid core {} in {}", core, list)))
+        };
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(EdgerunnerError::config(format!(
+                        "invalid core range {}",
+                        part
+                    )));
+                }
+                // checked before the range is collected, which could be huge
+                check_core_bounds(&[last])?;
+                cores.extend(first..=last);
+            }
+            None => cores.push(parse(part)?),
+        }
+    }
+    if cores.is_empty() {
+        return Err(EdgerunnerError::config("the core list is empty"));
+    }
+    check_core_bounds(&cores)?;
+    Ok(cores)
+}
+
+/// The thread configuration actually in effect, reported in logs and benchmarks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EffectiveThreads {
+    pub prefill_threads: usize,
+    pub decode_threads: usize,
+    pub pinned_cores: Option<Vec<usize>>,
+}
+
+/// The rayon pools the model runs in, candle's cpu kernels parallelize over the current pool.
+pub struct ThreadPools {
+    prefill: Option<ThreadPool>,
+    decode: Option<ThreadPool>,
+    effective: EffectiveThreads,
+}
+
+impl Default for ThreadPools {
+    fn default() -> Self {
+        Self::new(&ThreadConfig::default()).expect("the global pool needs no setup")
+    }
+}
+
+impl ThreadPools {
+    pub fn new(config: &ThreadConfig) -> Result<Self> {
+        let pin_cores = match &config.pin_cores {
+            Some(cores) if !pinning_supported() => {
+                warn!(
+                    "Core pinning is only supported on linux, ignoring cores {:?}",
+                    cores
+                );
+                None
+            }
+            Some(cores) => {
+                check_cores(cores)?;
+                Some(cores.clone())
+            }
+            None => None,
+        };
+        let threads = |count: Option<usize>| count.or(pin_cores.as_ref().map(Vec::len));
+        let prefill = build_pool("prefill", threads(config.prefill_threads), &pin_cores)?;
+        let decode = build_pool("decode", threads(config.decode_threads), &pin_cores)?;
+
+        let count = |pool: &Option<ThreadPool>| {
+            pool.as_ref()
+                .map_or_else(rayon::current_num_threads, ThreadPool::current_num_threads)
+        };
+        let effective = EffectiveThreads {
+            prefill_threads: count(&prefill),
+            decode_threads: count(&decode),
+            pinned_cores: pin_cores,
+        };
+        info!(
+            "threads: {} for the prefill, {} for the decode, pinned to cores {:?}",
+            effective.prefill_threads, effective.decode_threads, effective.pinned_cores
+        );
+
+        Ok(Self {
+            prefill,
+            decode,
+            effective,
+        })
+    }
+
+    pub fn effective(&self) -> &EffectiveThreads {
+        &self.effective
+    }
+
+    /// Runs the prompt processing `f` in the prefill pool.
+    pub fn prefill<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.prefill {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    /// Runs the token generation `f` in the decode pool.
+    pub fn decode<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.decode {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+fn build_pool(
+    name: &'static str,
+    threads: Option<usize>,
+    pin_cores: &Option<Vec<usize>>,
+) -> Result<Option<ThreadPool>> {
+    let threads = match threads {
+        Some(0) => {
+            return Err(EdgerunnerError::config(format!(
+                "{} needs at least one thread",
+                name
+            )))
+        }
+        Some(threads) => threads,
+        None => return Ok(None),
+    };
+    let mut builder = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |index| format!("edgerunner-{}-{}", name, index));
+    if let Some(cores) = pin_cores.clone() {
+        builder = builder.start_handler(move |index| {
+            let core = cores[index % cores.len()];
+            if let Err(e) = pin_current_thread(core) {
+                warn!(
+                    "Could not pin the {} thread {} to core {}: {}",
+                    name, index, core, e
+                );
+            }
+        });
+    }
+    Ok(Some(builder.build().kind(EdgerunnerError::Device)?))
+}
+
+fn pinning_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> std::io::Result<()> {
+    if core >= MAX_CORES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the core is beyond the cpu set",
+        ));
+    }
+    // SAFETY: the cpu set is initialized by CPU_ZERO before use and only read by the syscall
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "core pinning is only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_core_list() {
+        assert_eq!(parse_core_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert_eq!(parse_core_list(" 2 ").unwrap(), vec![2]);
+        assert!(parse_core_list("3-1").is_err());
+        assert!(parse_core_list("a").is_err());
+        assert!(parse_core_list("").is_err());
+    }
+
+    #[test]
+    fn test_unavailable_cores_are_rejected() {
+        assert!(parse_core_list("2048").is_err());
+        assert!(parse_core_list("0-18446744073709551615").is_err());
+        if let Some(allowed) = allowed_cores() {
+            let outside = (0..MAX_CORES).find(|core| !allowed.contains(core));
+            if let Some(core) = outside {
+                assert!(ThreadPools::new(&ThreadConfig {
+                    pin_cores: Some(vec![core]),
+                    ..ThreadConfig::default()
+                })
+                .is_err());
+            }
+        }
+        assert!(ThreadPools::new(&ThreadConfig {
+            pin_cores: Some(vec![MAX_CORES]),
+            ..ThreadConfig::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_thread_pools_use_configured_counts() {
+        let pools = ThreadPools::new(&ThreadConfig {
+            prefill_threads: Some(2),
+            decode_threads: Some(1),
+            pin_cores: None,
+        })
+        .unwrap();
+        assert_eq!(pools.effective().prefill_threads, 2);
+        assert_eq!(pools.effective().decode_threads, 1);
+        assert_eq!(pools.prefill(rayon::current_num_threads), 2);
+        assert_eq!(pools.decode(rayon::current_num_threads), 1);
+    }
+
+    #[test]
+    fn test_thread_pools_default_to_global_pool() {
+        let pools = ThreadPools::default();
+        assert_eq!(
+            pools.effective().decode_threads,
+            rayon::current_num_threads()
+        );
+        assert!(ThreadPools::new(&ThreadConfig {
+            decode_threads: Some(0),
+            ..ThreadConfig::default()
+        })
+        .is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pinned_threads_run_on_their_core() {
+        // cpusets may leave out core 0
+        let first = allowed_cores().unwrap()[0];
+        let pools = ThreadPools::new(&ThreadConfig {
+            pin_cores: Some(vec![first]),
+            ..ThreadConfig::default()
+        })
+        .unwrap();
+        assert_eq!(pools.effective().decode_threads, 1);
+        // SAFETY: sched_getcpu has no preconditions
+        let core = pools.decode(|| unsafe { libc::sched_getcpu() });
+        assert_eq!(core, first as i32);
+    }
+}
@@ -1,12 +1,25 @@
-use candle_core::Result;
+use crate::error::{EdgerunnerError, Result};
+
+/// What byte fallback and byte level decoders produce for an incomplete UTF-8 sequence.
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+///
+/// Only a small window of tokens is decoded for every new token, once: the tokens of the
+/// previous chunk, whose emitted text is cached, and the tokens that were not emitted yet. Text
+/// is emitted as soon as it ends with a complete unicode scalar, tokens holding the first bytes
+/// of a multi-byte character are kept back until the rest of its bytes arrive. A literal
+/// replacement character at the end of the text is kept back as well until the next token or
+/// [`Self::decode_rest`].
 pub struct TokenOutputStream {
     tokenizer: tokenizers::Tokenizer,
     tokens: Vec<u32>,
     prev_index: usize,
     current_index: usize,
+    /// The text emitted for `tokens[prev_index..current_index]`, without the decoder's stripping
+    /// of the window start.
+    prev_text: String,
 }
 
 impl TokenOutputStream {
@@ -16,6 +29,7 @@ impl TokenOutputStream {
             tokens: Vec::new(),
             prev_index: 0,
             current_index: 0,
+            prev_text: String::new(),
         }
     }
 
@@ -26,44 +40,64 @@ impl TokenOutputStream {
     fn decode(&self, tokens: &[u32]) -> Result<String> {
         match self.tokenizer.decode(tokens, true) {
             Ok(str) => Ok(str),
-            Err(err) => candle_core::bail!("cannot decode: {err}"),
+            Err(err) => Err(EdgerunnerError::Tokenizer(anyhow::Error::msg(format!(
+                "cannot decode: {err}"
+            )))),
         }
     }
 
+    /// The text added by the pending tokens, if the window decodes to an extension of the
+    /// previous chunk.
+    ///
+    /// Decoders such as sentencepiece's strip the leading whitespace of the window, which the
+    /// previous chunk kept when it was emitted after other text.
+    fn pending_text<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let prev_text = self.prev_text.as_str();
+        let trimmed = prev_text.len() - prev_text.trim_start().len();
+        (0..=trimmed)
+            .filter(|&i| prev_text.is_char_boundary(i))
+            .find_map(|i| text.strip_prefix(&prev_text[i..]))
+            .filter(|new_text| !new_text.is_empty())
+    }
+
     // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
     pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
         self.tokens.push(token);
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_ascii() {
-            let text = text.split_at(prev_text.len());
-            self.prev_index = self.current_index;
-            self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
+        // wait for the remaining bytes of a character split over several byte tokens
+        if text.ends_with(REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+        Ok(self.emit(text))
+    }
+
+    /// Marks the text added by the pending tokens of the window `text` as emitted.
+    fn emit(&mut self, text: String) -> Option<String> {
+        let new_text = self.pending_text(&text)?.to_string();
+        if new_text.trim_start().is_empty() {
+            // stripped whitespace would make the chunk ambiguous, the window keeps its start
+            self.prev_text = text;
         } else {
-            Ok(None)
+            self.prev_index = self.current_index;
+            self.prev_text.clone_from(&new_text);
         }
+        self.current_index = self.tokens.len();
+        Some(new_text)
     }
 
     pub fn decode_rest(&self) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() {
-            let text = text.split_at(prev_text.len());
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
+        Ok(self.pending_text(&text).map(str::to_string))
+    }
+
+    /// Like [`Self::decode_rest`], but the next tokens continue after the returned text instead
+    /// of emitting it again. An incomplete character stays pending.
+    pub fn flush_rest(&mut self) -> Result<Option<String>> {
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.ends_with(REPLACEMENT_CHARACTER) {
+            return Ok(self.pending_text(&text).map(str::to_string));
         }
+        Ok(self.emit(text))
     }
 
     pub fn decode_all(&self) -> Result<String> {
@@ -82,5 +116,6 @@ impl TokenOutputStream {
         self.tokens.clear();
         self.prev_index = 0;
         self.current_index = 0;
+        self.prev_text.clear();
     }
 }
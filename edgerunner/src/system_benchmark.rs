@@ -1,17 +1,23 @@
-use anyhow::Result;
-use candle_core::{Device, Tensor};
+use crate::error::{EdgerunnerError, Result, ResultExt};
+use candle_core::{
+    quantized::{GgmlDType, QMatMul, QTensor},
+    DType, Device, Module, Tensor,
+};
 use log::debug;
-use rand::Rng;
+
+use crate::runner::device::{available_devices, resolve_device, DeviceSpec};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::StandardNormal;
 use serde::Serialize;
 use std::{fmt, time::Instant};
 
 const WARMUP_ITERATIONS: usize = 5;
-pub(crate) trait BenchDevice {
+pub trait BenchDevice {
     fn sync(&self) -> Result<()>;
     fn name(&self) -> DeviceName;
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
 pub enum DeviceName {
     CPU,
     GPU,
@@ -32,15 +38,21 @@ impl BenchDevice for Device {
             Device::Cpu => Ok(()),
             Device::Cuda(device) => {
                 #[cfg(feature = "cuda")]
-                return Ok(device.synchronize()?);
+                return device.synchronize().kind(EdgerunnerError::Device);
                 #[cfg(not(feature = "cuda"))]
-                panic!("Cuda device without cuda feature enabled: {:?}", device)
+                Err(EdgerunnerError::Device(anyhow::anyhow!(
+                    "Cuda device without cuda feature enabled: {:?}",
+                    device
+                )))
             }
             Device::Metal(device) => {
                 #[cfg(feature = "metal")]
-                return Ok(device.wait_until_completed()?);
+                return device.wait_until_completed().kind(EdgerunnerError::Device);
                 #[cfg(not(feature = "metal"))]
-                panic!("Metal device without metal feature enabled: {:?}", device)
+                Err(EdgerunnerError::Device(anyhow::anyhow!(
+                    "Metal device without metal feature enabled: {:?}",
+                    device
+                )))
             }
         }
     }
@@ -59,70 +71,306 @@ pub struct BenchDeviceHandler {
 }
 
 impl BenchDeviceHandler {
+    /// The first available gpu, if any, followed by the cpu.
     pub fn new() -> Result<Self> {
         let mut devices = Vec::new();
-        if cfg!(all(
-            target_os = "macos",
-            target_arch = "aarch64",
-            feature = "metal"
-        )) {
-            devices.push(Device::new_metal(0)?);
-        } else if cfg!(feature = "cuda") {
-            devices.push(Device::new_cuda(0)?);
+        if let Some(gpu) = available_devices()
+            .into_iter()
+            .find(|spec| *spec != DeviceSpec::Cpu)
+        {
+            devices.push(resolve_device(gpu));
         }
         devices.push(Device::Cpu);
         Ok(Self { devices })
     }
 }
 
-pub fn run_benchmark(device: Device) -> Result<f64> {
-    debug!("Running benchmark...");
+/// Sizes and settings of the system microbenchmarks. The sizes are fixed and the tensor data
+/// is drawn from `seed`, so that reports of different runs and machines are comparable.
+#[derive(Clone, Debug)]
+pub struct BenchmarkConfig {
+    pub seed: u64,
+    /// The side of the square f32 matmul used to estimate the TFLOPS.
+    pub matmul_size: usize,
+    /// The `(out_features, in_features)` of the quantized weights, `in_features` must be a
+    /// multiple of the k-quants block size (256).
+    pub qmatmul_weights: (usize, usize),
+    /// The rows of the activations multiplied with the quantized weights, 1 matches decoding.
+    pub qmatmul_batch: usize,
+    pub quantized_dtypes: Vec<GgmlDType>,
+    /// The size of the buffer copied to measure the memory bandwidth.
+    pub bandwidth_bytes: usize,
+    /// The CPU thread counts the quantized matmul is measured with, empty to skip the scaling.
+    pub thread_counts: Vec<usize>,
+    pub iterations: usize,
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> Self {
+        let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut thread_counts = std::iter::successors(Some(1), |n| Some(n * 2))
+            .take_while(|n| *n < max_threads)
+            .collect::<Vec<_>>();
+        thread_counts.push(max_threads);
+
+        Self {
+            seed: 299792458,
+            matmul_size: 2048,
+            // a mistral 7b attention projection
+            qmatmul_weights: (4096, 4096),
+            qmatmul_batch: 1,
+            quantized_dtypes: vec![
+                GgmlDType::Q4_0,
+                GgmlDType::Q4_1,
+                GgmlDType::Q5_0,
+                GgmlDType::Q5_1,
+                GgmlDType::Q8_0,
+                GgmlDType::Q2K,
+                GgmlDType::Q3K,
+                GgmlDType::Q4K,
+                GgmlDType::Q5K,
+                GgmlDType::Q6K,
+            ],
+            bandwidth_bytes: 256 << 20,
+            thread_counts,
+            iterations: 10,
+        }
+    }
+}
 
-    let mut rng = rand::thread_rng();
+/// Parses the llama.cpp style names of the quantized dtypes, e.g. `q4_0` or `q4_k`.
+pub fn parse_ggml_dtype(name: &str) -> Result<GgmlDType> {
+    let dtype = match name.to_lowercase().replace('_', "").as_str() {
+        "f32" => GgmlDType::F32,
+        "f16" => GgmlDType::F16,
+        "q40" => GgmlDType::Q4_0,
+        "q41" => GgmlDType::Q4_1,
+        "q50" => GgmlDType::Q5_0,
+        "q51" => GgmlDType::Q5_1,
+        "q80" => GgmlDType::Q8_0,
+        "q2k" => GgmlDType::Q2K,
+        "q3k" => GgmlDType::Q3K,
+        "q4k" => GgmlDType::Q4K,
+        "q5k" => GgmlDType::Q5K,
+        "q6k" => GgmlDType::Q6K,
+        _ => {
+            return Err(EdgerunnerError::config(format!(
+                "unknown quantized dtype {}",
+                name
+            )))
+        }
+    };
+    Ok(dtype)
+}
 
-    let m = rng.gen_range(3000..=5000);
-    let n = rng.gen_range(3000..=5000);
-    let p = rng.gen_range(3000..=5000);
+/// The throughput of a quantized matmul with weights of one dtype.
+#[derive(Clone, Debug, Serialize)]
+pub struct QuantizedMatMulPerformance {
+    pub dtype: String,
+    pub ms_per_matmul: f64,
+    pub gflops: f64,
+    /// How fast the quantized weights are streamed through, which bounds the decode speed.
+    pub weights_gbps: f64,
+}
 
-    let a = Tensor::randn(0f32, 1., (m, n), &device)?;
-    let b = Tensor::randn(0f32, 1., (n, p), &device)?;
+/// The quantized matmul throughput with a given number of CPU threads.
+#[derive(Clone, Debug, Serialize)]
+pub struct ThreadScaling {
+    pub threads: usize,
+    pub gflops: f64,
+    /// The throughput relative to the first thread count measured.
+    pub speedup: f64,
+}
 
-    // Warm-up
-    for _ in 0..WARMUP_ITERATIONS {
-        let _ = a.matmul(&b)?;
-    }
+#[derive(Clone, Debug, Serialize)]
+pub struct DevicePerformance {
+    pub device: DeviceName,
+    pub tflops: f64,
+    pub memory_bandwidth_gbps: f64,
+    /// Empty on devices without quantized kernels.
+    pub quantized_matmul: Vec<QuantizedMatMulPerformance>,
+    /// Only measured on the CPU.
+    pub thread_scaling: Vec<ThreadScaling>,
+}
+
+/// The results of [`run_system_benchmarks`] for every available device.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchmarkReport {
+    pub seed: u64,
+    pub matmul_size: usize,
+    pub qmatmul_weights: (usize, usize),
+    pub qmatmul_batch: usize,
+    pub devices: Vec<DevicePerformance>,
+}
 
+fn seeded_tensor(rng: &mut StdRng, shape: (usize, usize), device: &Device) -> Result<Tensor> {
+    let data = (0..shape.0 * shape.1)
+        .map(|_| rng.sample::<f32, _>(StandardNormal))
+        .collect::<Vec<_>>();
+    Ok(Tensor::from_vec(data, shape, device)?)
+}
+
+/// Times `iterations` runs of `f` after warming up, returns the mean duration in seconds.
+fn time_iterations(
+    device: &Device,
+    iterations: usize,
+    mut f: impl FnMut() -> Result<()>,
+) -> Result<f64> {
+    for _ in 0..WARMUP_ITERATIONS.min(iterations.max(1)) {
+        f()?;
+    }
     device.sync()?;
 
     let start = Instant::now();
-    // Perform matrix multiplication
-    let _c = a.matmul(&b)?;
+    for _ in 0..iterations.max(1) {
+        f()?;
+    }
     device.sync()?;
-    let duration = start.elapsed();
+    Ok(start.elapsed().as_secs_f64() / iterations.max(1) as f64)
+}
+
+/// Estimates the TFLOPS of the device from a square f32 matmul.
+pub fn matmul_tflops(device: &Device, config: &BenchmarkConfig) -> Result<f64> {
+    debug!("Running benchmark...");
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let size = config.matmul_size;
+    let a = seeded_tensor(&mut rng, (size, size), device)?;
+    let b = seeded_tensor(&mut rng, (size, size), device)?;
 
-    debug!("Time taken for matrix multiplication: {:?}", duration);
+    // a single matmul is timed like the original estimate, large ones take long enough
+    let time_in_seconds = time_iterations(device, 1, || {
+        a.matmul(&b)?;
+        Ok(())
+    })?;
+    debug!(
+        "Time taken for matrix multiplication: {:.4}s",
+        time_in_seconds
+    );
 
     // Estimate the number of operations: 2 * m * n * p
-    let ops = 2 * m * n * p;
+    let ops = 2 * size * size * size;
+    let tflops = ops as f64 / time_in_seconds / 1e12;
+    debug!("Estimated performance: {:.2} TFLOPS", tflops);
 
-    // Convert time to seconds and calculate FLOPS
-    let time_in_seconds = duration.as_secs_f64();
-    let flops = (ops as f64) / time_in_seconds;
+    Ok((tflops * 100.).round() / 100.)
+}
 
-    debug!("flops: {:.2}", flops);
+/// Measures the bandwidth of copying a buffer on the device, counting the bytes read and written.
+pub fn memory_bandwidth_gbps(device: &Device, config: &BenchmarkConfig) -> Result<f64> {
+    let elements = config.bandwidth_bytes / std::mem::size_of::<f32>();
+    let src = Tensor::ones(elements, DType::F32, device)?;
+    let secs = time_iterations(device, config.iterations, || {
+        src.copy()?;
+        Ok(())
+    })?;
+    Ok(2. * (elements * std::mem::size_of::<f32>()) as f64 / secs / 1e9)
+}
 
-    // Convert to TFLOPS
-    let tflops = flops / 1e12;
-    let formated_tflops = format!("{:.2}", tflops);
+/// Measures a matmul of seeded activations with seeded weights quantized to `dtype`.
+pub fn quantized_matmul(
+    device: &Device,
+    config: &BenchmarkConfig,
+    dtype: GgmlDType,
+) -> Result<QuantizedMatMulPerformance> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let (out_features, in_features) = config.qmatmul_weights;
+    let weights = seeded_tensor(&mut rng, (out_features, in_features), device)?;
+    let weights = QTensor::quantize(&weights, dtype)?;
+    let weights_bytes = weights.storage_size_in_bytes();
+    let matmul = QMatMul::from_qtensor(weights)?;
+    let xs = seeded_tensor(&mut rng, (config.qmatmul_batch, in_features), device)?;
 
-    debug!("Estimated performance: {:.2} TFLOPS", tflops);
+    let secs = time_iterations(device, config.iterations, || {
+        matmul.forward(&xs)?;
+        Ok(())
+    })?;
+    let ops = 2 * config.qmatmul_batch * out_features * in_features;
+    Ok(QuantizedMatMulPerformance {
+        dtype: format!("{:?}", dtype),
+        ms_per_matmul: secs * 1e3,
+        gflops: ops as f64 / secs / 1e9,
+        weights_gbps: weights_bytes as f64 / secs / 1e9,
+    })
+}
+
+/// Measures the first quantized dtype on the CPU with every configured thread count.
+pub fn thread_scaling(config: &BenchmarkConfig) -> Result<Vec<ThreadScaling>> {
+    let dtype = match config.quantized_dtypes.first() {
+        Some(dtype) => *dtype,
+        None => return Ok(Vec::new()),
+    };
 
-    Ok(formated_tflops.parse().unwrap())
+    let mut results: Vec<ThreadScaling> = Vec::with_capacity(config.thread_counts.len());
+    for &threads in &config.thread_counts {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .kind(EdgerunnerError::Device)?;
+        let gflops = pool
+            .install(|| quantized_matmul(&Device::Cpu, config, dtype))?
+            .gflops;
+        let speedup = results.first().map_or(1., |first| gflops / first.gflops);
+        debug!("{threads} threads: {gflops:.2} GFLOPS, {speedup:.2}x");
+        results.push(ThreadScaling {
+            threads,
+            gflops,
+            speedup,
+        });
+    }
+    Ok(results)
 }
 
-pub struct DevicePerformance {
-    pub device: DeviceName,
-    pub tflops: f64,
+/// Runs all the microbenchmarks on one device, the quantized ones are skipped on devices
+/// without quantized kernels.
+pub fn device_performance(device: &Device, config: &BenchmarkConfig) -> Result<DevicePerformance> {
+    let tflops = matmul_tflops(device, config)?;
+    let memory_bandwidth_gbps = memory_bandwidth_gbps(device, config)?;
+
+    let mut quantized_matmul = Vec::with_capacity(config.quantized_dtypes.len());
+    for &dtype in &config.quantized_dtypes {
+        match self::quantized_matmul(device, config, dtype) {
+            Ok(performance) => quantized_matmul.push(performance),
+            Err(e) => {
+                debug!("Skipping quantized benchmarks on {}: {}", device.name(), e);
+                break;
+            }
+        }
+    }
+
+    let thread_scaling = match device {
+        Device::Cpu => thread_scaling(config)?,
+        _ => Vec::new(),
+    };
+
+    Ok(DevicePerformance {
+        device: device.name(),
+        tflops,
+        memory_bandwidth_gbps,
+        quantized_matmul,
+        thread_scaling,
+    })
+}
+
+pub fn run_system_benchmarks(config: &BenchmarkConfig) -> Result<BenchmarkReport> {
+    let handler = BenchDeviceHandler::new()?;
+    let devices = handler
+        .devices
+        .iter()
+        .map(|device| device_performance(device, config))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(BenchmarkReport {
+        seed: config.seed,
+        matmul_size: config.matmul_size,
+        qmatmul_weights: config.qmatmul_weights,
+        qmatmul_batch: config.qmatmul_batch,
+        devices,
+    })
+}
+
+pub fn run_benchmark(device: Device) -> Result<f64> {
+    matmul_tflops(&device, &BenchmarkConfig::default())
 }
 
 pub fn estimate_tflops() -> Result<Vec<(DeviceName, f64)>> {
@@ -138,3 +386,61 @@ pub fn estimate_tflops() -> Result<Vec<(DeviceName, f64)>> {
 
     Ok(tflops_results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_config() -> BenchmarkConfig {
+        BenchmarkConfig {
+            seed: 42,
+            matmul_size: 64,
+            qmatmul_weights: (64, 256),
+            qmatmul_batch: 1,
+            quantized_dtypes: vec![GgmlDType::Q4_0, GgmlDType::Q4K, GgmlDType::Q8_0],
+            bandwidth_bytes: 1 << 16,
+            thread_counts: vec![1, 2],
+            iterations: 2,
+        }
+    }
+
+    #[test]
+    fn test_device_performance_on_cpu() {
+        let performance = device_performance(&Device::Cpu, &small_config()).unwrap();
+        assert_eq!(performance.device, DeviceName::CPU);
+        assert!(performance.memory_bandwidth_gbps > 0.);
+
+        let dtypes = performance
+            .quantized_matmul
+            .iter()
+            .map(|q| q.dtype.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(dtypes, ["Q4_0", "Q4K", "Q8_0"]);
+        assert!(performance.quantized_matmul.iter().all(|q| q.gflops > 0.));
+
+        let threads = performance
+            .thread_scaling
+            .iter()
+            .map(|t| t.threads)
+            .collect::<Vec<_>>();
+        assert_eq!(threads, [1, 2]);
+        assert_eq!(performance.thread_scaling[0].speedup, 1.);
+    }
+
+    #[test]
+    fn test_parse_ggml_dtype() {
+        assert_eq!(parse_ggml_dtype("q4_0").unwrap(), GgmlDType::Q4_0);
+        assert_eq!(parse_ggml_dtype("Q4_K").unwrap(), GgmlDType::Q4K);
+        assert_eq!(parse_ggml_dtype("q6k").unwrap(), GgmlDType::Q6K);
+        assert!(parse_ggml_dtype("q9_0").is_err());
+    }
+
+    #[test]
+    fn test_quantized_matmul_rejects_unaligned_weights() {
+        let config = BenchmarkConfig {
+            qmatmul_weights: (64, 100),
+            ..small_config()
+        };
+        assert!(quantized_matmul(&Device::Cpu, &config, GgmlDType::Q4K).is_err());
+    }
+}
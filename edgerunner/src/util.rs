@@ -9,3 +9,40 @@ pub fn format_size(size_in_bytes: usize) -> String {
         format!("{:.2}GB", size_in_bytes as f64 / 1e9)
     }
 }
+
+const FNV_PRIME: u64 = 0x100000001b3;
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// Continues an FNV-1a `hash` over `bytes`, stable across runs and platforms unlike the std
+/// hasher. Hashes start from [`FNV_OFFSET_BASIS`].
+pub fn fnv1a(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(hash, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// The peak resident set size of the process, only available on linux.
+pub fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+/// Reads a `/proc/meminfo` entry in bytes, only available on linux.
+fn meminfo_bytes(key: &str) -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with(key))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+/// The total memory of the machine, only available on linux.
+pub fn total_memory_bytes() -> Option<u64> {
+    meminfo_bytes("MemTotal:")
+}
+
+/// The memory available to start new processes without swapping, only available on linux.
+pub fn available_memory_bytes() -> Option<u64> {
+    meminfo_bytes("MemAvailable:")
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::tiny_engine;
+use edgerunner::{conf::model::SamplingParams, runner::beam_search::BeamSearchConfig};
+
+fn beams(engine: &edgerunner::engine::Engine, config: BeamSearchConfig) -> SamplingParams {
+    SamplingParams {
+        sample_len: 6,
+        temperature: None,
+        repeat_penalty: 1.,
+        beam_search: Some(config),
+        ..engine.params()
+    }
+}
+
+#[test]
+fn test_a_single_beam_is_greedy() {
+    let mut engine = tiny_engine();
+    let params = beams(
+        &engine,
+        BeamSearchConfig {
+            beam_width: 1,
+            ..Default::default()
+        },
+    );
+    let greedy = SamplingParams {
+        beam_search: None,
+        ..params.clone()
+    };
+    let expected = engine.generate("the model is", &greedy).unwrap();
+    let generation = engine.generate("the model is", &params).unwrap();
+    assert_eq!(generation.text, expected.text);
+    assert_eq!(generation.generated_tokens, expected.generated_tokens);
+    assert!((generation.logprob - expected.logprob).abs() < 1e-4);
+    assert_eq!(generation.beams.len(), 1);
+    assert!(expected.beams.is_empty());
+}
+
+#[test]
+fn test_beam_search_returns_the_top_beams() {
+    let mut engine = tiny_engine();
+    let params = beams(
+        &engine,
+        BeamSearchConfig {
+            beam_width: 3,
+            no_repeat_ngram_size: 2,
+            ..Default::default()
+        },
+    );
+    let generation = engine.generate("the model is", &params).unwrap();
+    assert_eq!(generation.beams.len(), 3);
+    assert_eq!(generation.text, generation.beams[0].text);
+    assert_eq!(generation.finish_reason, generation.beams[0].finish_reason);
+    for pair in generation.beams.windows(2) {
+        assert!(pair[0].score >= pair[1].score);
+        assert_ne!(pair[0].tokens, pair[1].tokens);
+    }
+    for beam in &generation.beams {
+        for (i, bigram) in beam.tokens.windows(2).enumerate() {
+            assert!(!beam.tokens[i + 1..].windows(2).any(|other| other == bigram));
+        }
+    }
+
+    // the search is deterministic and does not sample n completions
+    let again = engine.generate("the model is", &params).unwrap();
+    assert_eq!(again.beams, generation.beams);
+    assert!(engine.generate_n("the model is", 2, &params).is_err());
+}
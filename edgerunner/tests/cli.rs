@@ -0,0 +1,592 @@
+mod common;
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use common::{fixture_tokenizer_path, tiny_gguf_path};
+
+#[test]
+fn test_cli_generates_with_local_model() {
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--sample-len", "8", "--skip-benchmark"])
+        .args(["--prompt", "hello", "world"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("prompt tokens processed"), "{stdout}");
+    assert!(stdout.contains("tokens generated"), "{stdout}");
+    assert!(!stdout.contains("TFLOPS"), "{stdout}");
+    // the library logs instead of printing
+    assert!(!stdout.contains("Prompt:"), "{stdout}");
+    assert!(!stdout.contains("tensors"), "{stdout}");
+}
+
+#[test]
+fn test_cli_falls_back_to_cpu_for_missing_device() {
+    if candle_core::utils::cuda_is_available() {
+        return;
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args([
+            "--device",
+            "cuda:0",
+            "--sample-len",
+            "4",
+            "--skip-benchmark",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("tokens generated"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("falling back to cpu"));
+}
+
+#[test]
+fn test_cli_rejects_unknown_model() {
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .args(["--model", "not-a-model", "--skip-benchmark"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not-a-model"));
+}
+
+fn tmp_path(name: &str) -> PathBuf {
+    std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "edgerunner-{}-{}",
+        name,
+        std::process::id()
+    ))
+}
+
+fn bench_tiny_model(history: &PathBuf) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("bench")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--prompt-lengths", "4", "--decode-tokens", "4"])
+        .args(["--repetitions", "1", "--skip-benchmark", "--history"])
+        .arg(history)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_cli_bench_reports_thread_config() {
+    let report_path = tmp_path("bench-threads.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("bench")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--prompt-lengths", "4", "--decode-tokens", "4"])
+        .args(["--repetitions", "1", "--skip-benchmark"])
+        .args([
+            "--prefill-threads",
+            "2",
+            "--decode-threads",
+            "1",
+            "--pin-cores",
+            "0",
+        ])
+        .arg("--report")
+        .arg(&report_path)
+        .arg("--history")
+        .arg(tmp_path("bench-threads-history.jsonl"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_path).unwrap()).unwrap();
+    let threads = &report["inference"]["threads"];
+    assert_eq!(threads["prefill_threads"], 2);
+    assert_eq!(threads["decode_threads"], 1);
+    if cfg!(target_os = "linux") {
+        assert_eq!(threads["pinned_cores"], serde_json::json!([0]));
+    }
+}
+
+fn compare_runs(history: &PathBuf, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .args(["bench", "compare"])
+        .args(args)
+        .arg("--history")
+        .arg(history)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_cli_bench_writes_json_report() {
+    let report_path = tmp_path("bench.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("bench")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--prompt-lengths", "4,16", "--decode-tokens", "8"])
+        .args(["--repetitions", "3", "--skip-benchmark", "--report"])
+        .arg(&report_path)
+        .arg("--history")
+        .arg(tmp_path("bench-report-history.jsonl"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_path).unwrap()).unwrap();
+    assert_eq!(report["architecture"], "Llama");
+    assert_eq!(report["device"], "CPU");
+    assert!(report["system"].is_null());
+    assert_eq!(report["inference"]["repetitions"], 3);
+
+    let prefill = report["inference"]["prefill"].as_array().unwrap();
+    assert_eq!(prefill.len(), 2);
+    assert_eq!(prefill[1]["prompt_tokens"], 16);
+    assert!(prefill[1]["tokens_per_second"]["p50"].as_f64().unwrap() > 0.);
+    assert!(
+        prefill[1]["time_to_first_token_ms"]["p95"]
+            .as_f64()
+            .unwrap()
+            > 0.
+    );
+
+    let decode = &report["inference"]["decode"];
+    assert_eq!(decode["prompt_tokens"], 4);
+    assert_eq!(decode["generated_tokens"], 8);
+    assert!(decode["tokens_per_second"]["mean"].as_f64().unwrap() > 0.);
+}
+
+#[test]
+fn test_cli_bench_rejects_prompts_beyond_context() {
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("bench")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--prompt-lengths", "4096", "--skip-benchmark"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(9));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error [context_overflow]"), "{stderr}");
+}
+
+#[test]
+fn test_cli_exits_with_load_error_code() {
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tmp_path("missing.gguf"))
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .arg("--skip-benchmark")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error [load]"), "{stderr}");
+}
+
+fn embed_tiny_model(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("embed")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_cli_embeds_to_jsonl_and_npy() {
+    let input = tmp_path("embed-input.txt");
+    std::fs::write(&input, "hello world\n\nfast\n").unwrap();
+    let output = embed_tiny_model(&["hello", "--input", input.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    let lines = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[2]["index"], 2);
+    assert_eq!(lines[0]["embedding"].as_array().unwrap().len(), 32);
+
+    let npy = tmp_path("embed.npy");
+    let output = embed_tiny_model(&[
+        "hello",
+        "world",
+        "--pooling",
+        "last-token",
+        "--format",
+        "npy",
+        "--output",
+        npy.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let npy = std::fs::read(npy).unwrap();
+    assert!(String::from_utf8_lossy(&npy).contains("'shape': (2, 32)"));
+
+    let output = embed_tiny_model(&["hello", "--format", "npy"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_eval_reports_perplexity() {
+    let input = tmp_path("eval-input.txt");
+    std::fs::write(&input, "the tiny model is small and fast on the local cpu").unwrap();
+    let report_path = tmp_path("eval-report.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("eval")
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .arg("--input")
+        .arg(&input)
+        .args(["--window", "6", "--stride", "3", "--report"])
+        .arg(&report_path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("perplexity"), "{stdout}");
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_path).unwrap()).unwrap();
+    assert_eq!(report["eval"]["tokens"], 10);
+    assert_eq!(report["eval"]["windows"], 3);
+    assert_eq!(report["eval"]["token_losses"].as_array().unwrap().len(), 10);
+    assert!(report["eval"]["perplexity"].as_f64().unwrap() > 1.);
+}
+
+fn generate_tiny_model(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--skip-benchmark", "--prompt", "hello", "world"])
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "{stdout}");
+    // the response comes before the stats
+    stdout.split("\n\n").next().unwrap().to_string()
+}
+
+#[test]
+fn test_cli_saves_and_loads_sessions() {
+    let session = tmp_path("cli.session");
+    let session = session.to_str().unwrap();
+    let full = generate_tiny_model(&["--sample-len", "8"]);
+    let first = generate_tiny_model(&["--sample-len", "5", "--save-session", session]);
+    let rest = generate_tiny_model(&["--sample-len", "3", "--load-session", session]);
+    assert_eq!(format!("{first}{rest}"), full);
+}
+
+#[test]
+fn test_cli_streams_json_events() {
+    let text = generate_tiny_model(&["--sample-len", "6"]);
+    assert_eq!(
+        generate_tiny_model(&["--sample-len", "6", "--quiet"]),
+        format!("{text}\n")
+    );
+
+    let stream = generate_tiny_model(&["--sample-len", "6", "--output", "jsonl-stream"]);
+    let events = stream
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let (summary, deltas) = events.split_last().unwrap();
+    // one event per generated token, the text held back until the end comes on its own
+    let tokens = deltas
+        .iter()
+        .take_while(|event| event["event"] == "token")
+        .collect::<Vec<_>>();
+    let indices = tokens.iter().map(|token| token["index"].as_u64().unwrap());
+    assert!(indices.eq(0..6));
+    assert!(tokens.iter().all(|token| token["id"].is_u64()));
+    assert!(deltas[tokens.len()..]
+        .iter()
+        .all(|event| event["event"] == "text"));
+    let streamed = deltas
+        .iter()
+        .map(|event| event["delta"].as_str().unwrap())
+        .collect::<String>();
+    assert_eq!(streamed, text);
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["text"], text.as_str());
+    assert_eq!(summary["generated_tokens"], 6);
+    assert_eq!(summary["finish_reason"], "length");
+
+    let json = generate_tiny_model(&["--sample-len", "6", "--output", "json"]);
+    let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    assert_eq!(json["text"], text.as_str());
+    assert!(json["tokens_per_second"].as_f64().unwrap() > 0.);
+}
+
+/// The text and prompt length of a generation of the tiny model, with `stdin` piped in.
+fn summarize_tiny_model(args: &[&str], stdin: &str) -> serde_json::Value {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--skip-benchmark", "--sample-len", "4", "--output", "json"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    // the timings differ between runs
+    serde_json::json!({"text": summary["text"], "prompt_tokens": summary["prompt_tokens"]})
+}
+
+#[test]
+fn test_cli_reads_prompts_from_stdin_files_and_templates() {
+    let expected = summarize_tiny_model(&["--prompt", "hello", "world"], "");
+    assert_eq!(summarize_tiny_model(&[], "hello world\n"), expected);
+    assert_eq!(
+        summarize_tiny_model(&["--prompt-file", "-"], "hello world\n"),
+        expected
+    );
+
+    let prompt = tmp_path("prompt.txt");
+    std::fs::write(&prompt, "{{greeting}} {{ place }}\n").unwrap();
+    let vars = tmp_path("vars.json");
+    std::fs::write(&vars, r#"{"greeting": "goodbye", "place": "world"}"#).unwrap();
+    let templated = summarize_tiny_model(
+        &[
+            "--prompt-file",
+            prompt.to_str().unwrap(),
+            "--vars",
+            vars.to_str().unwrap(),
+            "--var",
+            "greeting=hello",
+        ],
+        "ignored when a prompt is given",
+    );
+    assert_eq!(templated, expected);
+
+    // the system prompt goes into the chat prompt
+    let system = summarize_tiny_model(&["--prompt", "hello", "--system", "the", "model"], "");
+    let without = summarize_tiny_model(&["--prompt", "hello"], "");
+    assert!(system["prompt_tokens"].as_u64() > without["prompt_tokens"].as_u64());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .args([
+            "--skip-benchmark",
+            "--prompt",
+            "{{missing}}",
+            "--var",
+            "a=b",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no value for {{missing}}"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cli_does_not_wait_on_a_stdin_that_is_not_piped() {
+    // like a socket a parent process keeps open without writing to it
+    let (stdin, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+        .arg("--model-path")
+        .arg(tiny_gguf_path())
+        .arg("--tokenizer")
+        .arg(fixture_tokenizer_path())
+        .args(["--skip-benchmark", "--sample-len", "2", "--quiet"])
+        .stdin(std::os::fd::OwnedFd::from(stdin))
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = std::time::Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > std::time::Duration::from_secs(60) {
+            child.kill().unwrap();
+            panic!("the cli waits on stdin");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_cli_rag_ingests_and_answers_with_sources() {
+    let docs = tmp_path("rag-cli-docs");
+    std::fs::create_dir_all(&docs).unwrap();
+    std::fs::write(docs.join("a.md"), "hello world").unwrap();
+    let index = tmp_path("rag-cli-index.json");
+    let rag = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_edgerunner"))
+            .arg("rag")
+            .args(args)
+            .arg("--index")
+            .arg(&index)
+            .arg("--model-path")
+            .arg(tiny_gguf_path())
+            .arg("--tokenizer")
+            .arg(fixture_tokenizer_path())
+            .output()
+            .unwrap()
+    };
+
+    let output = rag(&["ingest", docs.to_str().unwrap()]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Indexed 1 chunks"), "{stdout}");
+
+    let output = rag(&["ask", "hello", "world", "--sample-len", "4"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Sources:\n[1] a.md#0"), "{stdout}");
+}
+
+#[test]
+fn test_cli_bench_records_history() {
+    let history = tmp_path("bench-history.jsonl");
+    let _ = std::fs::remove_file(&history);
+    assert!(bench_tiny_model(&history).status.success());
+    assert!(bench_tiny_model(&history).status.success());
+
+    let records = std::fs::read_to_string(&history).unwrap();
+    let records = records
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["kind"], "bench");
+    assert_eq!(records[0]["device"], "CPU");
+    assert_eq!(records[0]["crate_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(records[0]["settings"]["decode_tokens"], 4);
+    assert!(records[0]["metrics"]["decode.tokens_per_second"]["value"]
+        .as_f64()
+        .is_some());
+
+    // timings of debug builds are noisy, only check the comparison runs
+    let output = compare_runs(&history, &["--threshold", "1000000"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("decode.tokens_per_second"), "{stdout}");
+}
+
+#[test]
+fn test_cli_bench_compare_fails_on_regression() {
+    let history = tmp_path("bench-regression.jsonl");
+    let record = |decode: f64| {
+        serde_json::json!({
+            "timestamp": "2024-01-01T00:00:00Z",
+            "crate_version": "0.1.0",
+            "kind": "bench",
+            "device": "CPU",
+            "model": "model.gguf",
+            "settings": {},
+            "metrics": {
+                "decode.tokens_per_second": { "value": decode, "higher_is_better": true }
+            }
+        })
+        .to_string()
+    };
+    std::fs::write(&history, [record(10.), record(9.), record(10.5)].join("\n")).unwrap();
+
+    let output = compare_runs(&history, &["1", "2"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("REGRESSION"));
+
+    let output = compare_runs(&history, &["--threshold", "5"]);
+    assert!(output.status.success());
+
+    let output = compare_runs(&history, &["1", "4"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_cli_bench_compare_fails_on_unrelated_runs() {
+    let history = tmp_path("bench-unrelated.jsonl");
+    let record = |kind: &str, metric: &str| {
+        serde_json::json!({
+            "timestamp": "2024-01-01T00:00:00Z",
+            "crate_version": "0.1.0",
+            "kind": kind,
+            "device": "CPU",
+            "model": "model.gguf",
+            "settings": {},
+            "metrics": {
+                metric: { "value": 10., "higher_is_better": true }
+            }
+        })
+        .to_string()
+    };
+    std::fs::write(
+        &history,
+        [
+            record("bench", "decode.tokens_per_second"),
+            record("bench kernels", "cpu.tflops"),
+            record("bench", "prefill.16.tokens_per_second"),
+        ]
+        .join("\n"),
+    )
+    .unwrap();
+
+    // the default baseline skips the kernels run but shares no metrics with the last run
+    let output = compare_runs(&history, &[]);
+    assert!(!output.status.success());
+    let output = compare_runs(&history, &["2", "3"]);
+    assert!(!output.status.success());
+}
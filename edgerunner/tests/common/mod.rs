@@ -0,0 +1,396 @@
+//! Test support shared by the integration tests: a scripted model backend, a tiny randomly
+//! initialized llama GGUF written in-process, and a word level fixture tokenizer.
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use candle_core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    Device, Result, Tensor,
+};
+use edgerunner::{
+    conf::model::SamplingParams,
+    engine::{Engine, EngineBuilder},
+    model::{
+        causal_lm::CausalLm,
+        types::{ModelArchitecture, ModelMetadata},
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokenizers::Tokenizer;
+
+pub const EOS_TOKEN: &str = "</s>";
+
+pub fn fixture_tokenizer_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tokenizer.json")
+}
+
+pub fn fixture_tokenizer() -> Tokenizer {
+    Tokenizer::from_file(fixture_tokenizer_path()).unwrap()
+}
+
+pub fn token_id(tokenizer: &Tokenizer, token: &str) -> u32 {
+    tokenizer
+        .token_to_id(token)
+        .unwrap_or_else(|| panic!("{token} is not in the fixture vocabulary"))
+}
+
+pub fn token_ids(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+    text.split_whitespace()
+        .map(|t| token_id(tokenizer, t))
+        .collect()
+}
+
+/// A forward call seen by the [`ScriptedModel`], `(position, seq_len)`.
+pub type ForwardCall = (usize, usize);
+
+/// A model that deterministically emits a fixed sequence of tokens, one per forward call, and
+/// repeats the last one once the script is exhausted.
+pub struct ScriptedModel {
+    script: Vec<u32>,
+    step: usize,
+    calls: Arc<Mutex<Vec<ForwardCall>>>,
+    metadata: ModelMetadata,
+}
+
+impl ScriptedModel {
+    pub fn new(script: Vec<u32>, vocab_size: usize, context_length: usize, eos: u32) -> Self {
+        Self {
+            script,
+            step: 0,
+            calls: Arc::new(Mutex::new(Vec::new())),
+            metadata: ModelMetadata {
+                architecture: ModelArchitecture::Llama,
+                vocab_size,
+                context_length,
+                eos_token_ids: vec![eos],
+            },
+        }
+    }
+
+    /// Builds a scripted model emitting the whitespace separated `text` over the fixture vocabulary.
+    pub fn from_text(tokenizer: &Tokenizer, text: &str, context_length: usize) -> Self {
+        Self::new(
+            token_ids(tokenizer, text),
+            tokenizer.get_vocab_size(true),
+            context_length,
+            token_id(tokenizer, EOS_TOKEN),
+        )
+    }
+
+    /// Poses as another model, for instance of another architecture.
+    pub fn with_metadata(mut self, metadata: ModelMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// A handle on the recorded forward calls that stays valid once the model is boxed.
+    pub fn calls(&self) -> Arc<Mutex<Vec<ForwardCall>>> {
+        self.calls.clone()
+    }
+}
+
+impl CausalLm for ScriptedModel {
+    fn forward(&mut self, input: &Tensor, position: usize) -> Result<Tensor> {
+        let (batch, seq_len) = input.dims2()?;
+        self.calls.lock().unwrap().push((position, seq_len));
+
+        let next = self.script[self.step.min(self.script.len() - 1)];
+        self.step += 1;
+
+        let mut logits = vec![0f32; self.metadata.vocab_size];
+        logits[next as usize] = 100.;
+        Tensor::new(logits.as_slice(), input.device())?
+            .unsqueeze(0)?
+            .repeat((batch, 1))
+    }
+
+    fn reset_cache(&mut self) {
+        self.step = 0;
+    }
+
+    fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+}
+
+const TINY_EMBEDDING_LENGTH: usize = 32;
+const TINY_FEED_FORWARD_LENGTH: usize = 64;
+const TINY_HEAD_COUNT: u32 = 4;
+const TINY_HEAD_COUNT_KV: u32 = 2;
+const TINY_BLOCK_COUNT: usize = 2;
+const TINY_CONTEXT_LENGTH: u32 = 128;
+
+fn random_tensor(rng: &mut StdRng, shape: (usize, usize), dtype: GgmlDType) -> QTensor {
+    let scale = 1. / (shape.1 as f32).sqrt();
+    let data = (0..shape.0 * shape.1)
+        .map(|_| rng.gen_range(-scale..scale))
+        .collect::<Vec<_>>();
+    let tensor = Tensor::from_vec(data, shape, &Device::Cpu).unwrap();
+    QTensor::quantize(&tensor, dtype).unwrap()
+}
+
+fn ones(len: usize) -> QTensor {
+    let tensor = Tensor::ones(len, candle_core::DType::F32, &Device::Cpu).unwrap();
+    QTensor::quantize(&tensor, GgmlDType::F32).unwrap()
+}
+
+/// Writes a tiny llama GGUF with seeded random weights matching the fixture vocabulary, with
+/// `embed_vocab` the fixture words are also embedded as a sentencepiece vocabulary.
+pub fn write_tiny_gguf(path: &Path, seed: u64, embed_vocab: bool) {
+    let vocab_size = fixture_tokenizer().get_vocab_size(true);
+    let embd = TINY_EMBEDDING_LENGTH;
+    let ff = TINY_FEED_FORWARD_LENGTH;
+    let kv = embd / TINY_HEAD_COUNT as usize * TINY_HEAD_COUNT_KV as usize;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut tensors = vec![
+        (
+            "token_embd.weight".to_string(),
+            random_tensor(&mut rng, (vocab_size, embd), GgmlDType::F32),
+        ),
+        ("output_norm.weight".to_string(), ones(embd)),
+        (
+            "output.weight".to_string(),
+            random_tensor(&mut rng, (vocab_size, embd), GgmlDType::Q8_0),
+        ),
+    ];
+    for layer in 0..TINY_BLOCK_COUNT {
+        let prefix = format!("blk.{layer}");
+        let layer_tensors = [
+            ("attn_q", (embd, embd)),
+            ("attn_k", (kv, embd)),
+            ("attn_v", (kv, embd)),
+            ("attn_output", (embd, embd)),
+            ("ffn_gate", (ff, embd)),
+            ("ffn_up", (ff, embd)),
+            ("ffn_down", (embd, ff)),
+        ];
+        for (name, shape) in layer_tensors {
+            tensors.push((
+                format!("{prefix}.{name}.weight"),
+                random_tensor(&mut rng, shape, GgmlDType::Q8_0),
+            ));
+        }
+        tensors.push((format!("{prefix}.attn_norm.weight"), ones(embd)));
+        tensors.push((format!("{prefix}.ffn_norm.weight"), ones(embd)));
+    }
+
+    let eos = token_id(&fixture_tokenizer(), EOS_TOKEN);
+    let metadata = [
+        (
+            "general.architecture",
+            gguf_file::Value::String("llama".to_string()),
+        ),
+        (
+            "llama.context_length",
+            gguf_file::Value::U32(TINY_CONTEXT_LENGTH),
+        ),
+        ("llama.embedding_length", gguf_file::Value::U32(embd as u32)),
+        (
+            "llama.block_count",
+            gguf_file::Value::U32(TINY_BLOCK_COUNT as u32),
+        ),
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(TINY_HEAD_COUNT),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(TINY_HEAD_COUNT_KV),
+        ),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32(embd as u32 / TINY_HEAD_COUNT),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+        ("tokenizer.ggml.eos_token_id", gguf_file::Value::U32(eos)),
+    ];
+    let mut metadata = metadata.to_vec();
+    if embed_vocab {
+        let tokenizer = fixture_tokenizer();
+        let mut vocab = tokenizer.get_vocab(true).into_iter().collect::<Vec<_>>();
+        vocab.sort_by_key(|(_, id)| *id);
+        let tokens = vocab
+            .iter()
+            .map(|(token, id)| match *id < 3 {
+                true => gguf_file::Value::String(token.clone()),
+                false => gguf_file::Value::String(format!("▁{token}")),
+            })
+            .collect();
+        let token_types = vocab
+            .iter()
+            .map(|(_, id)| gguf_file::Value::I32(if *id < 3 { 3 } else { 1 }))
+            .collect();
+        metadata.push((
+            "tokenizer.ggml.model",
+            gguf_file::Value::String("llama".to_string()),
+        ));
+        metadata.push(("tokenizer.ggml.tokens", gguf_file::Value::Array(tokens)));
+        metadata.push((
+            "tokenizer.ggml.token_type",
+            gguf_file::Value::Array(token_types),
+        ));
+        metadata.push(("tokenizer.ggml.bos_token_id", gguf_file::Value::U32(1)));
+    }
+
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let mut file = std::fs::File::create(path).unwrap();
+    gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+}
+
+/// Writes the tiny GGUF once per test binary under cargo's integration test tmp dir.
+pub fn tiny_gguf_path() -> PathBuf {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+    PATH.get_or_init(|| {
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("edgerunner-tiny-llama-{}.gguf", std::process::id()));
+        write_tiny_gguf(&path, 42, false);
+        path
+    })
+    .clone()
+}
+
+/// Like [`tiny_gguf_path`] but the file embeds the fixture vocabulary.
+pub fn tiny_gguf_with_vocab_path() -> PathBuf {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+    PATH.get_or_init(|| {
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+            "edgerunner-tiny-llama-vocab-{}.gguf",
+            std::process::id()
+        ));
+        write_tiny_gguf(&path, 42, true);
+        path
+    })
+    .clone()
+}
+
+/// Like [`tiny_gguf_path`], with the same file name and size, but other weights.
+pub fn tiny_gguf_twin_path() -> PathBuf {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+    PATH.get_or_init(|| {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("edgerunner-twin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(tiny_gguf_path().file_name().unwrap());
+        write_tiny_gguf(&path, 43, false);
+        path
+    })
+    .clone()
+}
+
+/// Multilingual sentencepiece pieces on top of the byte tokens of [`byte_fallback_tokenizer`],
+/// anything else is encoded byte by byte.
+const BYTE_FALLBACK_PIECES: [&str; 16] = [
+    "▁",
+    "▁hello",
+    "▁world",
+    "▁the",
+    "e",
+    "é",
+    "ü",
+    "ß",
+    "日本",
+    "語",
+    "▁你好",
+    "мир",
+    "▁при",
+    "вет",
+    "🦀",
+    "ا",
+];
+
+/// A llama style sentencepiece tokenizer built from an in-memory GGUF vocabulary, with byte
+/// fallback for the characters it has no piece for.
+pub fn byte_fallback_tokenizer() -> Tokenizer {
+    let mut tokens = vec![
+        ("<unk>".to_string(), 2),
+        ("<s>".to_string(), 3),
+        ("</s>".to_string(), 3),
+    ];
+    tokens.extend((0..=255u8).map(|b| (format!("<0x{b:02X}>"), 6)));
+    tokens.extend(BYTE_FALLBACK_PIECES.iter().map(|t| (t.to_string(), 1)));
+
+    let metadata = [
+        (
+            "tokenizer.ggml.model",
+            gguf_file::Value::String("llama".to_string()),
+        ),
+        (
+            "tokenizer.ggml.tokens",
+            gguf_file::Value::Array(
+                tokens
+                    .iter()
+                    .map(|(t, _)| gguf_file::Value::String(t.clone()))
+                    .collect(),
+            ),
+        ),
+        (
+            "tokenizer.ggml.scores",
+            gguf_file::Value::Array(
+                (0..tokens.len())
+                    .map(|i| gguf_file::Value::F32(-(i as f32)))
+                    .collect(),
+            ),
+        ),
+        (
+            "tokenizer.ggml.token_type",
+            gguf_file::Value::Array(
+                tokens
+                    .iter()
+                    .map(|(_, t)| gguf_file::Value::I32(*t))
+                    .collect(),
+            ),
+        ),
+        ("tokenizer.ggml.unknown_token_id", gguf_file::Value::U32(0)),
+        ("tokenizer.ggml.bos_token_id", gguf_file::Value::U32(1)),
+        ("tokenizer.ggml.eos_token_id", gguf_file::Value::U32(2)),
+    ];
+    let content = gguf_file::Content {
+        magic: gguf_file::VersionedMagic::GgufV3,
+        metadata: metadata
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+        tensor_infos: Default::default(),
+        tensor_data_offset: 0,
+    };
+    edgerunner::model::gguf_tokenizer::tokenizer_from_gguf(&content)
+        .unwrap()
+        .unwrap()
+}
+
+/// A builder of an engine running the tiny GGUF with the fixture tokenizer, the model path and
+/// any other setting can still be changed.
+pub fn tiny_engine_builder() -> EngineBuilder {
+    Engine::builder()
+        .model_path(tiny_gguf_path())
+        .tokenizer_path(fixture_tokenizer_path())
+}
+
+/// An engine running the tiny GGUF with the fixture tokenizer.
+pub fn tiny_engine() -> Engine {
+    tiny_engine_builder().build().unwrap()
+}
+
+/// Greedily samples `sample_len` tokens, the other settings are the engine defaults.
+pub fn greedy(engine: &Engine, sample_len: usize) -> SamplingParams {
+    SamplingParams {
+        sample_len,
+        temperature: None,
+        ..engine.params()
+    }
+}
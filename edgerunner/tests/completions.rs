@@ -0,0 +1,55 @@
+mod common;
+
+use common::tiny_engine;
+use edgerunner::conf::model::SamplingParams;
+
+#[test]
+fn test_completions_share_the_prefill_and_sample_their_own_streams() {
+    let mut engine = tiny_engine();
+    let params = SamplingParams {
+        sample_len: 8,
+        temperature: Some(1.5),
+        seed: 5,
+        ..engine.params()
+    };
+    let completions = engine.generate_n("the model is", 4, &params).unwrap();
+    assert_eq!(completions.len(), 4);
+
+    // the first completion is the one a single generation samples
+    let single = engine.generate("the model is", &params).unwrap();
+    assert_eq!(completions[0].text, single.text);
+    assert!((completions[0].logprob - single.logprob).abs() < 1e-4);
+    for completion in &completions {
+        assert_eq!(completion.prompt_tokens, single.prompt_tokens);
+        assert_eq!(completion.prompt_seconds, completions[0].prompt_seconds);
+        assert!(completion.logprob < 0. && completion.logprob.is_finite());
+    }
+    let texts = completions.iter().map(|c| &c.text).collect::<Vec<_>>();
+    assert!(
+        texts.iter().skip(1).any(|text| *text != texts[0]),
+        "{texts:?}"
+    );
+
+    // the streams only depend on the seed
+    let again = engine.generate_n("the model is", 4, &params).unwrap();
+    for (again, completion) in again.iter().zip(&completions) {
+        assert_eq!(again.text, completion.text);
+        assert_eq!(again.logprob, completion.logprob);
+    }
+}
+
+#[test]
+fn test_greedy_completions_are_the_same() {
+    let mut engine = tiny_engine();
+    let params = SamplingParams {
+        sample_len: 6,
+        temperature: None,
+        ..engine.params()
+    };
+    let completions = engine.chat_n("hello world", &[], None, 3, &params).unwrap();
+    let expected = engine.chat("hello world", &[], None, &params).unwrap();
+    for completion in completions {
+        assert_eq!(completion.text, expected.text);
+        assert!((completion.logprob - expected.logprob).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,321 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use candle_core::Device;
+use common::{fixture_tokenizer, tiny_engine, ScriptedModel};
+use edgerunner::{
+    conf::model::SamplingParams,
+    engine::Engine,
+    error::EdgerunnerError,
+    model::{
+        loader::Model,
+        tools::{ChatMessage, ChatRequest, Tool, ToolChoice},
+        types::{ModelArchitecture, ModelMetadata},
+    },
+    runner::{
+        embeddings::{EmbeddingOptions, Pooling},
+        events::FinishReason,
+    },
+};
+
+fn scripted_engine(text: &str) -> Engine {
+    let tokenizer = fixture_tokenizer();
+    let model = ScriptedModel::from_text(&tokenizer, text, 64);
+    Engine::builder()
+        .build_from(Model::new(tokenizer, Box::new(model), Device::Cpu))
+        .unwrap()
+}
+
+#[test]
+fn test_engine_overrides_sampling_per_call() {
+    let mut engine = tiny_engine();
+    let short = SamplingParams {
+        sample_len: 3,
+        temperature: None,
+        ..engine.params()
+    };
+    let long = SamplingParams {
+        sample_len: 8,
+        ..short.clone()
+    };
+
+    let first = engine.generate("hello world", &short).unwrap();
+    assert_eq!(first.generated_tokens, 3);
+    assert_eq!(first.finish_reason, FinishReason::Length);
+    // the same settings give the same text on the same loaded model
+    let again = engine.generate("hello world", &short).unwrap();
+    assert_eq!(again.text, first.text);
+    let longer = engine.generate("hello world", &long).unwrap();
+    assert_eq!(longer.generated_tokens, 8);
+    assert!(longer.text.starts_with(&first.text));
+    // the defaults are untouched by the overrides
+    assert_eq!(engine.params().sample_len, 1000);
+}
+
+#[test]
+fn test_engine_chats_until_eos() {
+    let mut engine = scripted_engine("hello world </s>");
+    let streamed = std::sync::Mutex::new(String::new());
+    let reply = engine
+        .chat_stream("how does this work ?", &[], None, &engine.params(), |t| {
+            streamed.lock().unwrap().push_str(t)
+        })
+        .unwrap();
+
+    assert_eq!(reply.text, "hello world");
+    assert_eq!(streamed.into_inner().unwrap(), reply.text);
+    assert_eq!(reply.finish_reason, FinishReason::Eos);
+}
+
+#[test]
+fn test_engine_caps_generations_to_the_context() {
+    let tokenizer = fixture_tokenizer();
+    let model = ScriptedModel::from_text(&tokenizer, "hello world", 8);
+    let mut engine = Engine::builder()
+        .build_from(Model::new(tokenizer, Box::new(model), Device::Cpu))
+        .unwrap();
+    let params = SamplingParams {
+        sample_len: 20,
+        ..engine.params()
+    };
+    let generation = engine.generate("hello world the model", &params).unwrap();
+    assert_eq!(generation.generated_tokens, 8);
+    assert_eq!(generation.finish_reason, FinishReason::Length);
+
+    // the kv cache of a real model would overflow
+    let mut engine = tiny_engine();
+    let params = SamplingParams {
+        sample_len: engine.context_length() + 10,
+        ..engine.params()
+    };
+    let generations = engine.generate_n("hello world", 2, &params).unwrap();
+    assert!(generations
+        .iter()
+        .all(|g| g.generated_tokens <= engine.context_length()));
+}
+
+#[test]
+fn test_engine_lowers_the_stop_flag_for_the_next_call() {
+    let mut engine = scripted_engine("hello world </s>");
+    engine.stop_flag().store(true, Ordering::SeqCst);
+    let stopping = engine.stop_flag();
+    let result = engine.generate_stream("hello", &engine.params(), |_| {
+        stopping.store(true, Ordering::SeqCst)
+    });
+    assert!(matches!(result, Err(EdgerunnerError::Cancelled)));
+
+    assert_eq!(
+        engine.generate("hello", &engine.params()).unwrap().text,
+        "hello world"
+    );
+}
+
+#[test]
+fn test_engine_counts_tokens() {
+    let engine = scripted_engine("hello");
+    let tokens = engine.tokenize("hello world").unwrap();
+    assert_eq!(
+        tokens,
+        common::token_ids(&fixture_tokenizer(), "hello world")
+    );
+    assert_eq!(engine.count_tokens("hello world fast").unwrap(), 3);
+}
+
+#[test]
+fn test_engine_embeds_text() {
+    let mut engine = tiny_engine();
+    let options = EmbeddingOptions::default();
+    let hello = engine.embed("hello world", &options).unwrap();
+    assert_eq!(hello.len(), 32);
+    let norm = hello.iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!((norm - 1.).abs() < 1e-4, "{norm}");
+    assert_eq!(engine.embed("hello world", &options).unwrap(), hello);
+    assert_ne!(engine.embed("fast", &options).unwrap(), hello);
+
+    // embedding in between leaves the generations unchanged
+    let params = SamplingParams {
+        sample_len: 4,
+        temperature: None,
+        ..engine.params()
+    };
+    let before = engine.generate("hello", &params).unwrap().text;
+    engine.embed("world", &options).unwrap();
+    assert_eq!(engine.generate("hello", &params).unwrap().text, before);
+}
+
+#[test]
+fn test_engine_batches_embeddings() {
+    let mut engine = tiny_engine();
+    let texts = ["hello world fast", "world", "hello"];
+    for pooling in [Pooling::Mean, Pooling::LastToken] {
+        let options = EmbeddingOptions {
+            pooling,
+            normalize: false,
+            batch_size: 3,
+        };
+        let batched = engine.embed_batch(&texts, &options).unwrap();
+        assert_eq!(batched.len(), 3);
+        for (text, batched) in texts.iter().zip(&batched) {
+            let single = engine.embed(text, &options).unwrap();
+            let diff = single
+                .iter()
+                .zip(batched)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0f32, f32::max);
+            assert!(diff < 1e-4, "{text} {pooling:?} {diff}");
+        }
+    }
+}
+
+#[test]
+fn test_engine_embeddings_need_hidden_states() {
+    let mut engine = scripted_engine("hello");
+    assert!(matches!(
+        engine.embed("hello", &EmbeddingOptions::default()),
+        Err(EdgerunnerError::Inference(_))
+    ));
+}
+
+/// A word level tokenizer over `vocab` whose first `special` tokens are special, decoding
+/// without separators.
+fn word_level_tokenizer(vocab: &[&str], special: usize) -> tokenizers::Tokenizer {
+    let vocab_ids = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id.into()))
+        .collect::<serde_json::Map<_, _>>();
+    let special_tokens = vocab[..special]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| {
+            serde_json::json!({
+                "id": id,
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true
+            })
+        })
+        .collect::<Vec<_>>();
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "model": {"type": "WordLevel", "vocab": vocab_ids, "unk_token": vocab[0]},
+        "added_tokens": special_tokens,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "decoder": {"type": "Fuse"}
+    });
+    tokenizers::Tokenizer::from_bytes(tokenizer.to_string()).unwrap()
+}
+
+/// A scripted engine over a vocabulary of JSON pieces, which decode without separators.
+fn tool_engine(script: &[&str]) -> Engine {
+    let vocab = [
+        "<unk>",
+        "<s>",
+        "</s>",
+        "Sure",
+        "{",
+        r#"{"name": ""#,
+        "get_weather",
+        r#"", "arguments": "#,
+        r#"{"city": "Paris""#,
+        "}",
+    ];
+    let tokenizer = word_level_tokenizer(&vocab, 3);
+    let script = script
+        .iter()
+        .map(|t| vocab.iter().position(|v| v == t).unwrap() as u32)
+        .collect();
+    let model = ScriptedModel::new(script, vocab.len(), 512, 2);
+    Engine::builder()
+        .build_from(Model::new(tokenizer, Box::new(model), Device::Cpu))
+        .unwrap()
+}
+
+#[test]
+fn test_engine_stops_phi2_at_its_end_of_text_token() {
+    // like the candle phi-2 gguf files, the model has no eos metadata and the vocabulary no </s>
+    let vocab = ["<|endoftext|>", "hello", "world", "the", "model"];
+    let tokenizer = word_level_tokenizer(&vocab, 1);
+    let model =
+        ScriptedModel::new(vec![3, 4, 0, 1], vocab.len(), 512, 0).with_metadata(ModelMetadata {
+            architecture: ModelArchitecture::Phi2,
+            vocab_size: vocab.len(),
+            context_length: 512,
+            eos_token_ids: Vec::new(),
+        });
+    let mut engine = Engine::builder()
+        .build_from(Model::new(tokenizer, Box::new(model), Device::Cpu))
+        .unwrap();
+
+    let generation = engine.generate("hello world", &engine.params()).unwrap();
+    assert_eq!(generation.text, "themodel");
+    assert_eq!(generation.finish_reason, FinishReason::Eos);
+}
+
+fn weather_request(tool_choice: ToolChoice) -> ChatRequest {
+    ChatRequest {
+        messages: vec![ChatMessage::user("Weather in Paris?")],
+        tools: vec![Tool::function(
+            "get_weather",
+            "The weather of a city",
+            serde_json::json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+        )],
+        tool_choice,
+    }
+}
+
+#[test]
+fn test_engine_constrains_required_tool_calls() {
+    // the grammar turns the reply into a call and ends it once the call is whole
+    let mut engine = tool_engine(&[
+        "Sure",
+        "get_weather",
+        r#"", "arguments": "#,
+        r#"{"city": "Paris""#,
+        "}",
+        "}",
+        "Sure",
+    ]);
+    let params = SamplingParams {
+        temperature: None,
+        ..engine.params()
+    };
+    let completion = engine
+        .chat_completion(&weather_request(ToolChoice::Required), &params)
+        .unwrap();
+    assert_eq!(
+        completion.generation.text,
+        r#"{"name": "get_weather", "arguments": {"city": "Paris"}}"#
+    );
+    assert_eq!(completion.generation.finish_reason, FinishReason::ToolCalls);
+    assert_eq!(completion.message.content, None);
+    let call = &completion.message.tool_calls[0];
+    assert_eq!(call.id, "call_0");
+    assert_eq!(call.function.name, "get_weather");
+    assert_eq!(
+        call.function.parsed_arguments().unwrap(),
+        serde_json::json!({"city": "Paris"})
+    );
+
+    // the same script answers in text when the model may choose
+    let mut engine = tool_engine(&["Sure", "</s>"]);
+    let completion = engine
+        .chat_completion(&weather_request(ToolChoice::Auto), &params)
+        .unwrap();
+    assert_eq!(completion.message.content.as_deref(), Some("Sure"));
+    assert!(completion.message.tool_calls.is_empty());
+    assert_eq!(completion.generation.finish_reason, FinishReason::Eos);
+
+    let no_tools = ChatRequest {
+        tools: vec![],
+        ..weather_request(ToolChoice::Required)
+    };
+    assert!(matches!(
+        engine.chat_completion(&no_tools, &params),
+        Err(EdgerunnerError::Config(_))
+    ));
+}
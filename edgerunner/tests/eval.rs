@@ -0,0 +1,90 @@
+mod common;
+
+use common::tiny_engine;
+use edgerunner::{conf::model::SamplingParams, error::EdgerunnerError, runner::eval::EvalConfig};
+
+const TEXT: &str = "the tiny model is small and fast on the local cpu device and the large model \
+                    is fast on the gpu device";
+
+#[test]
+fn test_sliding_window_scores_every_token_once() {
+    let mut engine = tiny_engine();
+    let tokens = engine.count_tokens(TEXT).unwrap();
+
+    let full = engine.perplexity(TEXT, &EvalConfig::default()).unwrap();
+    assert_eq!(full.windows, 1);
+    assert_eq!(full.tokens, tokens - 1);
+    assert_eq!(full.token_losses.len(), tokens - 1);
+    assert!((full.perplexity - full.mean_nll.exp()).abs() < 1e-9);
+    assert!(full.token_losses.iter().all(|t| t.nll >= 0.));
+
+    let config = EvalConfig {
+        window: Some(8),
+        stride: Some(4),
+    };
+    let sliding = engine.perplexity(TEXT, &config).unwrap();
+    assert_eq!(sliding.tokens, tokens - 1);
+    assert_eq!(sliding.windows, (tokens - 8).div_ceil(4) + 1);
+    let ids = |report: &edgerunner::runner::eval::PerplexityReport| {
+        report.token_losses.iter().map(|t| t.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&sliding), ids(&full));
+    // the first window sees the same context as the full one
+    for (a, b) in sliding.token_losses[..7].iter().zip(&full.token_losses) {
+        assert!((a.nll - b.nll).abs() < 1e-4, "{a:?} {b:?}");
+    }
+    // later tokens lost their distant context
+    assert_ne!(sliding.mean_nll, full.mean_nll);
+
+    let invalid = EvalConfig {
+        window: Some(8),
+        stride: Some(8),
+    };
+    assert!(matches!(
+        engine.perplexity(TEXT, &invalid),
+        Err(EdgerunnerError::Config(_))
+    ));
+    let too_long = EvalConfig {
+        window: Some(4096),
+        stride: None,
+    };
+    assert!(matches!(
+        engine.perplexity(TEXT, &too_long),
+        Err(EdgerunnerError::ContextOverflow { .. })
+    ));
+}
+
+#[test]
+fn test_score_matches_the_token_losses() {
+    let mut engine = tiny_engine();
+    let report = engine
+        .perplexity("hello world fast", &EvalConfig::default())
+        .unwrap();
+    let score = engine.score("hello", " world fast").unwrap();
+    assert_eq!(score.tokens, 2);
+    let nll = report
+        .token_losses
+        .iter()
+        .map(|t| t.nll as f64)
+        .sum::<f64>();
+    assert!((score.log_likelihood + nll).abs() < 1e-4);
+    assert!((score.mean_log_likelihood() - score.log_likelihood / 2.).abs() < 1e-12);
+}
+
+#[test]
+fn test_score_recognizes_the_greedy_continuation() {
+    let mut engine = tiny_engine();
+    let greedy = SamplingParams {
+        sample_len: 3,
+        temperature: None,
+        repeat_penalty: 1.,
+        ..engine.params()
+    };
+    let generated = engine.generate("hello world", &greedy).unwrap().text;
+    let score = engine
+        .score("hello world", &format!(" {}", generated.trim_start()))
+        .unwrap();
+    assert!(score.is_greedy, "{generated}");
+    let other = engine.score("hello world", " gpu gpu gpu").unwrap();
+    assert!(other.log_likelihood < score.log_likelihood);
+}
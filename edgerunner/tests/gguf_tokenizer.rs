@@ -0,0 +1,60 @@
+mod common;
+
+use common::{fixture_tokenizer, tiny_gguf_with_vocab_path};
+use edgerunner::{
+    conf::model::InferenceConfig,
+    model::{gguf_tokenizer::tokenizer_from_gguf_file, loader::LoadModel},
+};
+
+const PARITY_CORPUS: [&str; 6] = [
+    "How does this work?",
+    "<s>[INST] Always respond with concise messages [/INST]",
+    "  leading spaces and trailing ones  ",
+    "Émojis 🚀 and accents: naïve café, 日本語のテキスト",
+    "fn main() {\n    println!(\"hello\");\n}",
+    "1234567890 + 0.5 = 1234567890.5",
+];
+
+#[test]
+fn test_loader_prefers_embedded_vocabulary() {
+    let config = InferenceConfig {
+        model: Some(tiny_gguf_with_vocab_path().to_string_lossy().to_string()),
+        tokenizer: None,
+        ..Default::default()
+    };
+    let model = LoadModel::load_model(&config).unwrap();
+
+    assert_eq!(
+        model.tokenizer.get_vocab_size(true),
+        fixture_tokenizer().get_vocab_size(true)
+    );
+    // the fixture words are stored as sentencepiece pieces with the same ids
+    let fixture = fixture_tokenizer();
+    assert_eq!(
+        model.tokenizer.token_to_id("▁hello"),
+        fixture.token_to_id("hello")
+    );
+    let encoding = model.tokenizer.encode("</s>", true).unwrap();
+    assert_eq!(encoding.get_tokens(), ["<s>", "</s>"]);
+}
+
+#[test]
+#[ignore = "downloads the mistral 7b instruct gguf and tokenizer from the hub"]
+fn test_embedded_vocabulary_matches_hub_tokenizer() {
+    let config = InferenceConfig::default();
+    let embedded = tokenizer_from_gguf_file(&config.model().unwrap())
+        .unwrap()
+        .unwrap();
+    let hub = config.tokenizer().unwrap();
+
+    for text in PARITY_CORPUS {
+        let expected = hub.encode(text, true).unwrap();
+        let actual = embedded.encode(text, true).unwrap();
+        assert_eq!(actual.get_ids(), expected.get_ids(), "{text}");
+        assert_eq!(
+            embedded.decode(actual.get_ids(), true).unwrap(),
+            hub.decode(expected.get_ids(), true).unwrap(),
+            "{text}"
+        );
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{greedy, tiny_engine, tiny_engine_builder, tiny_gguf_twin_path};
+use edgerunner::{
+    engine::Engine,
+    runner::prefix_cache::{PrefixCacheConfig, PrefixCacheStats},
+};
+
+fn cached_engine(config: PrefixCacheConfig) -> Engine {
+    tiny_engine_builder().prefix_cache(config).build().unwrap()
+}
+
+#[test]
+fn test_prefix_cache_generates_the_same_text() {
+    let mut engine = tiny_engine();
+    let mut cached = cached_engine(PrefixCacheConfig::default());
+    let params = greedy(&engine, 6);
+    let prompts = [
+        "the model is small and fast",
+        "the model is small and slow on the cpu",
+        "hello world",
+    ];
+    for prompt in prompts {
+        let expected = engine.chat(prompt, &[], None, &params).unwrap();
+        let generation = cached.chat(prompt, &[], None, &params).unwrap();
+        assert_eq!(generation.text, expected.text, "{prompt}");
+        assert_eq!(generation.prompt_tokens, expected.prompt_tokens);
+    }
+    assert_eq!(engine.prefix_cache_stats(), None);
+
+    let stats = cached.prefix_cache_stats().unwrap();
+    // the chat template starts every prompt the same way
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, 1);
+    let generation = cached.chat(prompts[1], &[], None, &params).unwrap();
+    assert_eq!(generation.cached_tokens, generation.prompt_tokens - 1);
+}
+
+#[test]
+fn test_prefix_cache_persists_to_disk() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("edgerunner-prefix-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = PrefixCacheConfig {
+        dir: Some(dir.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+
+    let mut engine = cached_engine(config.clone());
+    let params = greedy(&engine, 6);
+    let first = engine.generate("hello world the model", &params).unwrap();
+    assert_eq!(first.cached_tokens, 0);
+    drop(engine);
+
+    let mut engine = cached_engine(config);
+    let again = engine.generate("hello world the model", &params).unwrap();
+    assert_eq!(again.text, first.text);
+    assert_eq!(again.cached_tokens, first.prompt_tokens - 1);
+    assert_eq!(
+        engine.prefix_cache_stats(),
+        Some(PrefixCacheStats {
+            hits: 1,
+            misses: 0,
+            disk_hits: 1,
+            reused_tokens: first.prompt_tokens - 1,
+        })
+    );
+}
+
+#[test]
+fn test_prefix_cache_dir_tells_weights_apart() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "edgerunner-shared-prefix-cache-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = PrefixCacheConfig {
+        dir: Some(dir.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+
+    let mut engine = cached_engine(config.clone());
+    let params = greedy(&engine, 6);
+    engine.generate("hello world the model", &params).unwrap();
+    drop(engine);
+
+    // same file name and size, other weights
+    let mut twin = tiny_engine_builder()
+        .model_path(tiny_gguf_twin_path())
+        .prefix_cache(config)
+        .build()
+        .unwrap();
+    let generation = twin.generate("hello world the model", &params).unwrap();
+    assert_eq!(generation.cached_tokens, 0);
+    assert_eq!(twin.prefix_cache_stats().unwrap().disk_hits, 0);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::path::PathBuf;
+
+use common::{tiny_engine, tiny_engine_builder, tiny_gguf_twin_path};
+use edgerunner::{
+    conf::model::SamplingParams,
+    error::EdgerunnerError,
+    rag::{ask, ChunkConfig, RagIndex},
+    runner::embeddings::EmbeddingOptions,
+};
+
+fn docs_dir(name: &str) -> PathBuf {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "edgerunner-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("guide")).unwrap();
+    std::fs::create_dir_all(dir.join(".cache")).unwrap();
+    std::fs::write(dir.join("a.md"), "hello world").unwrap();
+    std::fs::write(
+        dir.join("guide/b.txt"),
+        "the model is small and fast on the local cpu device",
+    )
+    .unwrap();
+    std::fs::write(dir.join(".cache/c.md"), "hidden").unwrap();
+    std::fs::write(dir.join("d.rs"), "not a document").unwrap();
+    dir
+}
+
+fn chunking() -> ChunkConfig {
+    ChunkConfig {
+        chunk_words: 6,
+        overlap_words: 2,
+    }
+}
+
+#[test]
+fn test_rag_ingests_and_searches_documents() {
+    let dir = docs_dir("rag-docs");
+    let mut engine = tiny_engine();
+    let index =
+        RagIndex::ingest(&mut engine, &dir, &chunking(), &EmbeddingOptions::default()).unwrap();
+
+    let citations = index
+        .chunks
+        .iter()
+        .map(|chunk| chunk.citation())
+        .collect::<Vec<_>>();
+    let b = PathBuf::from("guide").join("b.txt");
+    let b = b.to_string_lossy();
+    assert_eq!(
+        citations,
+        vec![
+            "a.md#0".to_string(),
+            format!("{b}#0"),
+            format!("{b}#1"),
+            format!("{b}#2")
+        ]
+    );
+    assert_eq!(index.chunks[2].text, "and fast on the local cpu");
+    // the index names the model file, not its path
+    let model = common::tiny_gguf_path();
+    assert_eq!(
+        index.model.model,
+        model.file_name().unwrap().to_string_lossy()
+    );
+    assert_eq!(index.model, engine.fingerprint());
+
+    let path = dir.join("index/rag.json");
+    index.save(&path).unwrap();
+    let index = RagIndex::load(&path).unwrap();
+
+    let hits = index.search(&mut engine, "hello world", 2).unwrap();
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits[0].chunk.citation(), "a.md#0");
+    assert!((hits[0].score - 1.).abs() < 1e-4);
+    assert!(hits[0].score >= hits[1].score);
+}
+
+#[test]
+fn test_rag_search_checks_the_weights() {
+    let dir = docs_dir("rag-twin");
+    let mut engine = tiny_engine();
+    let index =
+        RagIndex::ingest(&mut engine, &dir, &chunking(), &EmbeddingOptions::default()).unwrap();
+
+    // same file name and size, other weights
+    let mut twin = tiny_engine_builder()
+        .model_path(tiny_gguf_twin_path())
+        .build()
+        .unwrap();
+    let err = index.search(&mut twin, "hello world", 2).unwrap_err();
+    assert!(matches!(err, EdgerunnerError::Config(_)), "{err}");
+}
+
+#[test]
+fn test_rag_ask_fits_the_context() {
+    let dir = docs_dir("rag-ask");
+    let mut engine = tiny_engine();
+    let index =
+        RagIndex::ingest(&mut engine, &dir, &chunking(), &EmbeddingOptions::default()).unwrap();
+
+    let params = SamplingParams {
+        sample_len: 4,
+        ..engine.params()
+    };
+    let answer = ask(&mut engine, &index, "what is fast ?", 3, &params, |_| {}).unwrap();
+    assert_eq!(answer.citations.len(), 3);
+    assert_eq!(answer.generation.generated_tokens, 4);
+
+    // the tiny model has a context of 128 tokens, leaving no room for the chunks
+    let params = SamplingParams {
+        sample_len: 120,
+        ..params
+    };
+    let answer = ask(&mut engine, &index, "what is fast ?", 3, &params, |_| {}).unwrap();
+    assert!(answer.citations.is_empty());
+}
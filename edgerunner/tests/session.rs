@@ -0,0 +1,98 @@
+mod common;
+
+use common::{
+    tiny_engine, tiny_engine_builder, tiny_gguf_path, tiny_gguf_twin_path,
+    tiny_gguf_with_vocab_path,
+};
+use edgerunner::{conf::model::SamplingParams, engine::Engine, error::EdgerunnerError};
+
+fn session_path(name: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "edgerunner-{}-{}.session",
+        name,
+        std::process::id()
+    ))
+}
+
+fn sampling(engine: &Engine, sample_len: usize) -> SamplingParams {
+    SamplingParams {
+        sample_len,
+        temperature: Some(1.5),
+        seed: 11,
+        ..engine.params()
+    }
+}
+
+#[test]
+fn test_resumed_session_matches_an_uninterrupted_run() {
+    let mut engine = tiny_engine();
+    let full = engine
+        .generate("the model is", &sampling(&engine, 10))
+        .unwrap();
+    assert_eq!(full.generated_tokens, 10);
+
+    let first = engine
+        .generate("the model is", &sampling(&engine, 4))
+        .unwrap();
+    let path = session_path("resume");
+    engine.save_session(&path).unwrap();
+
+    // another engine with other sampling settings picks up the saved ones
+    let mut engine = tiny_engine();
+    engine.set_params(&SamplingParams {
+        temperature: None,
+        ..engine.params()
+    });
+    engine.load_session(&path).unwrap();
+    let second = engine.resume(3).unwrap();
+    assert_eq!(second.generated_tokens, 3);
+    assert!(!second.text.is_empty());
+    // a resumed generation can be resumed again
+    let third = engine.resume(3).unwrap();
+    assert_eq!(
+        format!("{}{}{}", first.text, second.text, third.text),
+        full.text
+    );
+}
+
+#[test]
+fn test_load_session_checks_the_model() {
+    let mut engine = tiny_engine();
+    assert!(matches!(engine.resume(3), Err(EdgerunnerError::Config(_))));
+    engine
+        .generate("hello world", &sampling(&engine, 2))
+        .unwrap();
+    let path = session_path("other-model");
+    engine.save_session(&path).unwrap();
+
+    let mut other = tiny_engine_builder()
+        .model_path(tiny_gguf_with_vocab_path())
+        .build()
+        .unwrap();
+    let err = other.load_session(&path).unwrap_err();
+    assert!(matches!(err, EdgerunnerError::Config(_)), "{err}");
+}
+
+#[test]
+fn test_load_session_checks_the_weights() {
+    let mut engine = tiny_engine();
+    engine
+        .generate("hello world", &sampling(&engine, 2))
+        .unwrap();
+    let path = session_path("other-weights");
+    engine.save_session(&path).unwrap();
+
+    let mut twin = tiny_engine_builder()
+        .model_path(tiny_gguf_twin_path())
+        .build()
+        .unwrap();
+    let (fingerprint, twin_fingerprint) = (engine.fingerprint(), twin.fingerprint());
+    assert_eq!(fingerprint.model, twin_fingerprint.model);
+    assert_eq!(
+        std::fs::metadata(tiny_gguf_path()).unwrap().len(),
+        twin_fingerprint.size_bytes
+    );
+    assert_ne!(fingerprint.weights_hash, twin_fingerprint.weights_hash);
+    let err = twin.load_session(&path).unwrap_err();
+    assert!(matches!(err, EdgerunnerError::Config(_)), "{err}");
+}
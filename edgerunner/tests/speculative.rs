@@ -0,0 +1,61 @@
+mod common;
+
+use std::path::PathBuf;
+
+use common::{greedy, tiny_engine, tiny_engine_builder, tiny_gguf_path, tiny_gguf_with_vocab_path};
+use edgerunner::{
+    conf::model::SamplingParams, engine::Engine, runner::speculative::SpeculativeConfig,
+};
+
+fn speculative_engine(draft_model: PathBuf, draft_tokens: usize) -> Engine {
+    tiny_engine_builder()
+        .speculative(SpeculativeConfig {
+            draft_model: draft_model.to_string_lossy().to_string(),
+            draft_tokens,
+        })
+        .build()
+        .unwrap()
+}
+
+fn penalized(engine: &Engine, repeat_penalty: f32) -> SamplingParams {
+    SamplingParams {
+        repeat_penalty,
+        ..greedy(engine, 12)
+    }
+}
+
+#[test]
+fn test_speculative_decoding_generates_the_same_text() {
+    let mut engine = tiny_engine();
+    for (draft_model, draft_tokens) in [(tiny_gguf_path(), 4), (tiny_gguf_with_vocab_path(), 3)] {
+        let mut speculative = speculative_engine(draft_model, draft_tokens);
+        for repeat_penalty in [1., 1.3] {
+            let params = penalized(&engine, repeat_penalty);
+            let expected = engine.generate("the model is", &params).unwrap();
+            let generation = speculative.generate("the model is", &params).unwrap();
+            assert_eq!(generation.text, expected.text);
+            assert_eq!(generation.generated_tokens, expected.generated_tokens);
+            assert_eq!(generation.finish_reason, expected.finish_reason);
+            assert!(expected.speculative.is_none());
+            assert!(generation.speculative.unwrap().drafted_tokens > 0);
+        }
+    }
+}
+
+#[test]
+fn test_a_draft_of_the_model_itself_is_always_accepted() {
+    let mut speculative = speculative_engine(tiny_gguf_path(), 4);
+    let params = penalized(&speculative, 1.);
+    let generation = speculative.chat("hello world", &[], None, &params).unwrap();
+    let stats = generation.speculative.unwrap();
+    assert_eq!(stats.accepted_tokens, stats.drafted_tokens);
+    assert_eq!(stats.acceptance_rate(), 1.);
+
+    // sampled tokens go through the same checks
+    let sampled = SamplingParams {
+        temperature: Some(1.),
+        ..params
+    };
+    let generation = speculative.generate("the model is", &sampled).unwrap();
+    assert_eq!(generation.generated_tokens, params.sample_len);
+}
@@ -0,0 +1,254 @@
+mod common;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use candle_core::Device;
+use common::{fixture_tokenizer, ScriptedModel};
+use edgerunner::{
+    conf::which::Which,
+    error::EdgerunnerError,
+    model::prompt::GeneratedPrompt,
+    runner::{
+        events::{EngineEvent, FinishReason},
+        text_generation::TextGeneration,
+    },
+};
+
+fn pipeline(model: ScriptedModel) -> TextGeneration {
+    TextGeneration::new(
+        Box::new(model),
+        Device::Cpu,
+        fixture_tokenizer(),
+        1.,
+        64,
+        299792458,
+        None,
+        None,
+    )
+}
+
+fn prompt(text: &str) -> GeneratedPrompt {
+    GeneratedPrompt(text.to_string())
+}
+
+#[test]
+fn test_run_streams_tokens_until_eos() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "hello world </s> fast", 64);
+    let calls = model.calls();
+    let mut pipeline = pipeline(model);
+
+    let streamed = Mutex::new(Vec::new());
+    let (response, prompt_tokens, _, sampled, _) = pipeline
+        .run(
+            prompt("how does this work ?"),
+            10,
+            &Which::Mistral7bInstruct,
+            Arc::new(AtomicBool::new(false)),
+            |t| streamed.lock().unwrap().push(t.to_string()),
+        )
+        .unwrap();
+
+    assert_eq!(response, "hello world");
+    assert_eq!(streamed.into_inner().unwrap().concat(), response);
+    assert_eq!(prompt_tokens, 5.);
+    // the eos token is sampled in the loop and ends it
+    assert_eq!(sampled, 2.);
+    assert_eq!(*calls.lock().unwrap(), vec![(0, 5), (5, 1), (6, 1)]);
+}
+
+#[test]
+fn test_run_respects_sample_len() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "one two three four five", 64);
+    let mut pipeline = pipeline(model);
+
+    let (response, _, _, sampled, _) = pipeline
+        .run(
+            prompt("hello"),
+            3,
+            &Which::Mistral7bInstruct,
+            Arc::new(AtomicBool::new(false)),
+            |_| {},
+        )
+        .unwrap();
+
+    assert_eq!(response, "one two three");
+    assert_eq!(sampled, 2.);
+}
+
+#[test]
+fn test_run_is_repeatable() {
+    let tokenizer = fixture_tokenizer();
+    let mut pipeline = pipeline(ScriptedModel::from_text(&tokenizer, "yes no </s>", 64));
+
+    let mut run = || {
+        pipeline
+            .run(
+                prompt("hello"),
+                10,
+                &Which::Mistral7bInstruct,
+                Arc::new(AtomicBool::new(false)),
+                |_| {},
+            )
+            .unwrap()
+            .0
+    };
+    assert_eq!(run(), "yes no");
+    assert_eq!(run(), "yes no");
+}
+
+#[test]
+fn test_run_stops_before_start() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "hello world", 64);
+    let calls = model.calls();
+    let mut pipeline = pipeline(model);
+
+    let result = pipeline.run(
+        prompt("hello"),
+        10,
+        &Which::Mistral7bInstruct,
+        Arc::new(AtomicBool::new(true)),
+        |_| {},
+    );
+
+    assert!(matches!(result, Err(EdgerunnerError::Cancelled)));
+    assert!(calls.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_run_stops_while_streaming() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "one two three four five", 64);
+    let mut pipeline = pipeline(model);
+
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let streamed = Mutex::new(Vec::new());
+    let result = pipeline.run(
+        prompt("hello"),
+        10,
+        &Which::Mistral7bInstruct,
+        stop_flag.clone(),
+        |t| {
+            streamed.lock().unwrap().push(t.to_string());
+            if streamed.lock().unwrap().len() == 2 {
+                stop_flag.store(true, Ordering::SeqCst);
+            }
+        },
+    );
+
+    assert!(matches!(result, Err(EdgerunnerError::Cancelled)));
+    assert_eq!(streamed.into_inner().unwrap().concat(), "one two");
+}
+
+#[test]
+fn test_run_truncates_prompt_to_context() {
+    let context_length = 16;
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "yes </s>", context_length);
+    let calls = model.calls();
+    let mut pipeline = pipeline(model);
+
+    let long_prompt = "one two three four five six seven eight nine ten ".repeat(2);
+    let (_, prompt_tokens, _, _, _) = pipeline
+        .run(
+            prompt(&long_prompt),
+            4,
+            &Which::Mistral7bInstruct,
+            Arc::new(AtomicBool::new(false)),
+            |_| {},
+        )
+        .unwrap();
+
+    // 20 prompt tokens + 3 to sample + 10 of slack - 16 of context leaves 3 prompt tokens
+    assert_eq!(prompt_tokens, 3.);
+    assert_eq!(calls.lock().unwrap()[0], (0, 3));
+}
+
+#[test]
+fn test_run_rejects_unavailable_model() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "hello", 64);
+    let mut pipeline = pipeline(model);
+
+    let result = pipeline.run(
+        prompt("hello"),
+        10,
+        &Which::OpenChat35,
+        Arc::new(AtomicBool::new(false)),
+        |_| {},
+    );
+
+    assert!(matches!(result, Err(EdgerunnerError::Config(_))));
+}
+
+fn recorded_events(pipeline: TextGeneration) -> (TextGeneration, Arc<Mutex<Vec<EngineEvent>>>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorder = events.clone();
+    let pipeline = pipeline.with_observer(Arc::new(move |event: &EngineEvent| {
+        recorder.lock().unwrap().push(event.clone())
+    }));
+    (pipeline, events)
+}
+
+#[test]
+fn test_run_reports_events() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "hello world </s> fast", 64);
+    let (mut pipeline, events) = recorded_events(pipeline(model));
+
+    pipeline
+        .run(
+            prompt("how does this work ?"),
+            10,
+            &Which::Mistral7bInstruct,
+            Arc::new(AtomicBool::new(false)),
+            |_| {},
+        )
+        .unwrap();
+
+    let events = events.lock().unwrap();
+    assert!(matches!(
+        events[0],
+        EngineEvent::PromptProcessed { tokens: 5, .. }
+    ));
+    let tokens = events
+        .iter()
+        .filter_map(|event| match event {
+            EngineEvent::TokenGenerated { index, text, .. } => Some((*index, text.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // the eos token completes no text
+    assert_eq!(
+        tokens,
+        vec![
+            (0, Some("hello".to_string())),
+            (1, Some(" world".to_string())),
+            (2, None),
+        ]
+    );
+    assert!(matches!(
+        events.last().unwrap(),
+        EngineEvent::Finished {
+            prompt_tokens: 5,
+            generated_tokens: 3,
+            reason: FinishReason::Eos,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_run_reports_stop_event() {
+    let model = ScriptedModel::from_text(&fixture_tokenizer(), "hello", 64);
+    let (mut pipeline, events) = recorded_events(pipeline(model));
+
+    let result = pipeline.run(
+        prompt("hello"),
+        10,
+        &Which::Mistral7bInstruct,
+        Arc::new(AtomicBool::new(true)),
+        |_| {},
+    );
+
+    assert!(matches!(result, Err(EdgerunnerError::Cancelled)));
+    assert_eq!(*events.lock().unwrap(), vec![EngineEvent::Stopped]);
+}
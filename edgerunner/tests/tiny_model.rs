@@ -0,0 +1,117 @@
+mod common;
+
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+
+use candle_core::{IndexOp, Tensor};
+use common::{fixture_tokenizer_path, tiny_gguf_path};
+use edgerunner::{
+    conf::model::InferenceConfig,
+    model::{loader::LoadModel, prompt::GeneratedPrompt, types::ModelArchitecture},
+    runner::{events::EngineEvent, text_generation::TextGeneration},
+};
+
+fn tiny_config() -> InferenceConfig {
+    InferenceConfig {
+        model: Some(tiny_gguf_path().to_string_lossy().to_string()),
+        tokenizer: Some(fixture_tokenizer_path().to_string_lossy().to_string()),
+        sample_len: 12,
+        ..Default::default()
+    }
+}
+
+fn generate(config: &InferenceConfig, prompt: &str) -> (String, f64) {
+    let model = LoadModel::load_model(config).unwrap();
+    let mut pipeline = TextGeneration::new(
+        model.weights,
+        model.device,
+        model.tokenizer,
+        config.repeat_penalty,
+        config.repeat_last_n,
+        config.seed,
+        config.temperature,
+        config.top_p,
+    );
+    let (response, _, _, sampled, _) = pipeline
+        .run(
+            GeneratedPrompt(prompt.to_string()),
+            config.sample_len,
+            &config.which,
+            Arc::new(AtomicBool::new(false)),
+            |_| {},
+        )
+        .unwrap();
+    (response, sampled)
+}
+
+#[test]
+fn test_load_tiny_gguf() {
+    let model = LoadModel::load_model(&tiny_config()).unwrap();
+    let metadata = model.weights.metadata();
+    assert_eq!(metadata.architecture, ModelArchitecture::Llama);
+    assert_eq!(metadata.vocab_size, model.tokenizer.get_vocab_size(true));
+    assert_eq!(metadata.context_length, 128);
+}
+
+#[test]
+fn test_load_reports_events() {
+    let events = Mutex::new(Vec::new());
+    let observer = |event: &EngineEvent| events.lock().unwrap().push(event.clone());
+    LoadModel::load_model_with_observer(&tiny_config(), &observer).unwrap();
+
+    let events = events.into_inner().unwrap();
+    assert_eq!(events.len(), 3);
+    assert!(matches!(&events[0], EngineEvent::ModelLoading { path } if path.ends_with(".gguf")));
+    assert!(matches!(events[1], EngineEvent::WeightsLoaded { size_bytes, .. } if size_bytes > 0));
+    assert!(matches!(
+        events[2],
+        EngineEvent::ModelLoaded {
+            architecture: ModelArchitecture::Llama,
+            embedded_tokenizer: false,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_tiny_gguf_generation_is_deterministic() {
+    let config = tiny_config();
+    let (first, sampled) = generate(&config, "hello world");
+    let (second, _) = generate(&config, "hello world");
+
+    assert!(sampled <= (config.sample_len - 1) as f64);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_tiny_gguf_greedy_generation() {
+    let config = InferenceConfig {
+        temperature: None,
+        ..tiny_config()
+    };
+    let (first, _) = generate(&config, "how does this work ?");
+    let (second, _) = generate(&config, "how does this work ?");
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_forward_all_matches_the_last_position_logits() {
+    let mut model = LoadModel::load_model(&tiny_config()).unwrap();
+    let input = Tensor::new(&[3u32, 4, 17, 5], &model.device)
+        .unwrap()
+        .unsqueeze(0)
+        .unwrap();
+    let all = model.weights.forward_all(&input, 0).unwrap();
+    assert_eq!(all.dims3().unwrap(), (1, 4, model.weights.vocab_size()));
+    let last = model.weights.forward(&input, 0).unwrap();
+    let diff = (all.i((.., 3, ..)).unwrap() - last)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .max_keepdim(1)
+        .unwrap()
+        .squeeze(1)
+        .unwrap()
+        .to_vec1::<f32>()
+        .unwrap()[0];
+    assert!(diff < 1e-5, "{diff}");
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::byte_fallback_tokenizer;
+use edgerunner::runner::token_output_stream::TokenOutputStream;
+use proptest::prelude::*;
+use tokenizers::Tokenizer;
+
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+const CORPUS: [&str; 10] = [
+    "hello world",
+    "héllo wörld, the façade is naïve",
+    "Grüße aus München, Straße und Fuß",
+    "日本語のテキストを生成します。",
+    "你好，世界！这是一个测试。",
+    "привет мир, как дела?",
+    "مرحبا بالعالم",
+    "नमस्ते दुनिया",
+    "Rust 🦀 goes brrr 🚀🔥 👩‍👩‍👧",
+    "  leading spaces and\ttabs\nand new lines  ",
+];
+
+fn encode(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+    tokenizer.encode(text, false).unwrap().get_ids().to_vec()
+}
+
+/// The text of the complete characters in `tokens`, worked out from the raw bytes of the
+/// pieces rather than through the tokenizer decoder, which mangles a whole run of byte tokens
+/// when it ends with an incomplete character.
+fn complete_text(tokenizer: &Tokenizer, tokens: &[u32]) -> String {
+    let mut bytes = Vec::new();
+    for &token in tokens {
+        let piece = tokenizer.id_to_token(token).unwrap();
+        match piece
+            .strip_prefix("<0x")
+            .and_then(|p| p.strip_suffix('>'))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend(piece.replace('▁', " ").into_bytes()),
+        }
+    }
+    let valid_up_to = match std::str::from_utf8(&bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    let text = String::from_utf8(bytes[..valid_up_to].to_vec()).unwrap();
+    // the decoder strips the space the normalizer prepends
+    text.strip_prefix(' ').map(str::to_string).unwrap_or(text)
+}
+
+/// Streams `tokens` and returns the text emitted after every token followed by the rest.
+fn stream(tokenizer: &Tokenizer, tokens: &[u32]) -> (Vec<Option<String>>, Option<String>) {
+    let mut stream = TokenOutputStream::new(tokenizer.clone());
+    let chunks = tokens
+        .iter()
+        .map(|&t| stream.next_token(t).unwrap())
+        .collect();
+    (chunks, stream.decode_rest().unwrap())
+}
+
+#[test]
+fn test_multi_byte_character_is_emitted_with_its_last_byte() {
+    let tokenizer = byte_fallback_tokenizer();
+    // "ñ" has no piece and falls back to its two utf-8 bytes after the leading "▁"
+    let tokens = encode(&tokenizer, "ñ");
+    assert_eq!(tokens.len(), 3);
+
+    let (chunks, rest) = stream(&tokenizer, &tokens);
+    assert_eq!(chunks, vec![None, None, Some("ñ".to_string())]);
+    assert_eq!(rest, None);
+}
+
+#[test]
+fn test_non_ascii_pieces_are_emitted_immediately() {
+    let tokenizer = byte_fallback_tokenizer();
+    let tokens = encode(&tokenizer, "é ü ß 🦀");
+
+    // only the leading "▁" decodes to nothing
+    let (chunks, _) = stream(&tokenizer, &tokens);
+    assert!(chunks[1..].iter().all(Option::is_some), "{chunks:?}");
+    assert_eq!(chunks.into_iter().flatten().collect::<String>(), "é ü ß 🦀");
+}
+
+#[test]
+fn test_clear_resets_the_stream() {
+    let tokenizer = byte_fallback_tokenizer();
+    let mut stream = TokenOutputStream::new(tokenizer.clone());
+    for token in encode(&tokenizer, "hello ñ") {
+        stream.next_token(token).unwrap();
+    }
+    stream.clear();
+
+    let tokens = encode(&tokenizer, "world");
+    let emitted = tokens
+        .iter()
+        .filter_map(|&t| stream.next_token(t).unwrap())
+        .collect::<String>();
+    assert_eq!(emitted, "world");
+    assert_eq!(stream.decode_all().unwrap(), "world");
+}
+
+fn corpus_text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::sample::select(CORPUS.to_vec()).prop_map(str::to_string),
+        prop::collection::vec(prop::sample::select(CORPUS.to_vec()), 1..4)
+            .prop_map(|texts| texts.join(" ")),
+        // a literal replacement character is indistinguishable from an incomplete one
+        "\\PC{0,32}".prop_map(|text| text.replace(REPLACEMENT_CHARACTER, "")),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn prop_streamed_text_matches_full_decoding(text in corpus_text()) {
+        let tokenizer = byte_fallback_tokenizer();
+        let tokens = encode(&tokenizer, &text);
+
+        let (chunks, rest) = stream(&tokenizer, &tokens);
+        let streamed = chunks.into_iter().flatten().chain(rest).collect::<String>();
+        prop_assert_eq!(streamed, tokenizer.decode(&tokens, true).unwrap());
+    }
+
+    #[test]
+    fn prop_flushed_text_is_not_emitted_again(text in corpus_text(), split in 0..64usize) {
+        let tokenizer = byte_fallback_tokenizer();
+        let tokens = encode(&tokenizer, &text);
+        let split = split.min(tokens.len());
+
+        let mut stream = TokenOutputStream::new(tokenizer.clone());
+        let mut streamed = String::new();
+        for &token in &tokens[..split] {
+            streamed.extend(stream.next_token(token).unwrap());
+        }
+        let flushed = stream.flush_rest().unwrap();
+        // an incomplete character stays pending, the next tokens emit it again completed
+        if !flushed.as_ref().is_some_and(|f| f.ends_with(REPLACEMENT_CHARACTER)) {
+            streamed.extend(flushed);
+        }
+        for &token in &tokens[split..] {
+            streamed.extend(stream.next_token(token).unwrap());
+        }
+        streamed.extend(stream.decode_rest().unwrap());
+        prop_assert_eq!(streamed, tokenizer.decode(&tokens, true).unwrap());
+    }
+
+    #[test]
+    fn prop_complete_characters_are_emitted_as_soon_as_decoded(text in corpus_text()) {
+        let tokenizer = byte_fallback_tokenizer();
+        let tokens = encode(&tokenizer, &text);
+
+        let mut stream = TokenOutputStream::new(tokenizer.clone());
+        let mut emitted = String::new();
+        for (i, &token) in tokens.iter().enumerate() {
+            if let Some(chunk) = stream.next_token(token).unwrap() {
+                prop_assert!(!chunk.contains(REPLACEMENT_CHARACTER));
+                emitted += &chunk;
+            }
+            prop_assert_eq!(&emitted, &complete_text(&tokenizer, &tokens[..=i]));
+        }
+    }
+}